@@ -0,0 +1,72 @@
+/// Dense vs sparse probability integration and planning of the greedy scheduler, on a
+/// large query space with a handful of explicit probabilities.
+///
+/// $ cargo bench --bench scheduler
+use criterion::{criterion_group, criterion_main, Criterion};
+use khameleon::ds::TimeManager;
+use khameleon::scheduler::greedy::{self, GreedyScheduler, Policy};
+use khameleon::scheduler::{Prob, SchedulerOptions, SchedulerTrait};
+use ndarray::Array1;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::sync::{Arc, RwLock};
+
+const TOTAL_QUERIES: usize = 100_000;
+const HORIZON: usize = 100;
+const NBLOCKS: usize = 10;
+
+fn scheduler() -> GreedyScheduler {
+    let utility: Array1<f32> = (0..NBLOCKS).map(|_| 1.0 / NBLOCKS as f32).collect();
+    let tm = Arc::new(RwLock::new(TimeManager::new(10, 0, 1.0)));
+    let options = SchedulerOptions{ threads: 1, seed: Some(7), ..SchedulerOptions::default() };
+    greedy::new(HORIZON, HORIZON, utility, vec![NBLOCKS; TOTAL_QUERIES], tm, &options, Policy::Sample)
+}
+
+// 50 explicit queries at three deltas, the rest is uniform
+fn probs() -> Prob {
+    let mut probs = Prob::new(TOTAL_QUERIES);
+    for &delta in [0, 200, 400].iter() {
+        let dist: indexmap::IndexMap<usize, f32> = (0..50).map(|i| (i * 7, 0.5 / 50.0)).collect();
+        probs.set_probs_at(dist, delta);
+    }
+    probs
+}
+
+fn bench_dense(c: &mut Criterion) {
+    let sched = scheduler();
+    let probs = probs();
+    let state: Array1<usize> = Array1::zeros(TOTAL_QUERIES);
+
+    c.bench_function("dense integrate", |b| {
+        b.iter(|| sched.integrate_probs_slow(probs.clone(), TOTAL_QUERIES, HORIZON))
+    });
+    let dense = sched.integrate_probs_slow(probs.clone(), TOTAL_QUERIES, HORIZON);
+    let mut rng = StdRng::seed_from_u64(7);
+    c.bench_function("dense greedy", |b| {
+        b.iter(|| sched.greedy_p(HORIZON, &dense, TOTAL_QUERIES, &sched.utility, state.clone(), &mut rng))
+    });
+}
+
+fn bench_sparse(c: &mut Criterion) {
+    let sched = scheduler();
+    let probs = probs();
+    let state: Array1<usize> = Array1::zeros(TOTAL_QUERIES);
+
+    c.bench_function("sparse integrate", |b| {
+        b.iter(|| sched.integrate_probs_sparse(&probs, TOTAL_QUERIES, HORIZON))
+    });
+    let sparse = sched.integrate_probs_sparse(&probs, TOTAL_QUERIES, HORIZON);
+    let mut rng = StdRng::seed_from_u64(7);
+    c.bench_function("sparse greedy", |b| {
+        b.iter(|| sched.greedy_p_sparse(HORIZON, &sparse, TOTAL_QUERIES, &sched.utility, state.clone(), &mut rng))
+    });
+
+    // what the server runs every round: the rest queries are tracked across plans
+    let mut sched = scheduler();
+    c.bench_function("sparse run_scheduler", |b| {
+        b.iter(|| sched.run_scheduler(probs.clone(), state.clone(), 0))
+    });
+}
+
+criterion_group!(benches, bench_dense, bench_sparse);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-import { App, Engine, Data  } from "../../khameleon-core";
+import { App, Engine, Data, CodedBlock, parseCodedBlock, decodeLevel } from "../../khameleon-core";
 import * as d3 from "d3";
 
 interface RenderData {
@@ -8,6 +8,8 @@ interface RenderData {
 export class TestApp implements App {
  private engine: Engine;
  public appName: string = "TestApp";
+ // blocks are erasure coded levels, see khameleon-core/utils/erasure.ts
+ private coded: boolean = false;
 
 
  constructor(private sysconfig) {
@@ -28,7 +30,10 @@ export class TestApp implements App {
   return state;
  }
 
- onopen(_data: string) {
+ onopen(data: any) {
+  let initstate = (typeof data === "string") ? (data.length > 0 ? JSON.parse(data) : {}) : (data || {});
+  this.coded = initstate.erasure == true;
+
   // optional setup code to initial webpage layout
   // this example load a single image from static folder
   this.setup();
@@ -99,6 +104,8 @@ export class TestApp implements App {
  // reconstruct set of blocks in the cache and return
  // data for rendering
  construct(req, blocks, nblocks: number) : Data {
+    if (this.coded) return this.constructCoded(req, blocks, nblocks);
+
     let image_data: any[] = [];
     for (var i = 0; i < blocks.size; i++) {
       if ( blocks.has(i) ) {
@@ -118,4 +125,30 @@ export class TestApp implements App {
   
   return  { render_data: {img_dir: img_dir}, inblocks: image_data.length };
  }
+
+ // erasure coded blocks: every level with k distinct coded blocks, in level order
+ constructCoded(req, blocks, nblocks: number) : Data {
+    let levels: CodedBlock[][] = [];
+    blocks.forEach(({data}) => {
+      let block = parseCodedBlock(data);
+      (levels[block.level] = levels[block.level] || []).push(block);
+    });
+
+    let image_data: any[] = [];
+    for (let l = 0; l < levels.length; l++) {
+      let sources = levels[l] ? decodeLevel(levels[l]) : undefined;
+      if (sources == undefined) break;
+      for (let source of sources) {
+        let block = this.decodeBlock(source.buffer);
+        image_data.push( new Uint8Array( block.content ) );
+      }
+    }
+
+    let img_dir  = URL.createObjectURL(new Blob( image_data ));
+
+    d3.select("#utility")
+      .text(req+" has "+image_data.length + " blocks out of "+nblocks);
+
+  return  { render_data: {img_dir: img_dir}, inblocks: image_data.length };
+ }
 }
@@ -320,7 +320,8 @@ export class KalmanFilter extends EventEmitter implements Predictor {
         else if (this._data_type == "BYTES") {
         }*/
 
-        return {model: this._model, data: data};
+        // client time of the prediction, the server maps it to its own clock
+        return {model: this._model, data: data, time: Date.now()};
 
     }
 
@@ -11,6 +11,8 @@ export enum DistModel {
 export interface Prediction {
     model: string;
     data: any;
+    // client timestamp (ms) of the prediction
+    time?: number;
 }
 
 export interface Predictor extends EventEmitter {
@@ -0,0 +1,102 @@
+// Decoder of erasure coded blocks, the client side of src/apps/erasure.rs:
+// systematic Reed-Solomon over GF(256) (polynomial 0x11d) with Cauchy parity rows,
+// any k of the n coded blocks of a level give back its k source blocks.
+
+export interface CodedBlock {
+  level: number;
+  // j of the level's code
+  index: number;
+  k: number;
+  n: number;
+  // length of each source block, to remove the padding
+  lens: number[];
+  data: Uint8Array;
+}
+
+function gfMul(a: number, b: number): number {
+  let p = 0;
+  while (b) {
+    if (b & 1) p ^= a;
+    a <<= 1;
+    if (a & 0x100) a ^= 0x11d;
+    b >>= 1;
+  }
+  return p;
+}
+
+// a^254
+function gfInv(a: number): number {
+  let result = 1;
+  let base = a;
+  for (let exp = 254; exp > 0; exp >>= 1) {
+    if (exp & 1) result = gfMul(result, base);
+    base = gfMul(base, base);
+  }
+  return result;
+}
+
+function coefficient(k: number, j: number, i: number): number {
+  if (j < k) return i == j ? 1 : 0;
+  return gfInv(j ^ i);
+}
+
+// bincode CodedBlock: level, index, k, n as u32, lens as u64 length + u32s, data as u64 length + bytes
+export function parseCodedBlock(buffer: ArrayBuffer): CodedBlock {
+  let view = new DataView(buffer);
+  let offset = 0;
+  const u32 = () => { let v = view.getUint32(offset, true); offset += 4; return v; };
+  const len = () => { let v = view.getUint32(offset, true); offset += 8; return v; };
+
+  let level = u32(), index = u32(), k = u32(), n = u32();
+  let nlens = len();
+  let lens: number[] = [];
+  for (let i = 0; i < nlens; i++) lens.push(u32());
+  let datalen = len();
+  let data = new Uint8Array(buffer, offset, datalen);
+
+  return { level, index, k, n, lens, data };
+}
+
+// the source blocks of a level from k distinct coded blocks of it, undefined if there are fewer
+export function decodeLevel(blocks: CodedBlock[]): Uint8Array[] | undefined {
+  if (blocks.length == 0) return undefined;
+  let { k, lens } = blocks[0];
+
+  let rows: CodedBlock[] = [];
+  for (let b of blocks) {
+    if (rows.length == k) break;
+    if (!rows.some((r) => r.index == b.index)) rows.push(b);
+  }
+  if (rows.length < k) return undefined;
+
+  // invert the k x k matrix of the received rows, Gauss-Jordan
+  let m = rows.map((r) => Array.from({length: k}, (_, i) => coefficient(k, r.index, i)));
+  let inv = Array.from({length: k}, (_, r) => Array.from({length: k}, (_, c) => r == c ? 1 : 0));
+  for (let col = 0; col < k; col++) {
+    let pivot = col;
+    while (pivot < k && m[pivot][col] == 0) pivot++;
+    if (pivot == k) return undefined;
+    [m[col], m[pivot]] = [m[pivot], m[col]];
+    [inv[col], inv[pivot]] = [inv[pivot], inv[col]];
+
+    let scale = gfInv(m[col][col]);
+    m[col] = m[col].map((x) => gfMul(x, scale));
+    inv[col] = inv[col].map((x) => gfMul(x, scale));
+    for (let r = 0; r < k; r++) {
+      let factor = m[r][col];
+      if (r == col || factor == 0) continue;
+      m[r] = m[r].map((x, c) => x ^ gfMul(factor, m[col][c]));
+      inv[r] = inv[r].map((x, c) => x ^ gfMul(factor, inv[col][c]));
+    }
+  }
+
+  return inv.map((coefs, i) => {
+    let out = new Uint8Array(lens[i]);
+    coefs.forEach((c, r) => {
+      if (c == 0) return;
+      let data = rows[r].data;
+      for (let b = 0; b < out.length && b < data.length; b++) out[b] ^= gfMul(c, data[b]);
+    });
+    return out;
+  });
+}
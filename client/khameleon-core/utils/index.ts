@@ -1,3 +1,4 @@
 export * from "./utils";
 export * from "./syslogger";
 export * from "./ws";
+export * from "./erasure";
@@ -2,6 +2,15 @@ import { Header } from "../apps";
 
 export class WS {
   private _running: boolean = false;
+  // session token from the server and the last block we acked, to resume after a drop
+  private session: string = null;
+  private lastAck: number = 0;
+  private reconnectMs: number = WS.MIN_RECONNECT_MS;
+
+  // the server closes a socket with this code when a newer connection replaces it
+  static readonly CLOSE_REPLACED = 4001;
+  static readonly MIN_RECONNECT_MS = 500;
+  static readonly MAX_RECONNECT_MS = 30000;
 
   
   constructor(private wsUri, private onmessage) {
@@ -13,18 +22,40 @@ export class WS {
   }
 
   setup() {
-    let socket = new WebSocket(this.wsUri);
+    let uri = this.wsUri;
+    if (this.session !== null) {
+      let sep = uri.indexOf("?") >= 0 ? "&" : "?";
+      uri = uri + sep + "session=" + this.session + "&last_ack=" + this.lastAck;
+    }
+    let socket = new WebSocket(uri);
     socket.binaryType = "arraybuffer";
     socket.onopen= () => {
       console.log("connected webworker websocket");
       this._running = true;
+      this.reconnectMs = WS.MIN_RECONNECT_MS;
     }
 
     socket.onmessage = (event) => {
+      if (typeof event.data === "string") {
+        // clock synchronization: reply with the server time, receive and reply times
+        let received = Date.now();
+        let msg = JSON.parse(event.data);
+        if (msg.sync !== undefined) {
+          socket.send("sync " + msg.sync + " " + received + " " + Date.now());
+        } else if (msg.session !== undefined) {
+          console.log("ws session", msg.session, "resumed", msg.resumed, "replayed", msg.replayed);
+          this.session = msg.session;
+        } else if (msg.error !== undefined) {
+          console.log("ws server error", msg.error, msg.message);
+        }
+        return;
+      }
+
       // one block currently
       let { header,blockbuffer,  blockIdx } = this.decode_bytebuffer(event.data);
       if (blockIdx > 0) {
           socket.send(blockIdx+" "+Date.now());
+          this.lastAck = Math.max(this.lastAck, blockIdx);
       }
       
       this.onmessage(blockbuffer, header, blockIdx);
@@ -34,8 +65,17 @@ export class WS {
       console.log("ws webworker error", error);
     };
 
-    socket.onclose = (error) => {
-      console.log('closed ws', error);
+    socket.onclose = (event) => {
+      console.log('closed ws', event.code, event.reason);
+      this._running = false;
+      if (event.code === WS.CLOSE_REPLACED) {
+        // another connection took over the session, don't fight it
+        return;
+      }
+      // resume the session, the server replays the blocks we didn't ack;
+      // back off while the server stays unreachable
+      setTimeout(() => this.setup(), this.reconnectMs);
+      this.reconnectMs = Math.min(this.reconnectMs * 2, WS.MAX_RECONNECT_MS);
     };
 
     console.log("start websocket here", socket);
@@ -0,0 +1,193 @@
+/*
+ * Brushes over histogram bins, the queries of the crossfilter app, and their
+ * place on screen for the Gaussian model in `decoders::decode_model`.
+ *
+ * Every dimension is drawn as a histogram chart. Brushing a chart from bin `lo`
+ * to bin `hi - 1` filters the rows; the other charts show the filtered counts.
+ */
+use ndarray::Array2;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// layout row of brushes the mouse can't reach from the current state: zero width box
+const OFF_SCREEN: f32 = -1.0e9;
+
+/// key of the query without a brush
+pub const NO_BRUSH: &str = "*";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Dimension {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub bins: usize,
+}
+
+impl Dimension {
+    pub fn width(&self) -> f64 {
+        (self.max - self.min) / std::cmp::max(1, self.bins) as f64
+    }
+
+    /// value range [lo, hi) of bins [lo, hi)
+    pub fn range(&self, lo: usize, hi: usize) -> (f64, f64) {
+        (self.min + lo as f64 * self.width(), self.min + hi as f64 * self.width())
+    }
+}
+
+/// bins [lo, hi) of `dimension`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Brush {
+    pub dimension: String,
+    pub lo: usize,
+    pub hi: usize,
+}
+
+impl Brush {
+    /// parse "<dimension>:<lo>:<hi>"
+    pub fn parse(key: &str) -> Option<Self> {
+        let mut parts = key.rsplitn(3, ':');
+        let hi = parts.next()?.parse::<usize>().ok()?;
+        let lo = parts.next()?.parse::<usize>().ok()?;
+        let dimension = parts.next()?.to_owned();
+        if lo >= hi {
+            return None;
+        }
+        Some(Brush{ dimension: dimension, lo: lo, hi: hi })
+    }
+
+    pub fn to_key(&self) -> String {
+        format!("{}:{}:{}", self.dimension, self.lo, self.hi)
+    }
+}
+
+/// every query: no brush, then every brush of every dimension
+pub fn queries(dimensions: &[Dimension]) -> Vec<String> {
+    let mut keys = vec![NO_BRUSH.to_owned()];
+    for d in dimensions {
+        for lo in 0..d.bins {
+            for hi in (lo + 1)..=d.bins {
+                keys.push(Brush{ dimension: d.name.clone(), lo: lo, hi: hi }.to_key());
+            }
+        }
+    }
+    keys
+}
+
+/// client side `Bounds` of a chart
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chart {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+/// the brush being drawn: the bin the mouse went down on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Anchor {
+    pub dimension: String,
+    pub bin: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct View {
+    pub charts: HashMap<String, Chart>,
+    #[serde(default)]
+    pub anchor: Option<Anchor>,
+}
+
+/// Layout matrix (xpw, xmw, yph, ymh per query, `keys` in query index order).
+/// Moving the mouse over bin b of a chart means the brush [b, b + 1) if no brush
+/// is being drawn, and the brush from the anchor to b if one is.
+pub fn layout_matrix(keys: &indexmap::IndexMap<String, usize>, dimensions: &[Dimension], view: &View) -> Array2<f32> {
+    let mut layout = Array2::from_elem((keys.len(), 4), OFF_SCREEN);
+    for d in dimensions {
+        let chart = match view.charts.get(&d.name) {
+            Some(chart) => chart,
+            None => continue,
+        };
+        let anchor = match &view.anchor {
+            Some(anchor) if anchor.dimension == d.name => Some(anchor.bin),
+            Some(_) => continue,
+            None => None,
+        };
+
+        let bin_w = chart.w / std::cmp::max(1, d.bins) as f32;
+        for b in 0..d.bins {
+            let (lo, hi) = match anchor {
+                Some(a) => (std::cmp::min(a, b), std::cmp::max(a, b) + 1),
+                None => (b, b + 1),
+            };
+            let key = Brush{ dimension: d.name.clone(), lo: lo, hi: hi }.to_key();
+            if let Some((i, _, _)) = keys.get_full(&key) {
+                let left = chart.x + b as f32 * bin_w;
+                let mut row = layout.row_mut(i);
+                row[0] = left + bin_w;
+                row[1] = left;
+                row[2] = chart.y + chart.h;
+                row[3] = chart.y;
+            }
+        }
+    }
+
+    layout
+}
+
+/// brush the mouse at (mx, my) is drawing, if it is over a chart
+pub fn brush_at(dimensions: &[Dimension], view: &View, mx: f32, my: f32) -> Option<Brush> {
+    for d in dimensions {
+        let chart = match view.charts.get(&d.name) {
+            Some(chart) if chart.x <= mx && mx < chart.x + chart.w && chart.y <= my && my < chart.y + chart.h => chart,
+            _ => continue,
+        };
+        let b = (((mx - chart.x) / chart.w) * d.bins as f32) as usize;
+        let b = std::cmp::min(b, d.bins.saturating_sub(1));
+        return match &view.anchor {
+            Some(anchor) if anchor.dimension == d.name =>
+                Some(Brush{ dimension: d.name.clone(), lo: std::cmp::min(anchor.bin, b), hi: std::cmp::max(anchor.bin, b) + 1 }),
+            Some(_) => None,
+            None => Some(Brush{ dimension: d.name.clone(), lo: b, hi: b + 1 }),
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brush_layout() {
+        let dims = vec![Dimension{ name: "delay".to_owned(), min: 0.0, max: 100.0, bins: 4 },
+                        Dimension{ name: "dist".to_owned(), min: 0.0, max: 10.0, bins: 2 }];
+        let keys: indexmap::IndexMap<String, usize> = queries(&dims).into_iter().map(|k| (k, 2)).collect();
+        // no brush, 4 * 5 / 2 brushes of delay and 3 of dist
+        assert_eq!(keys.len(), 1 + 10 + 3);
+        assert_eq!(Brush::parse("a:b:1:3"), Some(Brush{ dimension: "a:b".to_owned(), lo: 1, hi: 3 }));
+        assert_eq!(Brush::parse("delay:3:3"), None);
+        assert_eq!(dims[0].range(1, 3), (25.0, 75.0));
+
+        let mut charts = HashMap::new();
+        charts.insert("delay".to_owned(), Chart{ x: 0.0, y: 0.0, w: 400.0, h: 100.0 });
+        charts.insert("dist".to_owned(), Chart{ x: 0.0, y: 100.0, w: 200.0, h: 100.0 });
+        let mut view = View{ charts: charts, anchor: None };
+
+        // single bin brushes under each bin
+        let layout = layout_matrix(&keys, &dims, &view);
+        let i = keys.get_full("delay:1:2").unwrap().0;
+        assert_eq!(layout.row(i).to_vec(), vec![200.0, 100.0, 100.0, 0.0]);
+        let i = keys.get_full("dist:0:1").unwrap().0;
+        assert_eq!(layout.row(i).to_vec(), vec![100.0, 0.0, 200.0, 100.0]);
+        assert_eq!(layout.row(0)[1], OFF_SCREEN);
+
+        // brushing delay from bin 1: bin 3 means [1, 4), dist can't be brushed meanwhile
+        view.anchor = Some(Anchor{ dimension: "delay".to_owned(), bin: 1 });
+        let layout = layout_matrix(&keys, &dims, &view);
+        let i = keys.get_full("delay:1:4").unwrap().0;
+        assert_eq!(layout.row(i).to_vec(), vec![400.0, 300.0, 100.0, 0.0]);
+        let i = keys.get_full("dist:0:1").unwrap().0;
+        assert_eq!(layout.row(i)[1], OFF_SCREEN);
+        assert_eq!(brush_at(&dims, &view, 50.0, 50.0), Brush::parse("delay:0:2"));
+        assert_eq!(brush_at(&dims, &view, 50.0, 150.0), None);
+    }
+}
@@ -0,0 +1,280 @@
+/*
+ * CrossfilterApp: linked histograms over a table in an embedded SQLite database.
+ *
+ * A query is a brush over the bins of one dimension ("<dimension>:<lo>:<hi>", or "*"
+ * for no brush); its result is the histogram of every other dimension over the
+ * brushed rows. Blocks refine the result: counts on a sample of the rows for each
+ * of `strides`, then the exact counts. Results are counted by the manager's producer
+ * workers the first time the scheduler picks them (direct requests count them right
+ * away), and kept one value per block in a `BlockStore` next to the database.
+ *
+ * Server config (`app` section):
+ *     {"crossfilter": {"db": "data/flights.sqlite", "table": "flights", "cache": "data/crossfilter",
+ *                      "strides": [100, 10], "utility": [0.6, 0.85, 1.0],
+ *                      "dimensions": [{"name": "dep_delay", "min": -20, "max": 140, "bins": 16}, ...]}}
+ *
+ * Client distribution (PredictorState.data), the Gaussian over the mouse position
+ * and the charts, with the bin the mouse went down on while a brush is drawn:
+ *     {"dist": {"g": {"<delta ms>": {"xmu", "ymu", "xsigma", "ysigma"}}, "p": {"a", "X", "Y"}},
+ *      "state": {"charts": {dimension: {"x", "y", "w", "h"}}, "anchor"?: {"dimension", "bin"}}}
+ */
+use rusqlite::Connection;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use super::brush::{self, Brush, Dimension, View, NO_BRUSH};
+use super::sqlite::{self, Histogram};
+use crate::apps::{AppTrait, Producer};
+use crate::ds;
+use crate::scheduler::{self, decoders};
+use crate::backend;
+
+pub struct CrossfilterApp {
+    blocks_per_query: indexmap::IndexMap<String, usize>,
+    utility: Vec<f32>,
+    blocksize: usize,
+    counter: Arc<Counter>,
+    view: View,
+}
+
+/// Counts the results and caches them, shared with the producer workers
+pub struct Counter {
+    db_path: String,
+    /// used by one worker at a time, the others open their own
+    conn: Mutex<Connection>,
+    table: String,
+    dimensions: Vec<Dimension>,
+    /// sample 1 row out of stride, for each block but the exact one
+    strides: Vec<usize>,
+    /// query keys in index order
+    keys: Vec<String>,
+    /// computed results, keyed by `prefix` and query
+    backend: backend::blockstore::BlockStore,
+    /// fingerprint of the table, dimensions and strides: results of another config aren't reused
+    prefix: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CountBlock {
+    pub block_id: u32,
+    /// 1 for exact counts
+    pub stride: u32,
+    pub histograms: Vec<Histogram>,
+}
+
+/// appstate: specific data passed at initialization state from the client
+/// config: configuration data passed from the server
+pub fn new(_appstate: &ds::AppState, config: serde_json::Value) -> Result<CrossfilterApp, String> {
+    let config = &config["crossfilter"];
+    let db_path = config["db"].as_str().unwrap_or("data/crossfilter.sqlite").to_string();
+    let table = config["table"].as_str().unwrap_or("data").to_string();
+    let cache_path = config["cache"].as_str().unwrap_or("data/crossfilter").to_string();
+    let dimensions: Vec<Dimension> = serde_json::from_value(config["dimensions"].clone())
+        .map_err(|err| format!("crossfilter: dimensions should be [{{name, min, max, bins}}]: {}", err))?;
+    let strides: Vec<usize> = serde_json::from_value(config["strides"].clone()).unwrap_or(vec![100, 10]);
+
+    info!("1) open sqlite database {:?}", db_path);
+    if !std::path::Path::new(&db_path).exists() {
+        return Err(format!("database doesn't exist {:?}", db_path));
+    }
+    let conn = Connection::open(&db_path).map_err(|err| format!("couldn't open the sqlite database {:?}: {}", db_path, err))?;
+    match sqlite::count_rows(&conn, &table) {
+        Ok(rows) => info!("{} rows in {:?}", rows, table),
+        Err(err) => return Err(format!("couldn't read table {:?}: {}", table, err)),
+    }
+
+    info!("2) one query per brush");
+    let nblocks = strides.len() + 1;
+    let blocks_per_query: indexmap::IndexMap<String, usize> = brush::queries(&dimensions).into_iter()
+        .map(|k| (k, nblocks))
+        .collect();
+
+    // default: the first sample is most of the answer
+    let utility: Vec<f32> = match serde_json::from_value::<Vec<f32>>(config["utility"].clone()) {
+        Ok(ref u) if u.len() == nblocks => u.clone(),
+        _ => (0..nblocks).map(|i| ((i + 1) as f32 / nblocks as f32).sqrt()).collect(),
+    };
+
+    // every block holds the same bins
+    let histograms = dimensions.iter().skip(1)
+        .map(|d| Histogram{ dimension: d.name.clone(), counts: vec![0; d.bins] })
+        .collect();
+    let blocksize = bincode::serialized_size(&CountBlock{ block_id: 0, stride: 1, histograms }).unwrap_or(0) as usize;
+    info!("3) {} queries, {} blocks of {} bytes each", blocks_per_query.len(), nblocks, blocksize);
+
+    let mut hasher = DefaultHasher::new();
+    serde_json::json!([table, dimensions, strides]).to_string().hash(&mut hasher);
+    let prefix = format!("{:016x}", hasher.finish());
+
+    let backend = backend::blockstore::BlockStore::load(&cache_path, Counter::split_blocks)?;
+    let keys = blocks_per_query.keys().cloned().collect();
+    let counter = Counter{ db_path, conn: Mutex::new(conn), table, dimensions, strides, keys, backend, prefix };
+    Ok(CrossfilterApp{ blocks_per_query, utility, blocksize, counter: Arc::new(counter), view: View::default() })
+}
+
+impl Counter {
+    fn cache_key(&self, key: &str) -> String {
+        format!("{}/{}", self.prefix, key)
+    }
+
+    /// encoded blocks of a value of the one value per result layout, see `BlockStore::migrate`;
+    /// the utility is the app's
+    fn split_blocks(v: &[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let blocks: Vec<CountBlock> = bincode::deserialize(v).ok()?;
+        Some((blocks.iter().map(|b| bincode::serialize(b).unwrap()).collect(), Vec::new()))
+    }
+
+    /// count and cache the result of `key` unless it's cached, returns its cache key
+    fn ensure(&self, key: &str) -> Option<String> {
+        let cache_key = self.cache_key(key);
+        if self.backend.contains(&cache_key) {
+            return Some(cache_key);
+        }
+
+        let brush = match key {
+            NO_BRUSH => None,
+            _ => Some(Brush::parse(key)?),
+        };
+        let blocks = match self.count(brush.as_ref()) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                error!("counting {:?} failed: {}", key, err);
+                return None;
+            }
+        };
+
+        // sled trees are shared between clones
+        let encoded: Vec<Vec<u8>> = blocks.iter().map(|b| bincode::serialize(b).unwrap()).collect();
+        let mut backend = self.backend.clone();
+        backend.put_query(&cache_key, &encoded, Vec::new());
+        Some(cache_key)
+    }
+
+    /// block `index` of the result of `key`, counted first if needed
+    fn get_block(&self, key: &str, index: usize) -> Option<CountBlock> {
+        let cache_key = self.ensure(key)?;
+        let bytes = self.backend.get_block(&cache_key, index)?;
+        bincode::deserialize(&bytes).ok()
+    }
+
+    fn count(&self, brush: Option<&Brush>) -> rusqlite::Result<Vec<CountBlock>> {
+        let own;
+        let shared = self.conn.try_lock();
+        let conn = match shared {
+            Ok(ref conn) => &**conn,
+            Err(_) => {
+                own = Connection::open(&self.db_path)?;
+                &own
+            }
+        };
+
+        self.strides.iter().chain(std::iter::once(&1)).enumerate().map(|(i, &stride)| {
+            let histograms = sqlite::histograms(conn, &self.table, &self.dimensions, brush, stride)?;
+            Ok(CountBlock{ block_id: i as u32, stride: stride as u32, histograms })
+        }).collect()
+    }
+}
+
+impl Producer for Counter {
+    fn is_ready(&self, index: usize) -> bool {
+        self.keys.get(index).map(|key| self.backend.contains(&self.cache_key(key))).unwrap_or(false)
+    }
+
+    fn produce(&self, index: usize) -> Result<(), String> {
+        let key = self.keys.get(index).ok_or(format!("no query {}", index))?;
+        match self.ensure(key) {
+            Some(_) => Ok(()),
+            None => Err(format!("couldn't count {:?}", key)),
+        }
+    }
+}
+
+impl CrossfilterApp {
+    /// reads blocks incache..incache + count only; they're stored under the cache key
+    /// and framed with the query key
+    fn get_nblocks_bytes(&self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let cache_key = self.counter.ensure(key)?;
+        let nblocks = *self.blocks_per_query.get(key)?;
+
+        let end = std::cmp::min(nblocks, incache + count);
+        let mut sblocks = Vec::new();
+        for i in incache..end {
+            let block = self.counter.backend.get_block(&cache_key, i)?;
+            let mut bytebuffer = bincode::serialize(&(i as u32)).unwrap();
+            bytebuffer.extend(bincode::serialize(&(nblocks as u32)).unwrap());
+            bytebuffer.extend(bincode::serialize(&key).unwrap());
+            bytebuffer.extend(block);
+            sblocks.push(ds::StreamBlock::Binary(bytebuffer));
+        }
+
+        Some(sblocks)
+    }
+}
+
+impl AppTrait for CrossfilterApp {
+    fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+        (self.blocks_per_query.clone(), self.utility.clone())
+    }
+
+    fn get_nblocks_byindex(&mut self, index: usize, count: usize,
+                           incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let key = self.blocks_per_query.get_index(index).map(|(k, _)| k.clone())?;
+        self.get_nblocks_bytes(&key, count, incache)
+    }
+
+    fn get_nblocks_bykey(&mut self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        self.get_nblocks_bytes(key, count, incache)
+    }
+
+    /// the brushes the mouse would draw over the charts
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
+        let data = userstate.data;
+        if let Some(state) = data.get("state") {
+            match serde_json::from_value(state.clone()) {
+                Ok(view) => self.view = view,
+                Err(err) => debug!("no charts in the client state {:?}", err),
+            }
+        }
+
+        let dist = &data["dist"];
+        let mut prob = match dist.get("g") {
+            Some(g) => {
+                let layout = brush::layout_matrix(&self.blocks_per_query, &self.counter.dimensions, &self.view);
+                decoders::decode_model(g, &layout)
+            },
+            None => scheduler::Prob::new(self.blocks_per_query.len()),
+        };
+
+        let (alpha, x, y) = decoders::decode_point_model(&dist["p"]);
+        let index = brush::brush_at(&self.counter.dimensions, &self.view, x as f32, y as f32)
+            .and_then(|b| self.blocks_per_query.get_full(&b.to_key()).map(|(i, _, _)| i));
+        if let Some(index) = index {
+            prob.set_point_dist(alpha, index);
+        }
+
+        prob
+    }
+
+    fn get_producer(&self) -> Option<Arc<dyn Producer>> {
+        Some(self.counter.clone() as Arc<dyn Producer>)
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.blocksize
+    }
+
+    fn get_initstate(&mut self) -> String {
+        // the charts before any brush
+        let exact = self.counter.strides.len();
+        let initial = self.counter.get_block(NO_BRUSH, exact);
+        serde_json::json!({"dimensions": self.counter.dimensions,
+                           "histograms": initial.map(|b| b.histograms)}).to_string()
+    }
+
+    fn shutdown(&mut self) {
+        info!("flush cached results");
+        self.counter.backend.clone().flush();
+    }
+}
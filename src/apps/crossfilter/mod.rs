@@ -0,0 +1,4 @@
+pub mod crossfilter;
+pub mod brush;
+pub mod sqlite;
+pub use crossfilter::*;
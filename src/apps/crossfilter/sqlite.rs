@@ -0,0 +1,106 @@
+/*
+ * Histograms of the crossfilter app, counted by SQLite.
+ *
+ * Sampled counts only scan the rows whose rowid is a multiple of the stride and
+ * scale the counts up; a stride of 1 is the exact answer.
+ */
+use rusqlite::{Connection, NO_PARAMS};
+use serde_derive::{Deserialize, Serialize};
+
+use super::brush::{Brush, Dimension};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    pub dimension: String,
+    /// estimated rows per bin
+    pub counts: Vec<u64>,
+}
+
+/// quote an identifier for SQL
+fn ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Histogram of every dimension but the brushed one over the rows in `brush`
+/// (all rows if None), counted on one row out of `stride`.
+pub fn histograms(conn: &Connection, table: &str, dimensions: &[Dimension],
+                  brush: Option<&Brush>, stride: usize) -> rusqlite::Result<Vec<Histogram>> {
+    let stride = std::cmp::max(1, stride);
+    let mut filters: Vec<String> = Vec::new();
+    if let Some(brush) = brush {
+        let d = match dimensions.iter().find(|d| d.name == brush.dimension) {
+            Some(d) => d,
+            None => return Err(rusqlite::Error::InvalidColumnName(brush.dimension.clone())),
+        };
+        let (lo, hi) = d.range(brush.lo, brush.hi);
+        // the last bin holds the maximum
+        let op = if brush.hi >= d.bins { "<=" } else { "<" };
+        filters.push(format!("{col} >= {lo} AND {col} {op} {hi}", col = ident(&d.name), lo = lo, op = op, hi = hi));
+    }
+    if stride > 1 {
+        filters.push(format!("rowid % {} = 0", stride));
+    }
+
+    let mut histograms = Vec::new();
+    for d in dimensions.iter().filter(|d| brush.map(|b| b.dimension != d.name).unwrap_or(true)) {
+        let col = ident(&d.name);
+        let mut wheres = filters.clone();
+        wheres.push(format!("{col} >= {min} AND {col} <= {max}", col = col, min = d.min, max = d.max));
+        let sql = format!("SELECT MIN(CAST(({col} - {min}) / {width} AS INTEGER), {last}) AS bin, COUNT(*) \
+                           FROM {table} WHERE {wheres} GROUP BY bin",
+                          col = col, min = d.min, width = d.width(), last = d.bins.saturating_sub(1),
+                          table = ident(table), wheres = wheres.join(" AND "));
+
+        let mut counts = vec![0u64; d.bins];
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+        for row in rows {
+            let (bin, count) = row?;
+            if let Some(c) = counts.get_mut(bin as usize) {
+                *c = count as u64 * stride as u64;
+            }
+        }
+        histograms.push(Histogram{ dimension: d.name.clone(), counts: counts });
+    }
+
+    Ok(histograms)
+}
+
+/// number of rows in `table`
+pub fn count_rows(conn: &Connection, table: &str) -> rusqlite::Result<u64> {
+    let count: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {}", ident(table)), NO_PARAMS, |row| row.get(0))?;
+    Ok(count as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histograms() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE flights (delay REAL, dist REAL)", NO_PARAMS).unwrap();
+        for i in 0..100 {
+            conn.execute(&format!("INSERT INTO flights VALUES ({}, {})", i, (i % 10) as f64), NO_PARAMS).unwrap();
+        }
+        let dims = vec![Dimension{ name: "delay".to_owned(), min: 0.0, max: 99.0, bins: 4 },
+                        Dimension{ name: "dist".to_owned(), min: 0.0, max: 10.0, bins: 2 }];
+        assert_eq!(count_rows(&conn, "flights").unwrap(), 100);
+
+        let all = histograms(&conn, "flights", &dims, None, 1).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].counts.iter().sum::<u64>(), 100);
+        assert_eq!(all[1].counts, vec![50, 50]);
+
+        // the brushed dimension is left out, the last bin includes the maximum
+        let brush = Brush::parse("delay:2:4").unwrap();
+        let exact = histograms(&conn, "flights", &dims, Some(&brush), 1).unwrap();
+        assert_eq!(exact.len(), 1);
+        assert_eq!(exact[0].dimension, "dist");
+        assert_eq!(exact[0].counts.iter().sum::<u64>(), 50);
+
+        // sampled counts are scaled up
+        let sampled = histograms(&conn, "flights", &dims, None, 10).unwrap();
+        assert_eq!(sampled[0].counts.iter().sum::<u64>(), 100);
+    }
+}
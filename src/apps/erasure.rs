@@ -0,0 +1,320 @@
+/*
+ * Erasure coding of progressive blocks, so any k of the n coded blocks of a quality
+ * level reconstruct it.
+ *
+ * A query's blocks are split in levels of k consecutive blocks. Each level is coded
+ * with a systematic Reed-Solomon code over GF(256): coded blocks 0..k are the
+ * blocks themselves, k..n are parity rows of a Cauchy matrix, and any k rows of the
+ * code are invertible. With losses or reordering the sender sends another coded
+ * block of the level instead of the missing one, and the scheduler's utility counts
+ * distinct blocks the client holds instead of the length of a prefix.
+ *
+ * Apps opt in by returning a `Layout` per query from `AppTrait::get_erasure`; the
+ * blocks they send for coded index i are `CodedBlock`s, decoded by the client once
+ * k of a level arrived.
+ */
+use serde_derive::{Deserialize, Serialize};
+
+/// GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1d;
+        }
+        b >>= 1;
+    }
+    p
+}
+
+/// a^254, the inverse of a != 0
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exp = 254u32;
+    while exp > 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Code {
+    pub k: usize,
+    pub n: usize,
+}
+
+impl Code {
+    pub fn new(k: usize, n: usize) -> Result<Self, String> {
+        if k == 0 || k > n || n > 256 {
+            return Err(format!("erasure code: expected 0 < k <= n <= 256, got k={} n={}", k, n));
+        }
+        Ok(Code{ k: k, n: n })
+    }
+
+    /// coefficient of source block `i` in coded block `j`
+    fn coefficient(&self, j: usize, i: usize) -> u8 {
+        if j < self.k {
+            return if i == j { 1 } else { 0 };
+        }
+        // Cauchy row: x = j, y = i are distinct since j >= k > i
+        gf_inv((j as u8) ^ (i as u8))
+    }
+
+    /// coded block `j` of `sources`, shorter sources are zero padded to the longest
+    pub fn encode(&self, sources: &[&[u8]], j: usize) -> Vec<u8> {
+        let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut out = vec![0u8; len];
+        for (i, source) in sources.iter().enumerate().take(self.k) {
+            let c = self.coefficient(j, i);
+            if c == 0 {
+                continue;
+            }
+            for (o, &s) in out.iter_mut().zip(source.iter()) {
+                *o ^= gf_mul(c, s);
+            }
+        }
+        out
+    }
+
+    /// The zero padded sources from any k distinct coded blocks (j, bytes), None if
+    /// there are fewer.
+    pub fn decode(&self, coded: &[(usize, &[u8])]) -> Option<Vec<Vec<u8>>> {
+        let mut rows: Vec<(usize, &[u8])> = Vec::with_capacity(self.k);
+        for &(j, bytes) in coded {
+            if j < self.n && rows.iter().all(|&(r, _)| r != j) {
+                rows.push((j, bytes));
+            }
+            if rows.len() == self.k {
+                break;
+            }
+        }
+        if rows.len() < self.k {
+            return None;
+        }
+        let len = rows.iter().map(|(_, b)| b.len()).max().unwrap_or(0);
+
+        // invert the k x k matrix of the received rows, Gauss-Jordan
+        let k = self.k;
+        let mut m: Vec<Vec<u8>> = rows.iter().map(|&(j, _)| (0..k).map(|i| self.coefficient(j, i)).collect()).collect();
+        let mut inv: Vec<Vec<u8>> = (0..k).map(|r| (0..k).map(|c| if r == c { 1 } else { 0 }).collect()).collect();
+        for col in 0..k {
+            let pivot = (col..k).find(|&r| m[r][col] != 0)?;
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = gf_inv(m[col][col]);
+            for x in m[col].iter_mut().chain(inv[col].iter_mut()) {
+                *x = gf_mul(*x, scale);
+            }
+            let (pivot_m, pivot_inv) = (m[col].clone(), inv[col].clone());
+            for (r, (row, inv_row)) in m.iter_mut().zip(inv.iter_mut()).enumerate() {
+                let factor = row[col];
+                if r == col || factor == 0 {
+                    continue;
+                }
+                for (x, &p) in row.iter_mut().zip(pivot_m.iter()).chain(inv_row.iter_mut().zip(pivot_inv.iter())) {
+                    *x ^= gf_mul(factor, p);
+                }
+            }
+        }
+
+        let sources = inv.iter().map(|coefs| {
+            let mut out = vec![0u8; len];
+            for (&c, &(_, bytes)) in coefs.iter().zip(rows.iter()) {
+                if c == 0 {
+                    continue;
+                }
+                for (o, &b) in out.iter_mut().zip(bytes.iter()) {
+                    *o ^= gf_mul(c, b);
+                }
+            }
+            out
+        }).collect();
+        Some(sources)
+    }
+}
+
+/// payload of a coded block, see `Code::encode`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CodedBlock {
+    pub level: u32,
+    /// j of the level's code
+    pub index: u32,
+    pub k: u32,
+    pub n: u32,
+    /// length of each source block of the level, to remove the padding
+    pub lens: Vec<u32>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    /// first source block of the level
+    pub start: usize,
+    pub code: Code,
+}
+
+/// levels of a query, coded index i is coded block i - (n of the levels before) of its level
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Layout {
+    pub levels: Vec<Level>,
+}
+
+impl Layout {
+    /// `nblocks` source blocks in levels of `k`, each with `n - k` parity blocks;
+    /// the last level may have fewer sources
+    pub fn split(nblocks: usize, k: usize, n: usize) -> Result<Self, String> {
+        Code::new(k, n)?;
+        let parity = n - k;
+        let levels = (0..nblocks).step_by(k).map(|start| {
+            let k = std::cmp::min(k, nblocks - start);
+            Level{ start: start, code: Code{ k: k, n: k + parity } }
+        }).collect();
+        Ok(Layout{ levels: levels })
+    }
+
+    /// source blocks, the distinct blocks the client needs for every level
+    pub fn sources(&self) -> usize {
+        self.levels.iter().map(|l| l.code.k).sum()
+    }
+
+    /// coded blocks of every level
+    pub fn coded(&self) -> usize {
+        self.levels.iter().map(|l| l.code.n).sum()
+    }
+
+    /// (level, j) of coded index `i`
+    pub fn locate(&self, i: usize) -> Option<(usize, usize)> {
+        let mut first = 0;
+        for (l, level) in self.levels.iter().enumerate() {
+            if i < first + level.code.n {
+                return Some((l, i - first));
+            }
+            first += level.code.n;
+        }
+        None
+    }
+
+    /// Coded index to send to a client holding `held` distinct blocks of the query,
+    /// after `sent[l]` blocks of level l were sent (missing levels had none). The level
+    /// is the first one the client can't decode yet; sends to a level walk through its
+    /// coded blocks, so lost blocks are replaced by new ones. None if every level can
+    /// be decoded.
+    pub fn next(&self, held: usize, sent: &[usize]) -> Option<usize> {
+        let (mut needed, mut first) = (0, 0);
+        for (l, level) in self.levels.iter().enumerate() {
+            if held < needed + level.code.k {
+                let j = sent.get(l).cloned().unwrap_or(0) % level.code.n;
+                return Some(first + j);
+            }
+            needed += level.code.k;
+            first += level.code.n;
+        }
+        None
+    }
+
+    /// Utility of holding c + 1 distinct blocks, from the utility of the first
+    /// i + 1 source blocks. A level decodes once k of its blocks are there; its gain
+    /// is spread over them, so every distinct block has a marginal utility the
+    /// scheduler can climb.
+    pub fn utility(&self, source_utility: &[f32]) -> Vec<f32> {
+        let mut utility = Vec::with_capacity(self.sources());
+        let mut decoded = 0.0;
+        let mut needed = 0;
+        for level in self.levels.iter() {
+            needed += level.code.k;
+            let next = source_utility.get(needed.saturating_sub(1)).cloned()
+                .unwrap_or_else(|| source_utility.last().cloned().unwrap_or(0.0));
+            let k = level.code.k as f32;
+            for i in 1..level.code.k {
+                utility.push(decoded + (next - decoded) * i as f32 / k);
+            }
+            utility.push(next);
+            decoded = next;
+        }
+        utility
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_k_of_n() {
+        let code = Code::new(3, 5).unwrap();
+        let sources: Vec<Vec<u8>> = vec![vec![1, 2, 3, 4], vec![250, 7], vec![9, 9, 9]];
+        let refs: Vec<&[u8]> = sources.iter().map(|s| s.as_slice()).collect();
+        let coded: Vec<Vec<u8>> = (0..5).map(|j| code.encode(&refs, j)).collect();
+        // systematic
+        assert_eq!(coded[1], vec![250, 7, 0, 0]);
+
+        let padded: Vec<Vec<u8>> = sources.iter().map(|s| { let mut s = s.clone(); s.resize(4, 0); s }).collect();
+        for skip in &[(0, 1), (0, 4), (2, 3), (3, 4)] {
+            let received: Vec<(usize, &[u8])> = (0..5).filter(|&j| j != skip.0 && j != skip.1)
+                .map(|j| (j, coded[j].as_slice())).collect();
+            assert_eq!(code.decode(&received), Some(padded.clone()));
+        }
+        assert_eq!(code.decode(&[(3, &coded[3][..]), (3, &coded[3][..]), (4, &coded[4][..])]), None);
+        assert!(Code::new(4, 3).is_err());
+    }
+
+    #[test]
+    fn test_layout() {
+        // 5 source blocks: levels of 2 with 1 parity each, the last one has 1 source
+        let layout = Layout::split(5, 2, 3).unwrap();
+        assert_eq!(layout.levels.iter().map(|l| (l.start, l.code.k, l.code.n)).collect::<Vec<_>>(),
+                   vec![(0, 2, 3), (2, 2, 3), (4, 1, 2)]);
+        assert_eq!((layout.sources(), layout.coded()), (5, 8));
+        assert_eq!(layout.locate(4), Some((1, 1)));
+        assert_eq!(layout.locate(8), None);
+
+        // no loss: the systematic blocks in order
+        assert_eq!(layout.next(0, &[]), Some(0));
+        assert_eq!(layout.next(2, &[2]), Some(3));
+        // one block of the first level lost: the parity block instead
+        assert_eq!(layout.next(1, &[2]), Some(2));
+        assert_eq!(layout.next(5, &[2, 2, 1]), None);
+
+        // each level's gain is spread over its blocks
+        let utility = layout.utility(&[0.2, 0.4, 0.6, 0.8, 1.0]);
+        let expected = [0.2, 0.4, 0.6, 0.8, 1.0];
+        assert_eq!(utility.len(), expected.len());
+        assert!(utility.iter().zip(expected.iter()).all(|(u, e)| (u - e).abs() < 1e-6), "{:?}", utility);
+    }
+
+    #[test]
+    fn test_next_with_losses_across_levels() {
+        // levels of 2 with 2 parity blocks each
+        let layout = Layout::split(4, 2, 4).unwrap();
+        let (mut held, mut sent) = (0, vec![0; layout.levels.len()]);
+        let mut received: Vec<Vec<usize>> = vec![Vec::new(); layout.levels.len()];
+        // the first two sends of level 0 are lost, so is the first of level 1
+        let lost = [true, true, false, false, true, false, false];
+        let mut indices = Vec::new();
+        for &lost in lost.iter() {
+            let index = layout.next(held, &sent).unwrap();
+            let (level, j) = layout.locate(index).unwrap();
+            indices.push(index);
+            sent[level] += 1;
+            if !lost {
+                held += 1;
+                received[level].push(j);
+            }
+        }
+
+        // level 0 is decoded from its parity blocks, level 1 starts at its own first block
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(received, vec![vec![2, 3], vec![1, 2]]);
+        assert_eq!(layout.next(held, &sent), None);
+    }
+}
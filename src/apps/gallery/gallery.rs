@@ -0,0 +1,190 @@
+/*
+ * GalleryApp: scrollable, resizable grid of image thumbnails.
+ *
+ * Backend: `BlockStore` keyed by image name, each block a bincode ImageBlock holding
+ * a part of the image file, built with `build_backend`; or a packfile of it, see
+ * `src/bin/packfile.rs`.
+ *
+ * Server config (`app` section), the grid used until the client sends its layout:
+ *     {"gallery": {"db": "data/gallery", "pack"?: "data/gallery.pack",
+ *                  "columns": 6, "thumb_size": 128, "gap": 8}}
+ *
+ * The client sends its layout ({key: {x, y, w, h}} in page pixels) when the grid
+ * is resized, either to /layout or in the `layout` field of a distribution.
+ * Client distribution (PredictorState.data):
+ *     {"dist": {"g": {"<delta ms>": {"xmu", "ymu", "xsigma", "ysigma"}}, "p": {"a", "X", "Y"}},
+ *      "layout"?: {...}, "state"?: {"scroll": {"x", "y"}}}
+ */
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+use super::grid::{self, Grid, Layout, Scroll};
+use crate::apps::{self, AppTrait};
+use crate::ds;
+use crate::error::KhameleonError;
+use crate::scheduler::{self, decoders};
+use crate::backend;
+
+pub struct GalleryApp {
+    blocks_per_query: indexmap::IndexMap<String, usize>,
+    utility: Vec<f32>,
+    blocksize: usize,
+    /// sled store, or packfile if `pack` is set
+    backend: Arc<dyn backend::BlockSource>,
+
+    grid: Grid,
+    scroll: Scroll,
+    /// tells the scheduler the layout changed
+    state_change_flag: Arc<RwLock<bool>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImageBlock {
+    pub block_id: u32,
+    pub content: Vec<u8>,
+}
+
+/// appstate: specific data passed at initialization state from the client
+/// config: configuration data passed from the server
+pub fn new(_appstate: &ds::AppState, config: serde_json::Value,
+           state_change_flag: Arc<RwLock<bool>>) -> Result<GalleryApp, String> {
+    let config = &config["gallery"];
+    let db_path = config["db"].as_str().unwrap_or("data/gallery").to_string();
+    let columns = config["columns"].as_u64().unwrap_or(6) as usize;
+    let thumb_size = config["thumb_size"].as_f64().unwrap_or(128.0) as f32;
+    let gap = config["gap"].as_f64().unwrap_or(8.0) as f32;
+
+    info!("1) load images {:?}", config["pack"].as_str().unwrap_or(&db_path));
+    let backend = apps::open_source(config["pack"].as_str(), || {
+        if !std::path::Path::new(&db_path).exists() {
+            return Err(format!("backend is not initialized {:?}, see gallery::build_backend", db_path));
+        }
+        backend::blockstore::BlockStore::load(&db_path, GalleryApp::split_blocks)
+    })?;
+
+    info!("2) create an index of how many blocks/image from the metadata");
+    let metas = backend.queries();
+    let blocks_per_query: indexmap::IndexMap<String, usize> = metas.iter().map(|(k, m)| (k.clone(), m.nblocks)).collect();
+    let blocksize = metas.values().next().and_then(|m| m.sizes.first().cloned()).unwrap_or(0);
+
+    let max_blocks_count: usize = blocks_per_query.values().cloned().max().unwrap_or(0);
+    let utility = backend::blockstore::max_utility(&metas).unwrap_or_else(|| apps::linear_utility(max_blocks_count));
+
+    let layout = grid::grid_layout(blocks_per_query.keys(), columns, thumb_size, gap);
+    let (grid, _) = Grid::new(&blocks_per_query, &layout);
+    info!("3) {} images in {} columns", blocks_per_query.len(), columns);
+
+    Ok(GalleryApp{ blocks_per_query, utility, blocksize, backend,
+                   grid, scroll: Scroll::default(), state_change_flag })
+}
+
+/// Split every file in `images_dir` in blocks of `blocksize` bytes and store them
+/// in a `BlockStore` at `db_path`, keyed by file name. Returns the number of images.
+pub fn build_backend(images_dir: &str, db_path: &str, blocksize: usize) -> std::io::Result<usize> {
+    let mut backend = backend::blockstore::BlockStore::new(db_path.to_string());
+    let mut count = 0;
+
+    for entry in std::fs::read_dir(images_dir)? {
+        let path = entry?.path();
+        let key = match path.file_name().and_then(|s| s.to_str()) {
+            Some(name) if path.is_file() => name.to_owned(),
+            _ => continue,
+        };
+
+        let content = std::fs::read(&path)?;
+        let blocks: Vec<Vec<u8>> = content.chunks(std::cmp::max(1, blocksize)).enumerate()
+            .map(|(i, c)| bincode::serialize(&ImageBlock{ block_id: i as u32, content: c.to_vec() }).unwrap())
+            .collect();
+        backend.put_query(&key, &blocks, apps::linear_utility(blocks.len()));
+        count += 1;
+    }
+
+    backend.flush();
+    Ok(count)
+}
+
+impl GalleryApp {
+    /// encoded blocks of a value of the one value per image layout, see `BlockStore::migrate`
+    pub fn split_blocks(v: &[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let blocks: Vec<ImageBlock> = bincode::deserialize(v).ok()?;
+        let encoded: Vec<Vec<u8>> = blocks.iter().map(|b| bincode::serialize(b).unwrap()).collect();
+        Some((encoded, apps::linear_utility(blocks.len())))
+    }
+
+    /// reads blocks incache..incache + count only
+    fn get_nblocks_bytes(&self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let nblocks = *self.blocks_per_query.get(key)?;
+        Some(apps::stream_blocks(&*self.backend, key, nblocks, count, incache))
+    }
+}
+
+impl AppTrait for GalleryApp {
+    fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+        (self.blocks_per_query.clone(), self.utility.clone())
+    }
+
+    fn get_nblocks_byindex(&mut self, index: usize, count: usize,
+                           incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let key = self.blocks_per_query.get_index(index).map(|(k, _)| k.clone())?;
+        self.get_nblocks_bytes(&key, count, incache)
+    }
+
+    fn get_nblocks_bykey(&mut self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        self.get_nblocks_bytes(key, count, incache)
+    }
+
+    /// the thumbnails under the mouse, in the scrolled grid
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
+        let data = userstate.data;
+        if let Some(layout) = data.get("layout") {
+            if let Err(err) = self.update_layout(layout.clone()) {
+                error!("ignore layout from the distribution: {}", err);
+            }
+        }
+        if let Some(scroll) = data.get("state").and_then(|s| s.get("scroll")) {
+            match serde_json::from_value(scroll.clone()) {
+                Ok(scroll) => self.scroll = scroll,
+                Err(err) => debug!("bad scroll offset in the client state {:?}", err),
+            }
+        }
+
+        let dist = &data["dist"];
+        let mut prob = match dist.get("g") {
+            Some(g) => decoders::decode_model(g, &self.grid.layout_matrix(&self.scroll)),
+            None => scheduler::Prob::new(self.blocks_per_query.len()),
+        };
+
+        let (alpha, x, y) = decoders::decode_point_model(&dist["p"]);
+        if let Some(index) = self.grid.item_at(&self.scroll, x as f32, y as f32) {
+            prob.set_point_dist(alpha, index);
+        }
+
+        prob
+    }
+
+    /// Lay the grid out again, the query indices stay the same. The scheduler
+    /// plans from scratch with the next distribution.
+    fn update_layout(&mut self, layout: serde_json::Value) -> Result<usize, KhameleonError> {
+        let layout: Layout = serde_json::from_value(layout)?;
+        let (grid, unknown) = Grid::new(&self.blocks_per_query, &layout);
+        if !unknown.is_empty() {
+            warn!("layout has {} unknown images, e.g. {:?}", unknown.len(), unknown[0]);
+        }
+        if grid.placed() == 0 && !layout.is_empty() {
+            return Err(KhameleonError::BadRequest("layout has none of the gallery's images".to_owned()));
+        }
+
+        self.grid = grid;
+        *self.state_change_flag.write().unwrap() = true;
+        Ok(self.grid.placed())
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.blocksize
+    }
+
+    fn get_initstate(&mut self) -> String {
+        let keys: Vec<&String> = self.blocks_per_query.keys().collect();
+        serde_json::json!({"images": keys}).to_string()
+    }
+}
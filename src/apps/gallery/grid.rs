@@ -0,0 +1,155 @@
+/*
+ * Thumbnail grid: maps the client's layout (query key -> bounds) to the layout
+ * matrix of `decoders::decode_model`, rows in query index order.
+ *
+ * Bounds are in page pixels, i.e. relative to the top left corner of the scrolled
+ * grid, so scrolling doesn't change the layout; resizing the window or the
+ * thumbnails does. Mouse positions are relative to the viewport.
+ */
+use ndarray::Array2;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// layout row of thumbnails that aren't in the grid: zero width box
+const OFF_SCREEN: f32 = -1.0e9;
+
+/// client side `Bounds`: top left corner, width and height
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bounds {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+pub type Layout = HashMap<String, Bounds>;
+
+/// scroll offset of the grid, in page pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scroll {
+    #[serde(default)]
+    pub x: f32,
+    #[serde(default)]
+    pub y: f32,
+}
+
+/// `keys` laid out row by row in `columns` columns of `size` pixel thumbnails
+pub fn grid_layout<'a, I: Iterator<Item = &'a String>>(keys: I, columns: usize, size: f32, gap: f32) -> Layout {
+    let columns = std::cmp::max(1, columns);
+    keys.enumerate().map(|(i, key)| {
+        let (row, col) = (i / columns, i % columns);
+        let bounds = Bounds{ x: col as f32 * (size + gap), y: row as f32 * (size + gap), w: size, h: size };
+        (key.clone(), bounds)
+    }).collect()
+}
+
+pub struct Grid {
+    /// xpw, xmw, yph, ymh per query index, in page pixels
+    matrix: Array2<f32>,
+    /// queries with bounds in the layout
+    placed: usize,
+}
+
+impl Grid {
+    /// Map `layout` to query indices through `queries` (query keys in index order).
+    /// The query indices never change: keys missing from the layout are off screen
+    /// and keys the app doesn't know are skipped.
+    ///
+    /// Returns the grid and the skipped keys.
+    pub fn new(queries: &indexmap::IndexMap<String, usize>, layout: &Layout) -> (Self, Vec<String>) {
+        let mut matrix = Array2::from_elem((queries.len(), 4), OFF_SCREEN);
+        let mut placed = 0;
+        let mut unknown = Vec::new();
+        for (key, bounds) in layout.iter() {
+            let index = match queries.get_full(key) {
+                Some((index, _, _)) => index,
+                None => {
+                    unknown.push(key.clone());
+                    continue;
+                }
+            };
+
+            let mut row = matrix.row_mut(index);
+            row[0] = bounds.x + bounds.w;
+            row[1] = bounds.x;
+            row[2] = bounds.y + bounds.h;
+            row[3] = bounds.y;
+            placed += 1;
+        }
+
+        unknown.sort();
+        (Grid{ matrix: matrix, placed: placed }, unknown)
+    }
+
+    pub fn placed(&self) -> usize {
+        self.placed
+    }
+
+    /// layout matrix in viewport pixels, the coordinates of the mouse
+    pub fn layout_matrix(&self, scroll: &Scroll) -> Array2<f32> {
+        let mut matrix = self.matrix.clone();
+        for mut row in matrix.genrows_mut() {
+            if row[1] == OFF_SCREEN {
+                continue;
+            }
+            row[0] -= scroll.x;
+            row[1] -= scroll.x;
+            row[2] -= scroll.y;
+            row[3] -= scroll.y;
+        }
+        matrix
+    }
+
+    /// thumbnail under the mouse at (mx, my), relative to the viewport
+    pub fn item_at(&self, scroll: &Scroll, mx: f32, my: f32) -> Option<usize> {
+        let (x, y) = (mx + scroll.x, my + scroll.y);
+        self.matrix.genrows().into_iter().position(|row| {
+            row[1] <= x && x < row[0] && row[3] <= y && y < row[2]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::decoders;
+
+    fn queries(n: usize) -> indexmap::IndexMap<String, usize> {
+        (0..n).map(|i| (format!("img{}", i), 4)).collect()
+    }
+
+    #[test]
+    fn test_grid_remap() {
+        let queries = queries(6);
+        let keys: Vec<String> = vec!["img5", "img4", "img3", "missing"].into_iter().map(|k| k.to_owned()).collect();
+        let layout = grid_layout(keys.iter(), 2, 100.0, 10.0);
+        let (grid, unknown) = Grid::new(&queries, &layout);
+
+        // rows follow the query index, whatever the order of the layout
+        assert_eq!(unknown, vec!["missing".to_owned()]);
+        assert_eq!(grid.placed(), 3);
+        let matrix = grid.layout_matrix(&Scroll::default());
+        assert_eq!(matrix.row(5).to_vec(), vec![100.0, 0.0, 100.0, 0.0]);
+        assert_eq!(matrix.row(4).to_vec(), vec![210.0, 110.0, 100.0, 0.0]);
+        assert_eq!(matrix.row(3).to_vec(), vec![100.0, 0.0, 210.0, 110.0]);
+        assert_eq!(matrix.row(0).to_vec(), vec![OFF_SCREEN; 4]);
+
+        // scrolled by a row: img3 is at the top of the viewport
+        let scroll = Scroll{ x: 0.0, y: 110.0 };
+        assert_eq!(grid.layout_matrix(&scroll).row(3).to_vec(), vec![100.0, 0.0, 100.0, 0.0]);
+        assert_eq!(grid.item_at(&scroll, 50.0, 50.0), Some(3));
+        assert_eq!(grid.item_at(&scroll, 105.0, 50.0), None);
+    }
+
+    #[test]
+    fn test_grid_decode() {
+        let queries = queries(4);
+        let layout = grid_layout(queries.keys(), 4, 100.0, 0.0);
+        let (grid, _) = Grid::new(&queries, &layout);
+
+        let dist = serde_json::json!({"100": {"xmu": 250.0, "ymu": 50.0, "xsigma": 30.0, "ysigma": 30.0}});
+        let prob = decoders::decode_model(&dist, &grid.layout_matrix(&Scroll::default()));
+        assert!(prob.get(2, 100) > prob.get(1, 100));
+        assert!(prob.get(1, 100) > prob.get(0, 100));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod gallery;
+pub mod grid;
+pub use gallery::*;
@@ -0,0 +1,192 @@
+/*
+ * MapTileApp: slippy map over a z/x/y tile pyramid.
+ *
+ * Backend: `BlockStore` keyed by "z/x/y", each block a bincode TileBlock holding a
+ * part of the tile's image file; progressive JPEG tiles render from any prefix.
+ * Build it from a directory of rendered tiles (tiles/z/x/y.jpg) with `build_backend`.
+ *
+ * Server config (`app` section):
+ *     {"maptile": {"db": "data/maptile", "tile_size": 256}}
+ *
+ * Client distribution (PredictorState.data), the Gaussian over the mouse position
+ * and the viewport in world pixels at its zoom level:
+ *     {"dist": {"g": {"<delta ms>": {"xmu", "ymu", "xsigma", "ysigma"}}, "p": {"a", "X", "Y"}},
+ *      "state": {"z": zoom, "x": left, "y": top}}
+ */
+use serde_derive::{Deserialize, Serialize};
+use ndarray::{Array2};
+
+use super::pyramid::{Pyramid, TileKey, Viewport, ZoomPredictor};
+use crate::apps::{self, AppTrait};
+use crate::ds;
+use crate::scheduler::{self, decoders};
+use crate::backend;
+
+/// prior probability of zooming in (and out) between two distributions
+const ZOOM_PRIOR: f32 = 0.05;
+const ZOOM_SMOOTHING: f32 = 0.05;
+
+pub struct MapTileApp {
+    blocks_per_query: indexmap::IndexMap<String, usize>,
+    utility: Vec<f32>,
+    blocksize: usize,
+    tile_size: usize,
+    backend: backend::blockstore::BlockStore,
+
+    pyramid: Pyramid,
+    viewport: Viewport,
+    zoom: ZoomPredictor,
+    /// layout matrix of the current viewport
+    layout: Array2<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileBlock {
+    pub block_id: u32,
+    pub content: Vec<u8>,
+}
+
+/// appstate: specific data passed at initialization state from the client
+/// config: configuration data passed from the server
+pub fn new(_appstate: &ds::AppState, config: serde_json::Value) -> Result<MapTileApp, String> {
+    let config = &config["maptile"];
+    let db_path = config["db"].as_str().unwrap_or("data/maptile").to_string();
+    let tile_size = config["tile_size"].as_u64().unwrap_or(256) as usize;
+
+    info!("1) load tile pyramid {:?}", db_path);
+    if !std::path::Path::new(&db_path).exists() {
+        return Err(format!("backend is not initialized {:?}, see maptile::build_backend", db_path));
+    }
+    let backend = backend::blockstore::BlockStore::load(&db_path, MapTileApp::split_blocks)?;
+
+    info!("2) create an index of how many blocks/tile from the metadata");
+    let metas = backend.collect_meta();
+    let blocks_per_query: indexmap::IndexMap<String, usize> = metas.iter().map(|(k, m)| (k.clone(), m.nblocks)).collect();
+    let blocksize = metas.values().next().and_then(|m| m.sizes.first().cloned()).unwrap_or(0);
+
+    // the first blocks of a tile matter the most
+    let max_blocks_count: usize = blocks_per_query.values().cloned().max().unwrap_or(0);
+    let utility = backend::blockstore::max_utility(&metas).unwrap_or_else(|| apps::linear_utility(max_blocks_count));
+
+    let pyramid = Pyramid::new(blocks_per_query.keys(), tile_size);
+    info!("3) {} tiles, zoom levels {:?}", pyramid.len(), pyramid.zoom_range());
+
+    let viewport = Viewport{ z: pyramid.zoom_range().0, x: 0.0, y: 0.0 };
+    let layout = pyramid.layout_matrix(&viewport);
+    Ok(MapTileApp{ blocks_per_query, utility, blocksize, tile_size, backend,
+                   pyramid, viewport, zoom: ZoomPredictor::new(ZOOM_PRIOR, ZOOM_SMOOTHING), layout })
+}
+
+/// Split every tile under `tiles_dir` (laid out as z/x/y.<ext>) in blocks of `blocksize`
+/// bytes and store them in a `BlockStore` at `db_path`. Returns the number of tiles.
+pub fn build_backend(tiles_dir: &str, db_path: &str, blocksize: usize) -> std::io::Result<usize> {
+    let mut backend = backend::blockstore::BlockStore::new(db_path.to_string());
+    let mut count = 0;
+
+    for z in std::fs::read_dir(tiles_dir)? {
+        let z = z?.path();
+        for x in std::fs::read_dir(&z)? {
+            let x = x?.path();
+            for y in std::fs::read_dir(&x)? {
+                let y = y?.path();
+                let name = |p: &std::path::Path| p.file_stem().and_then(|s| s.to_str()).map(|s| s.to_owned());
+                let key = match (name(&z), name(&x), name(&y)) {
+                    (Some(z), Some(x), Some(y)) => format!("{}/{}/{}", z, x, y),
+                    _ => continue,
+                };
+                if TileKey::parse(&key).is_none() {
+                    warn!("skip {:?}, not a z/x/y tile", y);
+                    continue;
+                }
+
+                let content = std::fs::read(&y)?;
+                let blocks: Vec<Vec<u8>> = content.chunks(std::cmp::max(1, blocksize)).enumerate()
+                    .map(|(i, c)| bincode::serialize(&TileBlock{ block_id: i as u32, content: c.to_vec() }).unwrap())
+                    .collect();
+                backend.put_query(&key, &blocks, apps::linear_utility(blocks.len()));
+                count += 1;
+            }
+        }
+    }
+
+    backend.flush();
+    Ok(count)
+}
+
+impl MapTileApp {
+    /// encoded blocks of a value of the one value per tile layout, see `BlockStore::migrate`
+    fn split_blocks(v: &[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let blocks: Vec<TileBlock> = bincode::deserialize(v).ok()?;
+        let encoded: Vec<Vec<u8>> = blocks.iter().map(|b| bincode::serialize(b).unwrap()).collect();
+        Some((encoded, apps::linear_utility(blocks.len())))
+    }
+
+    /// reads blocks incache..incache + count only
+    fn get_nblocks_bytes(&self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let nblocks = *self.blocks_per_query.get(key)?;
+        Some(apps::stream_blocks(&self.backend, key, nblocks, count, incache))
+    }
+
+    fn update_viewport(&mut self, state: &serde_json::Value) {
+        let viewport: Viewport = match serde_json::from_value(state.clone()) {
+            Ok(viewport) => viewport,
+            Err(err) => {
+                debug!("no viewport in the client state {:?}", err);
+                return;
+            }
+        };
+
+        self.zoom.update(viewport.z);
+        if viewport != self.viewport {
+            self.viewport = viewport;
+            self.layout = self.pyramid.layout_matrix(&self.viewport);
+        }
+    }
+}
+
+impl AppTrait for MapTileApp {
+    fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+        (self.blocks_per_query.clone(), self.utility.clone())
+    }
+
+    fn get_nblocks_byindex(&mut self, index: usize, count: usize,
+                           incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let key = self.blocks_per_query.get_index(index).map(|(k, _)| k.clone())?;
+        self.get_nblocks_bytes(&key, count, incache)
+    }
+
+    fn get_nblocks_bykey(&mut self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        self.get_nblocks_bytes(key, count, incache)
+    }
+
+    /// the tiles under the mouse at the current zoom, and the ones a zoom in or out would show
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
+        let data = userstate.data;
+        if let Some(state) = data.get("state") {
+            self.update_viewport(state);
+        }
+
+        let dist = &data["dist"];
+        let weights = self.pyramid.level_weights(&self.viewport, &self.zoom);
+        let mut prob = match dist.get("g") {
+            Some(g) => decoders::decode_model_weighted(g, &self.layout, Some(&weights)),
+            None => scheduler::Prob::new(self.pyramid.len()),
+        };
+
+        let (alpha, x, y) = decoders::decode_point_model(&dist["p"]);
+        if let Some(index) = self.pyramid.tile_at(&self.viewport, x as f32, y as f32) {
+            prob.set_point_dist(alpha, index);
+        }
+
+        prob
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.blocksize
+    }
+
+    fn get_initstate(&mut self) -> String {
+        let (min_zoom, max_zoom) = self.pyramid.zoom_range();
+        serde_json::json!({"tile_size": self.tile_size, "min_zoom": min_zoom, "max_zoom": max_zoom}).to_string()
+    }
+}
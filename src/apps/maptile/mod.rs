@@ -0,0 +1,3 @@
+pub mod maptile;
+pub mod pyramid;
+pub use maptile::*;
@@ -0,0 +1,233 @@
+/*
+ * Tile pyramid: maps the queries of the map-tile app (z/x/y tile keys) to
+ * screen space, for the Gaussian model in `decoders::decode_model`.
+ *
+ * Zoom level z is a (tile_size * 2^z)^2 pixel world. The client's viewport is
+ * its zoom level and the world pixel at its top left corner; mouse positions are
+ * relative to the viewport.
+ */
+use ndarray::{Array1, Array2};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// layout row of tiles that can't be reached from the viewport: zero width box
+const OFF_SCREEN: f32 = -1.0e9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TileKey {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileKey {
+    /// parse "z/x/y"
+    pub fn parse(key: &str) -> Option<Self> {
+        let parts: Vec<u32> = key.split('/').filter_map(|p| p.parse::<u32>().ok()).collect();
+        match parts.len() {
+            3 => Some(TileKey{ z: parts[0], x: parts[1], y: parts[2] }),
+            _ => None,
+        }
+    }
+
+    pub fn to_key(&self) -> String {
+        format!("{}/{}/{}", self.z, self.x, self.y)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Viewport {
+    pub z: u32,
+    /// world pixel at the top left corner, at zoom z
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Viewport{ z: 0, x: 0.0, y: 0.0 }
+    }
+}
+
+pub struct Pyramid {
+    tile_size: f32,
+    min_zoom: u32,
+    max_zoom: u32,
+    /// tile of each query index, None for keys that aren't tiles
+    tiles: Vec<Option<TileKey>>,
+    index: HashMap<TileKey, usize>,
+}
+
+impl Pyramid {
+    /// keys: query keys in query index order
+    pub fn new<'a, I: Iterator<Item = &'a String>>(keys: I, tile_size: usize) -> Self {
+        let tiles: Vec<Option<TileKey>> = keys.map(|k| TileKey::parse(k)).collect();
+        let index = tiles.iter().enumerate().filter_map(|(i, t)| t.map(|t| (t, i))).collect();
+        let zooms = tiles.iter().filter_map(|t| t.map(|t| t.z));
+        let (min_zoom, max_zoom) = zooms.fold((std::u32::MAX, 0), |(lo, hi), z| (lo.min(z), hi.max(z)));
+
+        Pyramid{ tile_size: tile_size as f32, min_zoom: std::cmp::min(min_zoom, max_zoom),
+                 max_zoom: max_zoom, tiles: tiles, index: index }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn zoom_range(&self) -> (u32, u32) {
+        (self.min_zoom, self.max_zoom)
+    }
+
+    pub fn get_index(&self, tile: &TileKey) -> Option<usize> {
+        self.index.get(tile).cloned()
+    }
+
+    /// tile under the mouse at (mx, my), relative to the viewport
+    pub fn tile_at(&self, vp: &Viewport, mx: f32, my: f32) -> Option<usize> {
+        let wx = vp.x + mx;
+        let wy = vp.y + my;
+        if wx < 0.0 || wy < 0.0 {
+            return None;
+        }
+
+        let tile = TileKey{ z: vp.z, x: (wx / self.tile_size) as u32, y: (wy / self.tile_size) as u32 };
+        self.get_index(&tile)
+    }
+
+    /// Layout matrix (xpw, xmw, yph, ymh per query) of the tiles at the viewport's zoom
+    /// and one level above and below, in screen pixels. A tile one level deeper covers a
+    /// quarter of its parent: the mouse over it means zooming into it.
+    /// Tiles at other levels are off screen.
+    pub fn layout_matrix(&self, vp: &Viewport) -> Array2<f32> {
+        let mut layout = Array2::from_elem((self.tiles.len(), 4), OFF_SCREEN);
+        for (i, tile) in self.tiles.iter().enumerate() {
+            let tile = match tile {
+                Some(tile) if (tile.z as i64 - vp.z as i64).abs() <= 1 => tile,
+                _ => continue,
+            };
+
+            // size of the tile in pixels of the viewport's zoom level
+            let size = self.tile_size * 2f32.powi(vp.z as i32 - tile.z as i32);
+            let left = tile.x as f32 * size - vp.x;
+            let top = tile.y as f32 * size - vp.y;
+            let mut row = layout.row_mut(i);
+            row[0] = left + size;
+            row[1] = left;
+            row[2] = top + size;
+            row[3] = top;
+        }
+
+        layout
+    }
+
+    /// per query weight: probability that the viewport is at the tile's zoom level
+    pub fn level_weights(&self, vp: &Viewport, zoom: &ZoomPredictor) -> Array1<f32> {
+        let (p_out, p_stay, p_in) = zoom.level_probs(vp.z, self.min_zoom, self.max_zoom);
+        self.tiles.iter().map(|tile| match tile {
+            Some(tile) if tile.z + 1 == vp.z => p_out,
+            Some(tile) if tile.z == vp.z => p_stay,
+            Some(tile) if tile.z == vp.z + 1 => p_in,
+            _ => 0.0,
+        }).collect()
+    }
+}
+
+/// Predicts zoom changes from the history of the viewport: exponentially smoothed
+/// frequency of zooming in and out between successive distributions.
+#[derive(Clone, Debug)]
+pub struct ZoomPredictor {
+    last_zoom: Option<u32>,
+    p_in: f32,
+    p_out: f32,
+    /// smoothing factor
+    beta: f32,
+}
+
+impl ZoomPredictor {
+    pub fn new(prior: f32, beta: f32) -> Self {
+        ZoomPredictor{ last_zoom: None, p_in: prior, p_out: prior, beta: beta }
+    }
+
+    pub fn update(&mut self, z: u32) {
+        if let Some(last) = self.last_zoom {
+            let zoom_in = if z > last { 1.0 } else { 0.0 };
+            let zoom_out = if z < last { 1.0 } else { 0.0 };
+            self.p_in = (1.0 - self.beta) * self.p_in + self.beta * zoom_in;
+            self.p_out = (1.0 - self.beta) * self.p_out + self.beta * zoom_out;
+        }
+        self.last_zoom = Some(z);
+    }
+
+    /// (zoom out, stay, zoom in) probabilities at zoom z, the mass of a
+    /// level outside of [min_zoom, max_zoom] stays at z
+    pub fn level_probs(&self, z: u32, min_zoom: u32, max_zoom: u32) -> (f32, f32, f32) {
+        // keep some mass on staying, whatever the history
+        let p_in = if z < max_zoom { self.p_in.min(0.45) } else { 0.0 };
+        let p_out = if z > min_zoom { self.p_out.min(0.45) } else { 0.0 };
+        (p_out, 1.0 - p_in - p_out, p_in)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::decoders;
+
+    fn keys(max_zoom: u32) -> Vec<String> {
+        let mut keys = Vec::new();
+        for z in 0..=max_zoom {
+            for x in 0..(1 << z) {
+                for y in 0..(1 << z) {
+                    keys.push(TileKey{ z, x, y }.to_key());
+                }
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_layout_matrix() {
+        let keys = keys(2);
+        let pyramid = Pyramid::new(keys.iter(), 256);
+        let vp = Viewport{ z: 1, x: 100.0, y: 0.0 };
+        let layout = pyramid.layout_matrix(&vp);
+
+        // same level: 256px tiles shifted by the viewport
+        let i = pyramid.get_index(&TileKey{ z: 1, x: 1, y: 0 }).unwrap();
+        assert_eq!(layout.row(i).to_vec(), vec![412.0, 156.0, 256.0, 0.0]);
+        // a level deeper: quarter of the parent
+        let i = pyramid.get_index(&TileKey{ z: 2, x: 1, y: 1 }).unwrap();
+        assert_eq!(layout.row(i).to_vec(), vec![156.0, 28.0, 256.0, 128.0]);
+        // a level up: the whole world
+        let i = pyramid.get_index(&TileKey{ z: 0, x: 0, y: 0 }).unwrap();
+        assert_eq!(layout.row(i).to_vec(), vec![412.0, -100.0, 512.0, 0.0]);
+
+        assert_eq!(pyramid.tile_at(&vp, 200.0, 10.0), pyramid.get_index(&TileKey{ z: 1, x: 1, y: 0 }));
+    }
+
+    #[test]
+    fn test_zoom_prediction() {
+        let keys = keys(2);
+        let pyramid = Pyramid::new(keys.iter(), 256);
+        let mut zoom = ZoomPredictor::new(0.05, 0.2);
+        for &z in [0, 1, 1, 2, 2].iter() {
+            zoom.update(z);
+        }
+        let (p_out, p_stay, p_in) = zoom.level_probs(1, 0, 2);
+        assert!(p_in > p_out);
+        assert!((p_out + p_stay + p_in - 1.0).abs() < 1e-6);
+        // no zooming in past the deepest level
+        assert_eq!(zoom.level_probs(2, 0, 2).2, 0.0);
+
+        // mouse steady in the middle of tile 1/0/0: it and its children are the likely queries
+        let vp = Viewport{ z: 1, x: 0.0, y: 0.0 };
+        let dist = serde_json::json!({"100": {"xmu": 128.0, "ymu": 128.0, "xsigma": 20.0, "ysigma": 20.0}});
+        let weights = pyramid.level_weights(&vp, &zoom);
+        let prob = decoders::decode_model_weighted(&dist, &pyramid.layout_matrix(&vp), Some(&weights));
+        let same = pyramid.get_index(&TileKey{ z: 1, x: 0, y: 0 }).unwrap();
+        let other = pyramid.get_index(&TileKey{ z: 1, x: 1, y: 1 }).unwrap();
+        let child = pyramid.get_index(&TileKey{ z: 2, x: 1, y: 1 }).unwrap();
+        assert!(prob.get(same, 100) > prob.get(child, 100));
+        assert!(prob.get(child, 100) > prob.get(other, 100));
+    }
+}
@@ -1,29 +1,77 @@
-use serde_derive::{Deserialize, Serialize};
 use std::sync::{Arc, RwLock};
 
 // Available Apps
 pub mod testapp;
+pub mod maptile;
+pub mod gallery;
+pub mod timeseries;
+pub mod crossfilter;
 
+pub mod erasure;
+
+pub mod registry;
+pub use registry::AppRegistry;
+
+use crate::backend::blockstore::BlockStore;
+use crate::backend::packfile::PackFile;
+use crate::backend::BlockSource;
 use crate::ds;
+use crate::error::KhameleonError;
 use crate::scheduler;
 
-/// AppType: an enum that has the different types of apps supported
-///          to add a new app, add here name of the app, and in the
-///          new function below, include match for the new type
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
-pub enum AppType {
-    TestApp
+/// utility of the first i + 1 of `nblocks` blocks, when every block adds the same
+pub fn linear_utility(nblocks: usize) -> Vec<f32> {
+    (0..nblocks).map(|i| (1.0 / nblocks as f32) * (i as f32 + 1.0)).collect()
+}
+
+/// The app's blocks: the packfile at `pack` if the app's config sets one, the block
+/// store `open_store` opens otherwise.
+pub fn open_source<F>(pack: Option<&str>, open_store: F) -> Result<Arc<dyn BlockSource>, String>
+    where F: FnOnce() -> Result<BlockStore, String> {
+    match pack {
+        Some(pack_path) => {
+            info!("map packfile {:?}", pack_path);
+            match PackFile::open(pack_path) {
+                Ok(pack) => Ok(Arc::new(pack)),
+                Err(err) => Err(format!("couldn't open packfile {:?}: {}", pack_path, err)),
+            }
+        },
+        None => Ok(Arc::new(open_store()?)),
+    }
 }
 
-/// apps::new: function used by the manager to create app instance
-///            app struct has to support AppTrait trait
-///            an example of an app implementation is in gallary.ds file
-pub fn new(appstate: &ds::AppState, config: serde_json::Value, _state_change_flag: Arc<RwLock<bool>>) -> Box<dyn AppTrait> {
-    match appstate.appname {
-        AppType::TestApp => Box::new(testapp::new( appstate, config )) as Box<dyn AppTrait>,
+/// Blocks incache..incache + count of `key`, out of `nblocks`, framed as block index,
+/// nblocks, key and the encoded block as stored in `source`. Only these blocks are read,
+/// and they aren't copied if `source` can share them, see `BlockSource::shared_block`.
+pub fn stream_blocks(source: &dyn BlockSource, key: &str, nblocks: usize,
+                     count: usize, incache: usize) -> Vec<ds::StreamBlock> {
+    let end = std::cmp::min(nblocks, incache + count);
+    let mut sblocks = Vec::new();
+    for i in incache..end {
+        let block = match source.shared_block(key, i) {
+            Some(block) => block,
+            None => {
+                error!("block {} of {:?} is missing", i, key);
+                break;
+            }
+        };
+        let mut head = bincode::serialize(&(i as u32)).unwrap();
+        head.extend(bincode::serialize(&(nblocks as u32)).unwrap());
+        head.extend(bincode::serialize(&key).unwrap());
+        sblocks.push(ds::StreamBlock::Shared(ds::BlockBytes{ head: head, body: block }));
     }
+    sblocks
 }
 
+/// AppFactory: function used by the manager to create app instance
+///             app struct has to support AppTrait trait.
+///             To add a new app, register its factory under the app's name in
+///             `AppRegistry::with_builtin`, or pass it to the server builder.
+///             arguments: state sent by the client, server config, and the flag the
+///             app sets when its layout changes. Returns an error if the app can't
+///             start, e.g. its backend is missing.
+pub type AppFactory = Arc<dyn Fn(&ds::AppState, serde_json::Value, Arc<RwLock<bool>>) -> Result<Box<dyn AppTrait>, String> + Send + Sync>;
+
 /// AppTrait: apps need to supprt this trait, it recieves distrubtion from client
 ///           and run scheduler  to decide  list of blocks to stream using 'get_decisions',
 ///           and the actual blocks as a vector of blocks to stream to the client using
@@ -42,6 +90,12 @@ pub trait AppTrait: Send + Sync {
     /// decode received distribution from the client and return information in Prob object
     fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob;
 
+    /// optional: new client layout, {key: {x, y, w, h}}. Apps that support it keep their
+    /// query indices, set the state change flag and return how many queries are laid out
+    fn update_layout(&mut self, _layout: serde_json::Value) -> Result<usize, KhameleonError> {
+        Err(KhameleonError::BadRequest("the app doesn't support layout updates".to_owned()))
+    }
+
     /// return size of a block in Bytes
     fn get_block_size(&self) -> usize;
     
@@ -70,4 +124,36 @@ pub trait AppTrait: Send + Sync {
     /// optional: app specific policies to modify sequence of blocks
     fn prepare_schedule(&mut self, _schedule: &Vec<usize>) {
     }
+
+    /// optional: generates the blocks of queries that aren't in the backend yet, see `Producer`
+    fn get_producer(&self) -> Option<Arc<dyn Producer>> {
+        None
+    }
+
+    /// optional: erasure coded levels of each query, in query index order, see `erasure`.
+    /// The sender then asks `get_nblocks_byindex` for coded indices instead of the
+    /// number of blocks in cache, and blocks per query and utility count source blocks.
+    fn get_erasure(&self) -> Option<Vec<erasure::Layout>> {
+        None
+    }
+
+    /// optional: how late blocks are valued by the scheduler,
+    /// None uses the server's scheduler config
+    fn get_decay(&self) -> Option<scheduler::Decay> {
+        None
+    }
+}
+
+/// Producer: generates blocks on demand, e.g. by rendering an image or running a query,
+///           for apps whose blocks aren't all precomputed in their backend.
+///           The manager runs it on a pool of worker threads: queries the scheduler picks
+///           before they're ready are generated, and written back to the backend the app
+///           reads in `get_nblocks_byindex`.
+pub trait Producer: Send + Sync {
+    /// are the blocks of query `index` in the backend
+    fn is_ready(&self, index: usize) -> bool;
+
+    /// generate the blocks of query `index` and write them to the backend,
+    /// called concurrently for different queries
+    fn produce(&self, index: usize) -> Result<(), String>;
 }
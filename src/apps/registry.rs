@@ -0,0 +1,89 @@
+/*
+ * AppRegistry: maps app names to their factories.
+ *
+ * The client picks the app to run through `AppState.appname`; apps register under
+ * that name at startup, either the ones shipped with khameleon (`with_builtin`) or
+ * user supplied ones through the server builder.
+ */
+use std::sync::{Arc, RwLock};
+
+use super::{AppFactory, AppTrait};
+use crate::ds;
+use crate::error::KhameleonError;
+
+#[derive(Clone, Default)]
+pub struct AppRegistry {
+    factories: indexmap::IndexMap<String, AppFactory>,
+}
+
+impl AppRegistry {
+    pub fn new() -> Self {
+        AppRegistry{ factories: indexmap::IndexMap::new() }
+    }
+
+    /// registry with the apps shipped with khameleon
+    pub fn with_builtin() -> Self {
+        let mut registry = AppRegistry::new();
+        registry.register("TestApp", |appstate, config, _state_change_flag| {
+            Ok(Box::new(super::testapp::new(appstate, config)?) as Box<dyn AppTrait>)
+        });
+        registry.register("MapTileApp", |appstate, config, _state_change_flag| {
+            Ok(Box::new(super::maptile::new(appstate, config)?) as Box<dyn AppTrait>)
+        });
+        registry.register("GalleryApp", |appstate, config, state_change_flag| {
+            Ok(Box::new(super::gallery::new(appstate, config, state_change_flag)?) as Box<dyn AppTrait>)
+        });
+        registry.register("TimeSeriesApp", |appstate, config, _state_change_flag| {
+            Ok(Box::new(super::timeseries::new(appstate, config)?) as Box<dyn AppTrait>)
+        });
+        registry.register("CrossfilterApp", |appstate, config, _state_change_flag| {
+            Ok(Box::new(super::crossfilter::new(appstate, config)?) as Box<dyn AppTrait>)
+        });
+
+        registry
+    }
+
+    /// register `factory` under `name`, replacing any app with the same name
+    pub fn register<F>(&mut self, name: &str, factory: F)
+        where F: Fn(&ds::AppState, serde_json::Value, Arc<RwLock<bool>>) -> Result<Box<dyn AppTrait>, String> + Send + Sync + 'static {
+        if self.factories.insert(name.to_owned(), Arc::new(factory)).is_some() {
+            warn!("app {:?} registered twice, using the last one", name);
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.factories.keys().cloned().collect()
+    }
+
+    /// create the app named in `appstate.appname`
+    pub fn create(&self, appstate: &ds::AppState, config: serde_json::Value,
+                  state_change_flag: Arc<RwLock<bool>>) -> Result<Box<dyn AppTrait>, KhameleonError> {
+        match self.factories.get(&appstate.appname) {
+            Some(factory) => factory(appstate, config, state_change_flag).map_err(|err| {
+                KhameleonError::Internal(format!("couldn't start app {:?}: {}", appstate.appname, err))
+            }),
+            None => Err(KhameleonError::UnknownApp(format!("unknown app {:?}, available apps: {}",
+                                                           appstate.appname, self.names().join(", ")))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_errors() {
+        let mut registry = AppRegistry::new();
+        registry.register("Broken", |_appstate, _config, _flag| Err("backend is not initialized".to_owned()));
+        let appstate = |name: &str| ds::AppState{ appname: name.to_owned(), cachesize: 10, state: serde_json::Value::Null };
+        let flag = Arc::new(RwLock::new(false));
+
+        let err = registry.create(&appstate("Broken"), serde_json::Value::Null, flag.clone()).err().unwrap();
+        assert_eq!(err.kind(), "internal");
+        assert!(err.message().contains("backend is not initialized"));
+
+        let err = registry.create(&appstate("Missing"), serde_json::Value::Null, flag).err().unwrap();
+        assert_eq!(err.kind(), "unknown_app");
+    }
+}
@@ -1,49 +1,71 @@
 use serde_derive::{Deserialize, Serialize};
 use std::io::prelude::*;
+use std::sync::Arc;
 
-use super::AppTrait;
+use crate::apps::{linear_utility, AppTrait};
+use crate::apps::erasure::{self, CodedBlock, Layout};
 use crate::ds;
 use crate::scheduler;
-use crate::backend;
+use crate::backend::{self, BlockSource};
 
 #[derive(Clone)]
 pub struct TestApp {
     blocks_per_query: indexmap::IndexMap<String, usize>,
     utility: Vec<f32>,
     blocksize: usize,
-    backend: backend::inmem::InMemBackend,
+    /// sled store, or packfile if `{"testapp": {"pack": path}}` is set
+    backend: Arc<dyn BlockSource>,
+    /// coded levels of each query if `{"testapp": {"erasure": {"k", "n"}}}` is set
+    erasure: Option<Vec<Layout>>,
 }
 
-/// appstate: specific data passed at initialization state from the client
-/// config: configuration data passed from the server
-pub fn new(_appstate: &ds::AppState, _config: serde_json::Value) -> TestApp {
-    info!("1) load K/V store");
-    let db_path = "data/test_data".to_string();
-    let backend: backend::inmem::InMemBackend;
-    if std::path::Path::new(&db_path).exists() == true {
-       backend = backend::inmem::InMemBackend::new(db_path);
-    } else {
-        panic!("backend is not initialized {:?}", db_path);
+/// sled store at `db_path`, converted to one value per block if needed
+fn open_store(db_path: &str) -> Result<backend::blockstore::BlockStore, String> {
+    if std::path::Path::new(db_path).exists() == false {
+        return Err(format!("backend is not initialized {:?}", db_path));
     }
+    backend::blockstore::BlockStore::load(db_path, TestApp::split_blocks)
+}
 
-    info!("2) create an index  of how many blocks/query");
-    let blocks_per_query = backend.collect_blocks_per_query(TestApp::count_blocks);
-    let blocksize = match backend.get_iter().next() {
-        Some(Ok((_k, v))) => {
-            let value: Vec<ImageBlock> = bincode::deserialize(&v).unwrap();
-            let size = match value.iter().next() {
-                Some(v) => v.size(),
-                None => 0
-            };
-
-            size
+/// appstate: specific data passed at initialization state from the client
+/// config: configuration data passed from the server
+pub fn new(_appstate: &ds::AppState, config: serde_json::Value) -> Result<TestApp, String> {
+    info!("1) load K/V store or packfile");
+    let backend = crate::apps::open_source(config["testapp"]["pack"].as_str(), || open_store("data/test_data"))?;
+    TestApp::from_source(backend, &config)
+}
 
-        }, _ => 0,
-    };
+impl TestApp {
+    /// the app over the blocks of `backend`
+    fn from_source(backend: Arc<dyn BlockSource>, config: &serde_json::Value) -> Result<TestApp, String> {
+        info!("2) create an index  of how many blocks/query from the metadata");
+        let metas = backend.queries();
+        let blocks_per_query: indexmap::IndexMap<String, usize> = metas.iter().map(|(k, m)| (k.clone(), m.nblocks)).collect();
+        let blocksize = match metas.values().next() {
+            Some(meta) => meta.sizes.iter().next().cloned().unwrap_or(0),
+            None => 0,
+        };
+
+        let max_blocks_count: usize = blocks_per_query.iter().map(|(_, v)| *v).max().unwrap_or_else(|| 0 );
+        let mut utility: Vec<f32> = backend::blockstore::max_utility(&metas).unwrap_or_else(|| linear_utility(max_blocks_count));
+
+        // any k of the n coded blocks of a level decode it, utility counts distinct blocks
+        let erasure = match serde_json::from_value::<erasure::Code>(config["testapp"]["erasure"].clone()) {
+            Ok(code) => {
+                let layouts: Vec<Layout> = blocks_per_query.values()
+                    .map(|&nblocks| Layout::split(nblocks, code.k, code.n))
+                    .collect::<Result<_, _>>()?;
+                if let Ok(largest) = Layout::split(max_blocks_count, code.k, code.n) {
+                    utility = largest.utility(&utility);
+                }
+                info!("erasure coded levels of {} blocks, {} coded blocks each", code.k, code.n);
+                Some(layouts)
+            },
+            Err(_) => None,
+        };
 
-    let max_blocks_count: usize = blocks_per_query.iter().map(|(_, v)| *v).max().unwrap_or_else(|| 0 );
-    let utility: Vec<f32> = (0..max_blocks_count).enumerate().map(|(i, _)| (1.0 / max_blocks_count as f32)*(i as f32+1.0) ).collect();
-    TestApp{blocks_per_query, utility, blocksize, backend}
+        Ok(TestApp{blocks_per_query, utility, blocksize, backend, erasure})
+    }
 }
 
 // app specific
@@ -57,19 +79,16 @@ impl ImageBlock {
     fn serialize(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
     }
-
-    fn size(&self) -> usize {
-        self.content.len()
-    }
 }
 
 
 impl TestApp {
-    fn count_blocks(v: &Vec<u8>) -> usize {
-        let value: Vec<ImageBlock> = bincode::deserialize(&v).unwrap();
-        let blocks_count = value.len();
+    /// encoded blocks of a value of the one value per query layout, see `BlockStore::migrate`
+    pub fn split_blocks(v: &[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let value: Vec<ImageBlock> = bincode::deserialize(v).ok()?;
+        let utility = linear_utility(value.len());
 
-        blocks_count
+        Some((value.iter().map(|b| b.serialize()).collect(), utility))
     }
 
     fn create_blocks(fname: String, blocksize: usize) -> Vec<ImageBlock> {
@@ -101,33 +120,40 @@ impl TestApp {
         blocks
     }
     
+    /// reads blocks incache..incache + count only, the metadata has the count
     fn get_nblocks_bytes(&self, key: &str, count: usize, incache: usize) -> Option::<Vec<ds::StreamBlock>> {
-        if let Some(blocks_bytes) = self.backend.get(key.as_bytes().to_vec()) {
-            let mut sblocks: Vec<ds::StreamBlock> = Vec::new();
-            let blocks: Vec<ImageBlock> = bincode::deserialize(&blocks_bytes).unwrap();
-            let nblocks: u32 = blocks.len() as u32;
-
-            let end = if incache + count > blocks.len() { blocks.len() } else { incache + count };
-            for i in incache..end {
-                let block = &blocks[i];
-                let mut bytebuffer: Vec<u8> = Vec::new();
-                let mut block_byte = block.serialize();
-                let mut block_id = bincode::serialize(&block.block_id).unwrap();
-                let mut nblock = bincode::serialize(&nblocks).unwrap();
-                let mut key_byte = bincode::serialize(&key).unwrap();
-
-                bytebuffer.append( &mut block_id );
-                bytebuffer.append( &mut nblock );
-                bytebuffer.append( &mut key_byte );
-                bytebuffer.append( &mut block_byte );
-
-                sblocks.push(ds::StreamBlock::Binary(bytebuffer));
-            }
+        let total = *self.blocks_per_query.get(key)?;
+        Some(crate::apps::stream_blocks(&*self.backend, key, total, count, incache))
+    }
+}
 
-            Some(sblocks)
-        } else {
-            None
+impl TestApp {
+    /// coded blocks first..first + count of query `qid`, see `erasure::Layout::locate`
+    fn get_coded_bytes(&self, qid: usize, key: &str, count: usize, first: usize) -> Option<Vec<ds::StreamBlock>> {
+        let layout = self.erasure.as_ref()?.get(qid)?;
+        let nblocks = layout.sources() as u32;
+
+        let end = std::cmp::min(first + count, layout.coded());
+        let mut sblocks = Vec::new();
+        for index in first..end {
+            let (l, j) = layout.locate(index)?;
+            let level = layout.levels[l];
+            let sources = (level.start..level.start + level.code.k)
+                .map(|i| self.backend.block(key, i))
+                .collect::<Option<Vec<_>>>()?;
+            let refs: Vec<&[u8]> = sources.iter().map(|s| s.as_ref()).collect();
+            let block = CodedBlock{ level: l as u32, index: j as u32, k: level.code.k as u32, n: level.code.n as u32,
+                                    lens: refs.iter().map(|s| s.len() as u32).collect(),
+                                    data: level.code.encode(&refs, j) };
+
+            let mut bytebuffer = bincode::serialize(&(index as u32)).unwrap();
+            bytebuffer.extend(bincode::serialize(&nblocks).unwrap());
+            bytebuffer.extend(bincode::serialize(&key).unwrap());
+            bytebuffer.extend(bincode::serialize(&block).unwrap());
+            sblocks.push(ds::StreamBlock::Binary(bytebuffer));
         }
+
+        Some(sblocks)
     }
 }
 
@@ -141,6 +167,9 @@ impl AppTrait for TestApp {
         let kv = self.blocks_per_query.get_index(index);
         debug!("get {:?}", kv);
         match kv {
+            Some((k, _)) if self.erasure.is_some() => {
+                self.get_coded_bytes(index, k, count, incache)
+            },
             Some((k, _)) => {
                 self.get_nblocks_bytes(k, count, incache)
             },
@@ -148,6 +177,18 @@ impl AppTrait for TestApp {
         }
     }
 
+    fn get_erasure(&self) -> Option<Vec<Layout>> {
+        self.erasure.clone()
+    }
+
+    /// tells the client whether blocks are erasure coded
+    fn get_initstate(&mut self) -> String {
+        match &self.erasure {
+            Some(_) => serde_json::json!({"erasure": true}).to_string(),
+            None => "".to_owned(),
+        }
+    }
+
     fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
         debug!("decode_dist: {:?}", userstate);
         let total_queries = 1;
@@ -173,13 +214,58 @@ mod tests {
         let image_path = "data/img_5_30_11.jpg";
         let db_path = "data/test_data";
         let blocks = TestApp::create_blocks(image_path.to_string(), blocksize);
-        // create backend key/value store
-        let mut backend = backend::inmem::InMemBackend::new(db_path.to_string());
-        let bytes = bincode::serialize(&blocks).unwrap();
+        // create backend key/value store, one value per block
+        let mut backend = backend::blockstore::BlockStore::new(db_path.to_string());
+        let encoded: Vec<Vec<u8>> = blocks.iter().map(|b| b.serialize()).collect();
         let query = "R1";
-        let key  = query.as_bytes().to_vec();
-        backend.set(key, bytes.clone());
+        backend.put_query(query, &encoded, linear_utility(blocks.len()));
         backend.flush();
     }
-}
 
+    #[test]
+    // the blocks the sender picks with a lost systematic block still decode to the originals
+    fn test_erasure_coded_round_trip() {
+        let db_path = std::env::temp_dir().join(format!("khameleon_testapp_erasure_{}", std::process::id()));
+        let mut store = backend::blockstore::BlockStore::new(db_path.to_str().unwrap().to_string());
+        let originals: Vec<ImageBlock> = (0..5).map(|i| ImageBlock{ block_id: i, content: vec![i as u8; 8 + i as usize] }).collect();
+        let blocks: Vec<Vec<u8>> = originals.iter().map(|b| b.serialize()).collect();
+        store.put_query("R1", &blocks, linear_utility(blocks.len()));
+
+        let config = serde_json::json!({"testapp": {"erasure": {"k": 2, "n": 3}}});
+        let mut app = TestApp::from_source(Arc::new(store), &config).unwrap();
+        let layout = app.get_erasure().unwrap()[0].clone();
+
+        // what the client receives, per level; the first block sent is lost
+        let mut received: Vec<Vec<CodedBlock>> = vec![Vec::new(); layout.levels.len()];
+        let (mut held, mut sent) = (0, vec![0; layout.levels.len()]);
+        while let Some(index) = layout.next(held, &sent) {
+            let (level, _) = layout.locate(index).unwrap();
+            sent[level] += 1;
+            let frame = match app.get_nblocks_byindex(0, 1, index).unwrap().pop() {
+                Some(ds::StreamBlock::Binary(frame)) => frame,
+                other => panic!("unexpected block {:?}", other),
+            };
+            if index == 0 {
+                continue;
+            }
+            let (bid, nblocks, key, block): (u32, u32, String, CodedBlock) = bincode::deserialize(&frame).unwrap();
+            assert_eq!((bid as usize, nblocks, key.as_str(), block.level as usize), (index, 5, "R1", level));
+            received[level].push(block);
+            held += 1;
+        }
+        assert_eq!(sent, vec![3, 2, 1]);
+
+        let mut decoded: Vec<ImageBlock> = Vec::new();
+        for (level, blocks) in layout.levels.iter().zip(received.iter()) {
+            let coded: Vec<(usize, &[u8])> = blocks.iter().map(|b| (b.index as usize, b.data.as_slice())).collect();
+            let sources = level.code.decode(&coded).unwrap();
+            for (source, &len) in sources.iter().zip(blocks[0].lens.iter()) {
+                decoded.push(bincode::deserialize(&source[..len as usize]).unwrap());
+            }
+        }
+        assert_eq!(decoded, originals);
+
+        drop(app);
+        let _ = std::fs::remove_dir_all(&db_path);
+    }
+}
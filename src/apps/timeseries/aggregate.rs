@@ -0,0 +1,136 @@
+/*
+ * Multi-resolution aggregates of a time series window.
+ *
+ * Level l summarizes the window in buckets of LEVELS[l] points (min, max and mean
+ * per bucket); the last level is the raw series. The client draws the mean line and
+ * the min/max band of the finest level it has, so each block replaces the previous one.
+ */
+use serde_derive::{Deserialize, Serialize};
+
+/// points per bucket of each block, coarse to full resolution
+pub const LEVELS: [usize; 4] = [64, 16, 4, 1];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    /// points per bucket
+    pub bucket: u32,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+    pub mean: Vec<f32>,
+}
+
+impl Aggregate {
+    pub fn new(values: &[f32], bucket: usize) -> Self {
+        let bucket = std::cmp::max(1, bucket);
+        let mut agg = Aggregate{ bucket: bucket as u32, min: Vec::new(), max: Vec::new(), mean: Vec::new() };
+        for chunk in values.chunks(bucket) {
+            agg.min.push(chunk.iter().cloned().fold(std::f32::INFINITY, f32::min));
+            agg.max.push(chunk.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max));
+            agg.mean.push(chunk.iter().sum::<f32>() / chunk.len() as f32);
+        }
+        agg
+    }
+
+    /// the series as drawn from this level: each point at its bucket's mean
+    pub fn reconstruct(&self, len: usize) -> Vec<f32> {
+        let bucket = self.bucket as usize;
+        (0..len).map(|i| self.mean.get(i / bucket).cloned().unwrap_or(0.0)).collect()
+    }
+
+    /// Root mean square error of the reconstruction, relative to the range of
+    /// `values`: 0 for the raw series, at most 1.
+    pub fn error(&self, values: &[f32]) -> f32 {
+        let lo = values.iter().cloned().fold(std::f32::INFINITY, f32::min);
+        let hi = values.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max);
+        if values.is_empty() || hi - lo <= 0.0 {
+            return 0.0;
+        }
+
+        let approx = self.reconstruct(values.len());
+        let mse = values.iter().zip(approx.iter()).map(|(v, a)| (v - a) * (v - a)).sum::<f32>() / values.len() as f32;
+        (mse.sqrt() / (hi - lo)).min(1.0)
+    }
+}
+
+/// Utility of the first i+1 blocks of a window: one minus the mean approximation error
+/// of level i across windows, non decreasing and 1 at full resolution.
+/// errors: per window, the error of each level
+pub fn utility(errors: &[Vec<f32>]) -> Vec<f32> {
+    let levels = errors.iter().map(|e| e.len()).max().unwrap_or(0);
+    let mut utility = Vec::with_capacity(levels);
+    let mut best: f32 = 0.0;
+    for level in 0..levels {
+        let (sum, count) = errors.iter().filter_map(|e| e.get(level))
+            .fold((0.0, 0), |(sum, count), e| (sum + e, count + 1));
+        let u = if count > 0 { 1.0 - sum / count as f32 } else { 1.0 };
+        best = best.max(u);
+        utility.push(best);
+    }
+    if let Some(last) = utility.last_mut() {
+        *last = 1.0;
+    }
+    utility
+}
+
+/// Read a CSV with a header row: one column per series, one row per sample.
+/// A `time` or `timestamp` column is skipped, samples are evenly spaced.
+/// Missing or bad cells repeat the previous sample.
+pub fn read_csv(path: &str) -> Result<Vec<(String, Vec<f32>)>, csv::Error> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let columns: Vec<usize> = headers.iter().enumerate()
+        .filter(|(_, h)| !["time", "timestamp"].contains(&h.trim().to_lowercase().as_str()))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut series: Vec<(String, Vec<f32>)> = columns.iter().map(|&i| (headers[i].trim().to_owned(), Vec::new())).collect();
+    for record in reader.records() {
+        let record = record?;
+        for (s, &i) in series.iter_mut().zip(columns.iter()) {
+            let previous = s.1.last().cloned().unwrap_or(0.0);
+            let value = record.get(i).and_then(|v| v.trim().parse::<f32>().ok())
+                .filter(|v| v.is_finite())
+                .unwrap_or(previous);
+            s.1.push(value);
+        }
+    }
+
+    Ok(series)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels_error() {
+        let values: Vec<f32> = (0..256).map(|i| ((i as f32) / 10.0).sin()).collect();
+        let levels: Vec<Aggregate> = LEVELS.iter().map(|&b| Aggregate::new(&values, b)).collect();
+
+        assert_eq!(levels[0].mean.len(), 4);
+        assert_eq!(levels[3].mean, values);
+        assert!(levels[0].min.iter().zip(levels[0].max.iter()).all(|(lo, hi)| lo <= hi));
+
+        // finer levels approximate better
+        let errors: Vec<f32> = levels.iter().map(|l| l.error(&values)).collect();
+        assert!(errors.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(errors[3], 0.0);
+
+        // partial last bucket
+        let agg = Aggregate::new(&values[..70], 64);
+        assert_eq!(agg.mean.len(), 2);
+        assert_eq!(agg.reconstruct(70)[69], agg.mean[1]);
+    }
+
+    #[test]
+    fn test_utility() {
+        let errors = vec![vec![0.3, 0.4, 0.05, 0.0], vec![0.1, 0.2, 0.0, 0.0]];
+        let utility = utility(&errors);
+        assert_eq!(utility.len(), 4);
+        assert!((utility[0] - 0.8).abs() < 1e-6);
+        // never decreases, even if a level is worse on average
+        assert!((utility[1] - 0.8).abs() < 1e-6);
+        assert!((utility[2] - 0.975).abs() < 1e-6);
+        assert_eq!(utility[3], 1.0);
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * Dashboard: maps the queries of the time series app, one window of one series,
+ * to screen space for the Gaussian model in `decoders::decode_model`.
+ *
+ * Each series is drawn in its own chart; all charts show the same time range.
+ * A window is laid out where it is, or would be after panning, in its series' chart.
+ */
+use ndarray::Array2;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// layout row of windows of series without a chart: zero width box
+const OFF_SCREEN: f32 = -1.0e9;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WindowKey {
+    pub series: String,
+    /// window index, samples [window * size, (window + 1) * size)
+    pub window: usize,
+}
+
+impl WindowKey {
+    /// parse "<series>/<window>", the series name may contain '/'
+    pub fn parse(key: &str) -> Option<Self> {
+        let mut parts = key.rsplitn(2, '/');
+        let window = parts.next()?.parse::<usize>().ok()?;
+        let series = parts.next()?.to_owned();
+        Some(WindowKey{ series: series, window: window })
+    }
+
+    pub fn to_key(&self) -> String {
+        format!("{}/{}", self.series, self.window)
+    }
+}
+
+/// client side `Bounds` of a chart
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chart {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+/// what the dashboard shows: samples [t0, t1) in every chart
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct View {
+    pub t0: f64,
+    pub t1: f64,
+    pub charts: HashMap<String, Chart>,
+}
+
+pub struct Dashboard {
+    /// samples per window
+    window: usize,
+    /// window of each query index, None for keys that aren't windows
+    windows: Vec<Option<WindowKey>>,
+}
+
+impl Dashboard {
+    /// keys: query keys in query index order
+    pub fn new<'a, I: Iterator<Item = &'a String>>(keys: I, window: usize) -> Self {
+        let windows = keys.map(|k| WindowKey::parse(k)).collect();
+        Dashboard{ window: std::cmp::max(1, window), windows: windows }
+    }
+
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// series names, in order of first appearance
+    pub fn series(&self) -> Vec<String> {
+        let mut series: Vec<String> = Vec::new();
+        for w in self.windows.iter().filter_map(|w| w.as_ref()) {
+            if !series.contains(&w.series) {
+                series.push(w.series.clone());
+            }
+        }
+        series
+    }
+
+    /// Layout matrix (xpw, xmw, yph, ymh per query) in screen pixels: the time axis of
+    /// each chart extends past its edges, so the windows a pan would show are next to it.
+    pub fn layout_matrix(&self, view: &View) -> Array2<f32> {
+        let mut layout = Array2::from_elem((self.windows.len(), 4), OFF_SCREEN);
+        let span = view.t1 - view.t0;
+        if span <= 0.0 {
+            return layout;
+        }
+
+        for (i, w) in self.windows.iter().enumerate() {
+            let (w, chart) = match w {
+                Some(w) => match view.charts.get(&w.series) {
+                    Some(chart) => (w, chart),
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            let scale = chart.w as f64 / span;
+            let start = (w.window * self.window) as f64;
+            let left = chart.x + ((start - view.t0) * scale) as f32;
+            let mut row = layout.row_mut(i);
+            row[0] = left + (self.window as f64 * scale) as f32;
+            row[1] = left;
+            row[2] = chart.y + chart.h;
+            row[3] = chart.y;
+        }
+
+        layout
+    }
+
+    /// window under the mouse at (mx, my), if it is over a chart
+    pub fn window_at(&self, view: &View, mx: f32, my: f32) -> Option<usize> {
+        let layout = self.layout_matrix(view);
+        let inside = view.charts.values().any(|c| c.x <= mx && mx < c.x + c.w && c.y <= my && my < c.y + c.h);
+        if !inside {
+            return None;
+        }
+        layout.genrows().into_iter().position(|row| row[1] <= mx && mx < row[0] && row[3] <= my && my < row[2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_matrix() {
+        let keys: Vec<String> = ["cpu", "mem/used"].iter()
+            .flat_map(|s| (0..4).map(move |w| WindowKey{ series: s.to_string(), window: w }.to_key()))
+            .collect();
+        assert_eq!(WindowKey::parse("mem/used/3"), Some(WindowKey{ series: "mem/used".to_owned(), window: 3 }));
+
+        let dashboard = Dashboard::new(keys.iter(), 100);
+        assert_eq!(dashboard.series(), vec!["cpu".to_owned(), "mem/used".to_owned()]);
+
+        // the cpu chart shows samples [100, 300) over 400 pixels
+        let mut charts = HashMap::new();
+        charts.insert("cpu".to_owned(), Chart{ x: 0.0, y: 0.0, w: 400.0, h: 100.0 });
+        let view = View{ t0: 100.0, t1: 300.0, charts: charts };
+        let layout = dashboard.layout_matrix(&view);
+
+        assert_eq!(layout.row(1).to_vec(), vec![200.0, 0.0, 100.0, 0.0]);
+        // panning left shows window 0, right of the chart is window 3
+        assert_eq!(layout.row(0).to_vec(), vec![0.0, -200.0, 100.0, 0.0]);
+        assert_eq!(layout.row(3).to_vec(), vec![600.0, 400.0, 100.0, 0.0]);
+        // no chart for mem/used
+        assert_eq!(layout.row(5)[1], OFF_SCREEN);
+
+        assert_eq!(dashboard.window_at(&view, 250.0, 50.0), Some(2));
+        assert_eq!(dashboard.window_at(&view, 450.0, 50.0), None);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod timeseries;
+pub mod aggregate;
+pub mod dashboard;
+pub use timeseries::*;
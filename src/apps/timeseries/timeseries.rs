@@ -0,0 +1,185 @@
+/*
+ * TimeSeriesApp: progressive loading of large time series in dashboard charts.
+ *
+ * A query is one window of one series ("<series>/<window>"). Its blocks are the
+ * window's aggregates at 1/64, 1/16, 1/4 and full resolution, so a few blocks
+ * across many likely windows beat the full resolution of one of them; the
+ * utility of each block is one minus the approximation error of its level.
+ *
+ * Backend: `BlockStore` keyed by query, each block a bincode LevelBlock and each
+ * query's metadata one minus the error of its levels, built from a CSV with
+ * `build_backend`.
+ *
+ * Server config (`app` section):
+ *     {"timeseries": {"db": "data/timeseries"}}
+ *
+ * Client distribution (PredictorState.data), the Gaussian over the mouse position
+ * and the dashboard's time range and charts:
+ *     {"dist": {"g": {"<delta ms>": {"xmu", "ymu", "xsigma", "ysigma"}}, "p": {"a", "X", "Y"}},
+ *      "state": {"t0": first sample, "t1": last sample, "charts": {series: {"x", "y", "w", "h"}}}}
+ */
+use serde_derive::{Deserialize, Serialize};
+
+use super::aggregate::{self, Aggregate, LEVELS};
+use super::dashboard::{Dashboard, View, WindowKey};
+use crate::apps::{self, AppTrait};
+use crate::ds;
+use crate::scheduler::{self, decoders};
+use crate::backend;
+
+pub struct TimeSeriesApp {
+    blocks_per_query: indexmap::IndexMap<String, usize>,
+    utility: Vec<f32>,
+    /// mean size of a block in bytes, blocks of finer levels are larger
+    blocksize: usize,
+    /// samples per window
+    window: usize,
+    backend: backend::blockstore::BlockStore,
+
+    dashboard: Dashboard,
+    view: View,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LevelBlock {
+    pub block_id: u32,
+    /// samples per window, the same for every window
+    pub window: u32,
+    /// approximation error of this level, see `Aggregate::error`
+    pub error: f32,
+    pub aggregate: Aggregate,
+}
+
+/// appstate: specific data passed at initialization state from the client
+/// config: configuration data passed from the server
+pub fn new(_appstate: &ds::AppState, config: serde_json::Value) -> Result<TimeSeriesApp, String> {
+    let config = &config["timeseries"];
+    let db_path = config["db"].as_str().unwrap_or("data/timeseries").to_string();
+
+    info!("1) load time series {:?}", db_path);
+    if !std::path::Path::new(&db_path).exists() {
+        return Err(format!("backend is not initialized {:?}, see timeseries::build_backend", db_path));
+    }
+    let backend = backend::blockstore::BlockStore::load(&db_path, TimeSeriesApp::split_blocks)?;
+
+    info!("2) create an index of how many blocks/window from the metadata");
+    let metas = backend.collect_meta();
+    let blocks_per_query: indexmap::IndexMap<String, usize> = metas.iter().map(|(k, m)| (k.clone(), m.nblocks)).collect();
+
+    info!("3) utility from the approximation error of each level");
+    let errors: Vec<Vec<f32>> = metas.values().map(|m| m.utility.iter().map(|u| 1.0 - u).collect()).collect();
+    let utility = aggregate::utility(&errors);
+    let total_bytes: usize = metas.values().map(|m| m.sizes.iter().sum::<usize>()).sum();
+    let total_blocks: usize = metas.values().map(|m| m.nblocks).sum();
+    let blocksize = if total_blocks > 0 { total_bytes / total_blocks } else { 0 };
+
+    // every window has the same number of samples, the first block has it
+    let window = metas.keys().next()
+        .and_then(|key| backend.get_block(key, 0))
+        .and_then(|bytes| bincode::deserialize::<LevelBlock>(&bytes).ok())
+        .map(|block| block.window as usize)
+        .unwrap_or(0);
+    info!("utility per level {:?}, mean block size {}", utility, blocksize);
+
+    let dashboard = Dashboard::new(blocks_per_query.keys(), window);
+    Ok(TimeSeriesApp{ blocks_per_query, utility, blocksize, window, backend,
+                      dashboard, view: View::default() })
+}
+
+/// Split every series of the CSV at `csv_path` in windows of `window` samples and
+/// store their aggregates at each of `LEVELS` in a `BlockStore` at `db_path`.
+/// Returns the number of windows.
+pub fn build_backend(csv_path: &str, db_path: &str, window: usize) -> Result<usize, csv::Error> {
+    let series = aggregate::read_csv(csv_path)?;
+    let window = std::cmp::max(1, window);
+    let mut backend = backend::blockstore::BlockStore::new(db_path.to_string());
+    let mut count = 0;
+
+    for (name, values) in series.iter() {
+        for (w, chunk) in values.chunks(window).enumerate() {
+            let blocks: Vec<LevelBlock> = LEVELS.iter().enumerate().map(|(i, &bucket)| {
+                let aggregate = Aggregate::new(chunk, bucket);
+                LevelBlock{ block_id: i as u32, window: window as u32, error: aggregate.error(chunk), aggregate }
+            }).collect();
+
+            let key = WindowKey{ series: name.clone(), window: w }.to_key();
+            let (encoded, utility) = TimeSeriesApp::encode_blocks(&blocks);
+            backend.put_query(&key, &encoded, utility);
+            count += 1;
+        }
+    }
+
+    backend.flush();
+    Ok(count)
+}
+
+impl TimeSeriesApp {
+    /// encoded blocks of a window and one minus the error of each level
+    fn encode_blocks(blocks: &[LevelBlock]) -> (Vec<Vec<u8>>, Vec<f32>) {
+        let encoded = blocks.iter().map(|b| bincode::serialize(b).unwrap()).collect();
+        let utility = blocks.iter().map(|b| 1.0 - b.error).collect();
+        (encoded, utility)
+    }
+
+    /// encoded blocks of a value of the one value per window layout, see `BlockStore::migrate`
+    fn split_blocks(v: &[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let blocks: Vec<LevelBlock> = bincode::deserialize(v).ok()?;
+        Some(TimeSeriesApp::encode_blocks(&blocks))
+    }
+
+    /// reads blocks incache..incache + count only
+    fn get_nblocks_bytes(&self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let nblocks = *self.blocks_per_query.get(key)?;
+        Some(apps::stream_blocks(&self.backend, key, nblocks, count, incache))
+    }
+}
+
+impl AppTrait for TimeSeriesApp {
+    fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+        (self.blocks_per_query.clone(), self.utility.clone())
+    }
+
+    fn get_nblocks_byindex(&mut self, index: usize, count: usize,
+                           incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let key = self.blocks_per_query.get_index(index).map(|(k, _)| k.clone())?;
+        self.get_nblocks_bytes(&key, count, incache)
+    }
+
+    fn get_nblocks_bykey(&mut self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        self.get_nblocks_bytes(key, count, incache)
+    }
+
+    /// the windows under the mouse, and the ones next to the charts a pan would show
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
+        let data = userstate.data;
+        if let Some(state) = data.get("state") {
+            match serde_json::from_value(state.clone()) {
+                Ok(view) => self.view = view,
+                Err(err) => debug!("no dashboard view in the client state {:?}", err),
+            }
+        }
+
+        let dist = &data["dist"];
+        let mut prob = match dist.get("g") {
+            Some(g) => decoders::decode_model(g, &self.dashboard.layout_matrix(&self.view)),
+            None => scheduler::Prob::new(self.dashboard.len()),
+        };
+
+        let (alpha, x, y) = decoders::decode_point_model(&dist["p"]);
+        if let Some(index) = self.dashboard.window_at(&self.view, x as f32, y as f32) {
+            prob.set_point_dist(alpha, index);
+        }
+
+        prob
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.blocksize
+    }
+
+    fn get_initstate(&mut self) -> String {
+        let windows: Vec<&String> = self.blocks_per_query.keys().collect();
+        serde_json::json!({"series": self.dashboard.series(), "window": self.window,
+                           "levels": LEVELS.to_vec(), "windows": windows}).to_string()
+    }
+}
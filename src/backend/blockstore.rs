@@ -0,0 +1,234 @@
+/*
+ * BlockStore: per-block storage layout on top of an `InMemBackend`.
+ *
+ * Every block is its own value in the "blocks" tree, keyed by (query, index), and
+ * every query has a `QueryMeta` record in the "meta" tree. Startup only reads the
+ * metadata, and reading block k of a query doesn't touch its other blocks.
+ *
+ * Stores built with one value per query (the whole bincode Vec of blocks) are
+ * converted with `migrate`, in the same database; `load` does it when it opens one.
+ */
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use super::inmem::InMemBackend;
+use super::BlockSource;
+
+const META_TREE: &str = "meta";
+const BLOCKS_TREE: &str = "blocks";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueryMeta {
+    pub nblocks: usize,
+    /// encoded size of each block in bytes
+    pub sizes: Vec<usize>,
+    /// utility of the first i + 1 blocks, empty if the app computes it
+    pub utility: Vec<f32>,
+}
+
+#[derive(Clone)]
+pub struct BlockStore {
+    backend: InMemBackend,
+    meta: Arc<sled::Tree>,
+    blocks: Arc<sled::Tree>,
+}
+
+/// key of block `index` of `query`: 0xff never appears in UTF-8, so keys of two queries don't collide
+fn block_key(query: &str, index: usize) -> Vec<u8> {
+    let mut key = query.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(&(index as u32).to_be_bytes());
+    key
+}
+
+impl BlockStore {
+    pub fn new(dbname: String) -> Self {
+        BlockStore::open(InMemBackend::new(dbname))
+    }
+
+    pub fn open(backend: InMemBackend) -> Self {
+        let meta = backend.open_tree(META_TREE);
+        let blocks = backend.open_tree(BLOCKS_TREE);
+        BlockStore{ backend: backend, meta: meta, blocks: blocks }
+    }
+
+    /// Open the store at `db_path`, converting the values of the one value per query
+    /// layout left in it with `split`, see `migrate`.
+    pub fn load<F>(db_path: &str, split: F) -> Result<Self, String>
+        where F: Fn(&[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let mut store = BlockStore::open(InMemBackend::open(db_path.to_string())?);
+        if store.has_legacy() {
+            let count = store.migrate(split);
+            store.flush();
+            info!("converted {} queries of {:?} to one value per block", count, db_path);
+        }
+        Ok(store)
+    }
+
+    /// the store has no query in the per-block layout
+    pub fn is_empty(&self) -> bool {
+        self.meta.iter().next().is_none()
+    }
+
+    /// the store has values of the one value per query layout
+    pub fn has_legacy(&self) -> bool {
+        self.backend.get_iter().next().is_some()
+    }
+
+    pub fn contains(&self, query: &str) -> bool {
+        self.meta.contains_key(query.as_bytes()).unwrap_or(false)
+    }
+
+    /// store the blocks of `query` and its metadata, replacing previous blocks
+    pub fn put_query(&mut self, query: &str, blocks: &[Vec<u8>], utility: Vec<f32>) {
+        let previous = self.get_meta(query).map(|m| m.nblocks).unwrap_or(0);
+        for (i, block) in blocks.iter().enumerate() {
+            let _ = self.blocks.set(block_key(query, i), block.clone());
+        }
+        for i in blocks.len()..previous {
+            let _ = self.blocks.del(block_key(query, i));
+        }
+
+        let meta = QueryMeta{ nblocks: blocks.len(), sizes: blocks.iter().map(|b| b.len()).collect(), utility: utility };
+        let _ = self.meta.set(query.as_bytes(), bincode::serialize(&meta).unwrap());
+    }
+
+    pub fn get_meta(&self, query: &str) -> Option<QueryMeta> {
+        let bytes = self.meta.get(query.as_bytes()).ok()??;
+        bincode::deserialize(&bytes).ok()
+    }
+
+    /// block `index` of `query`
+    pub fn get_block(&self, query: &str, index: usize) -> Option<Vec<u8>> {
+        match self.blocks.get(block_key(query, index)) {
+            Ok(Some(bytes)) => Some(bytes.to_vec()),
+            Ok(None) => None,
+            Err(err) => {
+                error!("block {} of {:?}: {:?}", index, query, err);
+                None
+            }
+        }
+    }
+
+    /// metadata of every query, in key order
+    pub fn collect_meta(&self) -> indexmap::IndexMap<String, QueryMeta> {
+        let mut metas = indexmap::IndexMap::new();
+        for result in self.meta.iter() {
+            match result {
+                Ok((k, v)) => {
+                    let key = String::from_utf8_lossy(&k).to_string();
+                    match bincode::deserialize::<QueryMeta>(&v) {
+                        Ok(meta) => { metas.insert(key, meta); },
+                        Err(err) => error!("metadata of {:?}: {:?}", key, err),
+                    }
+                },
+                Err(err) => error!("{:?}", err),
+            }
+        }
+        metas
+    }
+
+    pub fn collect_blocks_per_query(&self) -> indexmap::IndexMap<String, usize> {
+        self.collect_meta().into_iter().map(|(k, m)| (k, m.nblocks)).collect()
+    }
+
+    /// Convert the values of the one value per query layout with `split`, which
+    /// returns the encoded blocks of a value and their utility. Returns the number
+    /// of queries converted; their old values are deleted once they all are, values
+    /// `split` can't read are kept.
+    pub fn migrate<F>(&mut self, split: F) -> usize
+        where F: Fn(&[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)> {
+        let mut converted: Vec<Vec<u8>> = Vec::new();
+        for result in self.backend.get_iter() {
+            let (k, v) = match result {
+                Ok(kv) => kv,
+                Err(err) => {
+                    error!("{:?}", err);
+                    continue;
+                }
+            };
+            let key = String::from_utf8_lossy(&k).to_string();
+            match split(&v) {
+                Some((blocks, utility)) => {
+                    self.put_query(&key, &blocks, utility);
+                    converted.push(k.to_vec());
+                },
+                None => error!("couldn't split the blocks of {:?}", key),
+            }
+        }
+
+        for key in converted.iter() {
+            self.backend.del(key);
+        }
+        converted.len()
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(err) = self.meta.flush() {
+            error!("flush error: {:?}", err);
+        }
+        if let Err(err) = self.blocks.flush() {
+            error!("flush error: {:?}", err);
+        }
+        self.backend.flush();
+    }
+}
+
+/// the stored utility of the query with the most blocks, None if it wasn't stored
+pub fn max_utility(metas: &indexmap::IndexMap<String, QueryMeta>) -> Option<Vec<f32>> {
+    let meta = metas.values().max_by_key(|m| m.nblocks)?;
+    if meta.utility.len() == meta.nblocks && meta.nblocks > 0 {
+        Some(meta.utility.clone())
+    } else {
+        None
+    }
+}
+
+impl BlockSource for BlockStore {
+    fn queries(&self) -> indexmap::IndexMap<String, QueryMeta> {
+        self.collect_meta()
+    }
+
+    fn block(&self, query: &str, index: usize) -> Option<Cow<'_, [u8]>> {
+        self.get_block(query, index).map(Cow::Owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blockstore_layout() {
+        let path = std::env::temp_dir().join(format!("khameleon_blockstore_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mut legacy = InMemBackend::new(path.to_string_lossy().to_string());
+        legacy.set(b"q1".to_vec(), bincode::serialize(&vec![vec![1u8, 2], vec![3u8]]).unwrap());
+
+        let mut store = BlockStore::open(legacy);
+        assert!(store.is_empty());
+        let converted = store.migrate(|v| {
+            let blocks: Vec<Vec<u8>> = bincode::deserialize(v).ok()?;
+            Some((blocks, vec![0.5, 1.0]))
+        });
+        assert_eq!(converted, 1);
+        assert!(!store.has_legacy());
+        assert!(store.contains("q1"));
+
+        store.put_query("q0", &[vec![9u8; 4]], vec![]);
+        assert_eq!(max_utility(&store.collect_meta()), Some(vec![0.5, 1.0]));
+        assert_eq!(store.get_block("q1", 1), Some(vec![3u8]));
+        assert_eq!(store.get_block("q1", 2), None);
+        assert_eq!(store.get_meta("q1"), Some(QueryMeta{ nblocks: 2, sizes: vec![2, 1], utility: vec![0.5, 1.0] }));
+
+        // fewer blocks replace the old ones
+        store.put_query("q1", &[vec![7u8]], vec![1.0]);
+        assert_eq!(store.get_block("q1", 1), None);
+        let counts: Vec<(String, usize)> = store.collect_blocks_per_query().into_iter().collect();
+        assert_eq!(counts, vec![("q0".to_owned(), 1), ("q1".to_owned(), 1)]);
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -10,12 +10,20 @@ pub struct InMemBackend {
 
 impl InMemBackend {
     pub fn new(dbname: String) -> Self {
+        InMemBackend::open(dbname).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// like `new`, with an error if the store can't be opened, e.g. it's locked or corrupted
+    pub fn open(dbname: String) -> Result<Self, String> {
         // initialize backend server
         let config = sled::ConfigBuilder::new()
                 .path(&dbname)
                 .build();
 
-        InMemBackend{dbname: dbname, db:Db::start(config).unwrap()}
+        match Db::start(config) {
+            Ok(db) => Ok(InMemBackend{dbname: dbname, db: db}),
+            Err(err) => Err(format!("couldn't open the store {:?}: {:?}", dbname, err)),
+        }
     }
 
     pub fn set(&mut self, key:Vec<u8>, val: Vec<u8>) {
@@ -37,6 +45,22 @@ impl InMemBackend {
         }
     }
 
+    pub fn del(&mut self, key: &[u8]) {
+        if let Err(err) = self.db.del(key) {
+            error!("couldn't delete {:?}: {:?}", key, err);
+        }
+    }
+
+    /// is there a value at key @key, without the warning of `get`
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.db.contains_key(key).unwrap_or(false)
+    }
+
+    /// named tree in the same database, next to the default one
+    pub fn open_tree(&self, name: &str) -> std::sync::Arc<sled::Tree> {
+        self.db.open_tree(name).unwrap()
+    }
+
     pub fn get_iter(&self) -> sled::Iter {
         self.db.iter()
     }
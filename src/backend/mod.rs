@@ -1 +1,57 @@
 pub mod inmem;
+pub mod blockstore;
+pub mod packfile;
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Read side of the per-block layouts: `blockstore::BlockStore` and `packfile::PackFile`
+pub trait BlockSource: Send + Sync {
+    /// metadata of every query, in query order
+    fn queries(&self) -> indexmap::IndexMap<String, blockstore::QueryMeta>;
+
+    /// block `index` of `query`, borrowed if the backend can
+    fn block(&self, query: &str, index: usize) -> Option<Cow<'_, [u8]>>;
+
+    /// block `index` of `query` that outlives the borrow of the source: a view of the
+    /// source's buffer if it has one, a copy otherwise
+    fn shared_block(&self, query: &str, index: usize) -> Option<SharedBytes> {
+        self.block(query, index).map(|block| SharedBytes::from(block.into_owned()))
+    }
+}
+
+/// Bytes start..end of a buffer held by an Arc, e.g. a packfile mapping; clones share the buffer
+#[derive(Clone)]
+pub struct SharedBytes {
+    buf: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    start: usize,
+    end: usize,
+}
+
+impl SharedBytes {
+    pub fn new(buf: Arc<dyn AsRef<[u8]> + Send + Sync>, start: usize, end: usize) -> Self {
+        assert!(start <= end && end <= (*buf).as_ref().len(), "{}..{} is out of the buffer", start, end);
+        SharedBytes{ buf: buf, start: start, end: end }
+    }
+}
+
+impl From<Vec<u8>> for SharedBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        let end = bytes.len();
+        SharedBytes{ buf: Arc::new(bytes), start: 0, end: end }
+    }
+}
+
+impl std::ops::Deref for SharedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.buf).as_ref()[self.start..self.end]
+    }
+}
+
+impl std::fmt::Debug for SharedBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SharedBytes({} bytes)", self.end - self.start)
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * PackFile: read-only backend over a memory-mapped packfile, for static datasets.
+ *
+ * Layout:
+ *     magic "KHPACK01" | header length (u64 LE) | header | blocks
+ * The header is the bincode `Vec<PackEntry>`: per query its `QueryMeta` and the
+ * offset of each block from the start of the blocks. Blocks are concatenated in
+ * query order, and read as slices of the mapping without a copy; `shared_block`
+ * hands them to the sender as views that keep the mapping alive.
+ *
+ * Packfiles are written by `write` from any source of blocks; `src/bin/packfile.rs`
+ * builds one from a `BlockStore`.
+ */
+use memmap::Mmap;
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use super::blockstore::QueryMeta;
+use super::{BlockSource, SharedBytes};
+
+const MAGIC: &[u8; 8] = b"KHPACK01";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub key: String,
+    pub meta: QueryMeta,
+    /// offset of each block from the start of the blocks
+    pub offsets: Vec<u64>,
+}
+
+pub struct PackFile {
+    /// shared with the blocks on their way to the client
+    mmap: Arc<Mmap>,
+    /// offset of the first block in the file
+    data_start: usize,
+    index: indexmap::IndexMap<String, PackEntry>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl PackFile {
+    /// map the packfile at `path` and read its header, checking every block is in the file
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // the file is read-only and not modified while the server runs
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < 16 || &mmap[..8] != MAGIC {
+            return Err(invalid(format!("{:?} is not a packfile", path)));
+        }
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&mmap[8..16]);
+        let header_len = u64::from_le_bytes(len) as usize;
+        let data_start = 16usize.checked_add(header_len)
+            .filter(|&end| end <= mmap.len())
+            .ok_or_else(|| invalid(format!("{:?}: header is truncated", path)))?;
+
+        let entries: Vec<PackEntry> = bincode::deserialize(&mmap[16..data_start])
+            .map_err(|e| invalid(format!("{:?}: bad header {}", path, e)))?;
+        let data_len = (mmap.len() - data_start) as u64;
+        for entry in entries.iter() {
+            if entry.offsets.len() != entry.meta.nblocks || entry.meta.sizes.len() != entry.meta.nblocks {
+                return Err(invalid(format!("{:?}: inconsistent entry {:?}", path, entry.key)));
+            }
+            let out_of_file = entry.offsets.iter().zip(entry.meta.sizes.iter())
+                .any(|(&offset, &size)| offset.checked_add(size as u64).map(|end| end > data_len).unwrap_or(true));
+            if out_of_file {
+                return Err(invalid(format!("{:?}: blocks of {:?} are past the end of the file", path, entry.key)));
+            }
+        }
+
+        let index = entries.into_iter().map(|e| (e.key.clone(), e)).collect();
+        Ok(PackFile{ mmap: Arc::new(mmap), data_start: data_start, index: index })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get_meta(&self, query: &str) -> Option<&QueryMeta> {
+        self.index.get(query).map(|e| &e.meta)
+    }
+
+    /// range of block `index` of `query` in the mapping
+    fn range(&self, query: &str, index: usize) -> Option<(usize, usize)> {
+        let entry = self.index.get(query)?;
+        let start = self.data_start + *entry.offsets.get(index)? as usize;
+        Some((start, start + entry.meta.sizes[index]))
+    }
+
+    /// block `index` of `query`, borrowed from the mapping
+    pub fn get_block(&self, query: &str, index: usize) -> Option<&[u8]> {
+        let (start, end) = self.range(query, index)?;
+        Some(&self.mmap[start..end])
+    }
+}
+
+impl BlockSource for PackFile {
+    fn queries(&self) -> indexmap::IndexMap<String, QueryMeta> {
+        self.index.iter().map(|(k, e)| (k.clone(), e.meta.clone())).collect()
+    }
+
+    fn block(&self, query: &str, index: usize) -> Option<Cow<'_, [u8]>> {
+        self.get_block(query, index).map(Cow::Borrowed)
+    }
+
+    fn shared_block(&self, query: &str, index: usize) -> Option<SharedBytes> {
+        let (start, end) = self.range(query, index)?;
+        Some(SharedBytes::new(self.mmap.clone(), start, end))
+    }
+}
+
+/// Write a packfile of the queries in `metas` to `out`, reading each block with
+/// `block(query, index)` one at a time. Returns the number of bytes written.
+pub fn write<W, F>(out: &mut W, metas: &indexmap::IndexMap<String, QueryMeta>, mut block: F) -> io::Result<u64>
+    where W: Write, F: FnMut(&str, usize) -> Option<Vec<u8>> {
+    // offsets follow from the sizes, the header goes first
+    let mut offset = 0u64;
+    let entries: Vec<PackEntry> = metas.iter().map(|(key, meta)| {
+        let offsets = meta.sizes.iter().map(|&size| {
+            let o = offset;
+            offset += size as u64;
+            o
+        }).collect();
+        PackEntry{ key: key.clone(), meta: meta.clone(), offsets: offsets }
+    }).collect();
+    let header = bincode::serialize(&entries).map_err(|e| invalid(e.to_string()))?;
+
+    out.write_all(MAGIC)?;
+    out.write_all(&(header.len() as u64).to_le_bytes())?;
+    out.write_all(&header)?;
+    for (key, meta) in metas.iter() {
+        for (i, &size) in meta.sizes.iter().enumerate() {
+            let bytes = block(key, i).ok_or_else(|| invalid(format!("block {} of {:?} is missing", i, key)))?;
+            if bytes.len() != size {
+                return Err(invalid(format!("block {} of {:?} has {} bytes, the metadata says {}", i, key, bytes.len(), size)));
+            }
+            out.write_all(&bytes)?;
+        }
+    }
+    out.flush()?;
+
+    Ok(16 + header.len() as u64 + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packfile_roundtrip() {
+        let blocks: Vec<(String, Vec<Vec<u8>>)> = vec![("a".to_owned(), vec![vec![1, 2, 3], vec![4]]),
+                                                       ("b".to_owned(), vec![vec![5, 6]])];
+        let metas: indexmap::IndexMap<String, QueryMeta> = blocks.iter().map(|(k, b)| {
+            (k.clone(), QueryMeta{ nblocks: b.len(), sizes: b.iter().map(|x| x.len()).collect(), utility: vec![] })
+        }).collect();
+
+        let path = std::env::temp_dir().join(format!("khameleon_packfile_{}.pack", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        let written = write(&mut file, &metas, |k, i| {
+            blocks.iter().find(|(key, _)| key == k).and_then(|(_, b)| b.get(i).cloned())
+        }).unwrap();
+        drop(file);
+        assert_eq!(written, std::fs::metadata(&path).unwrap().len());
+
+        let pack = PackFile::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(pack.len(), 2);
+        assert_eq!(pack.get_block("a", 1), Some(&[4u8][..]));
+        assert_eq!(pack.get_block("b", 0), Some(&[5u8, 6][..]));
+        assert_eq!(pack.get_block("b", 1), None);
+        // shared blocks are views of the mapping, not copies
+        let shared = pack.shared_block("a", 0).unwrap();
+        assert_eq!(&*shared, &[1u8, 2, 3][..]);
+        assert_eq!(shared.as_ptr(), pack.get_block("a", 0).unwrap().as_ptr());
+        assert_eq!(pack.queries(), metas);
+
+        // a truncated file is rejected
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(PackFile::open(path.to_str().unwrap()).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,99 @@
+/// packfile: builds a read-only packfile (see khameleon::backend::packfile) from an app's
+/// sled store in the per-block layout of khameleon::backend::blockstore. The store is
+/// only read. Stores with one value per query are refused unless `--migrate APP` is
+/// given: they're then converted in place with the block format of APP, and their
+/// old values deleted, before the packfile is written.
+///
+/// $ cargo run --release --bin packfile -- data/test_data data/test_data.pack
+/// $ cargo run --release --bin packfile -- --migrate gallery data/gallery data/gallery.pack
+use khameleon::apps::gallery::GalleryApp;
+use khameleon::apps::testapp::TestApp;
+use khameleon::backend::blockstore::BlockStore;
+use khameleon::backend::inmem::InMemBackend;
+use khameleon::backend::packfile;
+
+const USAGE: &str = "usage: packfile [--migrate testapp|gallery] SLED_DB OUT.pack";
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let migrate = match args.iter().position(|arg| arg == "--migrate") {
+        Some(i) if i + 1 < args.len() => {
+            let app = args.remove(i + 1);
+            args.remove(i);
+            Some(app)
+        },
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+        None => None,
+    };
+    let split: Option<fn(&[u8]) -> Option<(Vec<Vec<u8>>, Vec<f32>)>> = match migrate.as_ref().map(|a| a.as_str()) {
+        Some("testapp") => Some(TestApp::split_blocks),
+        Some("gallery") => Some(GalleryApp::split_blocks),
+        Some(other) => {
+            eprintln!("no migration for app {:?}\n{}", other, USAGE);
+            std::process::exit(2);
+        },
+        None => None,
+    };
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let (db_path, out_path) = (&args[0], &args[1]);
+
+    if !std::path::Path::new(db_path).exists() {
+        eprintln!("no sled store at {:?}", db_path);
+        std::process::exit(1);
+    }
+    let mut store = match InMemBackend::open(db_path.clone()) {
+        Ok(backend) => BlockStore::open(backend),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if store.has_legacy() {
+        match split {
+            Some(split) => {
+                let count = store.migrate(split);
+                store.flush();
+                println!("{}: converted {} queries to one value per block", db_path, count);
+            },
+            None => {
+                eprintln!("{:?} stores one value per query; start its app on it once, or pass \
+                           --migrate APP to convert it in place (its old values are deleted)", db_path);
+                std::process::exit(1);
+            }
+        }
+    }
+    if store.is_empty() {
+        eprintln!("{:?} has no blocks", db_path);
+        std::process::exit(1);
+    }
+    let metas = store.collect_meta();
+
+    // written next to the output and renamed, a server never maps a partial file
+    let tmp_path = format!("{}.tmp", out_path);
+    let result = std::fs::File::create(&tmp_path).and_then(|file| {
+        let mut out = std::io::BufWriter::new(file);
+        packfile::write(&mut out, &metas, |query, index| store.get_block(query, index))
+    }).and_then(|bytes| std::fs::rename(&tmp_path, out_path).map(|_| bytes));
+
+    match result {
+        Ok(bytes) => {
+            let blocks: usize = metas.values().map(|m| m.nblocks).sum();
+            println!("{}: {} queries, {} blocks, {} bytes", out_path, metas.len(), blocks, bytes);
+        },
+        Err(err) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            eprintln!("couldn't write {:?}: {}", out_path, err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+/*
+ * ServerConfig: typed server configuration.
+ *
+ * Values are read, in increasing order of precedence, from the defaults below,
+ * a JSON config file, KHAMELEON_* environment variables, and command line flags.
+ * Unknown keys and invalid values are rejected at startup.
+ *
+ * # Example
+ * $ khameleon config.json --bind 127.0.0.1:9000 --latency 50
+ * $ KHAMELEON_LOG_LEVEL=info khameleon --config config.json
+ */
+use crate::scheduler;
+
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// address the webserver listens on
+    pub bind: String,
+    /// one of: off, error, warn, info, debug, trace
+    pub log_level: String,
+    /// initial round trip latency estimate in ms
+    pub latency: usize,
+    /// initial bandwidth estimate in Mbps
+    pub bandwidth: f64,
+    /// fixed sending rate in Mbps, replaces `bandwidth` if > 0
+    pub rate: usize,
+    /// minimum wait between two blocks in ns
+    pub min_wait: usize,
+    /// run the scheduler and sender threads, otherwise only serve direct requests
+    #[serde(rename = "runScheduler")]
+    pub run_scheduler: bool,
+    /// plan with arrival times at this percentile of the bandwidth forecast, e.g. 0.9
+    pub bw_percentile: Option<f64>,
+    pub scheduler: scheduler::SchedulerOptions,
+    /// worker threads generating blocks, for apps with a `Producer`
+    pub producer_workers: usize,
+    /// time to generate a query's blocks assumed until one is generated, in ms
+    pub producer_latency_ms: f64,
+    /// memory for encoded blocks kept across sessions, in MB, 0 disables the block cache
+    pub block_cache_mb: usize,
+    /// app specific configuration, passed to the app as is
+    pub app: serde_json::Value,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig{ bind: "0.0.0.0:8080".to_owned(),
+                      log_level: "debug".to_owned(),
+                      latency: 100,
+                      bandwidth: 10.0,
+                      rate: 0,
+                      min_wait: 0,
+                      run_scheduler: true,
+                      bw_percentile: None,
+                      scheduler: scheduler::SchedulerOptions::default(),
+                      producer_workers: 4,
+                      producer_latency_ms: 100.0,
+                      block_cache_mb: 256,
+                      app: serde_json::json!({}) }
+    }
+}
+
+/// (command line flag, environment variable, config key)
+const OVERRIDES: [(&str, &str, &str); 10] = [
+    ("--bind", "KHAMELEON_BIND", "bind"),
+    ("--log-level", "KHAMELEON_LOG_LEVEL", "log_level"),
+    ("--latency", "KHAMELEON_LATENCY", "latency"),
+    ("--bandwidth", "KHAMELEON_BANDWIDTH", "bandwidth"),
+    ("--rate", "KHAMELEON_RATE", "rate"),
+    ("--min-wait", "KHAMELEON_MIN_WAIT", "min_wait"),
+    ("--run-scheduler", "KHAMELEON_RUN_SCHEDULER", "runScheduler"),
+    ("--bw-percentile", "KHAMELEON_BW_PERCENTILE", "bw_percentile"),
+    ("--producer-workers", "KHAMELEON_PRODUCER_WORKERS", "producer_workers"),
+    ("--block-cache-mb", "KHAMELEON_BLOCK_CACHE_MB", "block_cache_mb"),
+];
+
+pub fn usage() -> String {
+    let mut usage = "usage: khameleon [CONFIG.json] [--config CONFIG.json] [--FLAG VALUE]...\n\nflags:\n".to_owned();
+    for (flag, env, key) in OVERRIDES.iter() {
+        usage += &format!("  {:<16} {:<26} config key: {}\n", flag, env, key);
+    }
+    usage += &format!("\ndefaults:\n{}\n", serde_json::to_string_pretty(&ServerConfig::default()).unwrap());
+    usage
+}
+
+/// parse a flag value as JSON (numbers, bools), falling back to a string
+fn parse_value(raw: &str) -> serde_json::Value {
+    match serde_json::from_str(raw) {
+        Ok(value) => value,
+        Err(_) => serde_json::Value::String(raw.to_owned()),
+    }
+}
+
+impl ServerConfig {
+    /// build the config from command line arguments (without the program name)
+    /// and the process environment
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let env: Vec<(String, String)> = std::env::vars().collect();
+        ServerConfig::from_sources(args, &env)
+    }
+
+    pub fn from_sources(args: &[String], env: &[(String, String)]) -> Result<Self, String> {
+        // 1) collect file name and flags
+        let mut file: Option<String> = None;
+        let mut flags: Vec<(&str, String)> = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--config" {
+                file = Some(iter.next().ok_or("--config expects a file name")?.clone());
+            } else if arg.starts_with("--") {
+                let key = match OVERRIDES.iter().find(|(flag, _, _)| flag == arg) {
+                    Some((_, _, key)) => *key,
+                    None => return Err(format!("unknown flag {:?}\n{}", arg, usage())),
+                };
+                let value = iter.next().ok_or(format!("{} expects a value", arg))?;
+                flags.push((key, value.clone()));
+            } else if file.is_none() {
+                file = Some(arg.clone());
+            } else {
+                return Err(format!("unexpected argument {:?}\n{}", arg, usage()));
+            }
+        }
+
+        // 2) config file
+        let mut config: serde_json::Value = match &file {
+            Some(fname) => {
+                let file = std::fs::File::open(fname).map_err(|e| format!("couldn't open config {:?}: {}", fname, e))?;
+                serde_json::from_reader(file).map_err(|e| format!("config {:?} is not valid JSON: {}", fname, e))?
+            },
+            None => serde_json::json!({}),
+        };
+        let obj = config.as_object_mut().ok_or("config file should contain a JSON object")?;
+
+        // 3) environment, then 4) flags
+        for (_, var, key) in OVERRIDES.iter() {
+            if let Some((_, value)) = env.iter().find(|(name, _)| name == var) {
+                obj.insert(key.to_string(), parse_value(value));
+            }
+        }
+        for (key, value) in flags {
+            obj.insert(key.to_owned(), parse_value(&value));
+        }
+
+        let config: ServerConfig = serde_json::from_value(config).map_err(|e| format!("invalid config: {}", e))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        use std::net::ToSocketAddrs;
+
+        if self.bind.to_socket_addrs().is_err() {
+            return Err(format!("bind: invalid address {:?}", self.bind));
+        }
+        self.log_level_filter()?;
+        if !(self.bandwidth > 0.0) || !self.bandwidth.is_finite() {
+            return Err(format!("bandwidth: should be a positive number of Mbps, got {}", self.bandwidth));
+        }
+        if let Some(p) = self.bw_percentile {
+            if !(p > 0.0 && p < 1.0) {
+                return Err(format!("bw_percentile: should be in (0, 1), got {}", p));
+            }
+        }
+        if self.producer_workers == 0 {
+            return Err("producer_workers: should be at least 1".to_owned());
+        }
+        if !(self.producer_latency_ms >= 0.0) || !self.producer_latency_ms.is_finite() {
+            return Err(format!("producer_latency_ms: should be a non negative number of ms, got {}", self.producer_latency_ms));
+        }
+        if !self.app.is_object() {
+            return Err("app: should be a JSON object".to_owned());
+        }
+
+        let sched = &self.scheduler;
+        if sched.threads == 0 {
+            return Err("scheduler.threads: should be at least 1".to_owned());
+        }
+        if !(sched.replan_tolerance >= 0.0 && sched.replan_tolerance <= 1.0) {
+            return Err(format!("scheduler.replan_tolerance: should be in [0, 1], got {}", sched.replan_tolerance));
+        }
+        if sched.min_horizon == 0 || sched.min_horizon > sched.max_horizon {
+            return Err(format!("scheduler: expected 0 < min_horizon <= max_horizon, got {} and {}",
+                               sched.min_horizon, sched.max_horizon));
+        }
+        match sched.decay {
+            scheduler::Decay::None => (),
+            scheduler::Decay::Exponential { half_life_ms: v } |
+            scheduler::Decay::Linear { deadline_ms: v } |
+            scheduler::Decay::Step { deadline_ms: v } => {
+                if !(v > 0.0) {
+                    return Err(format!("scheduler.decay: expected a positive duration, got {}", v));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn log_level_filter(&self) -> Result<log::LevelFilter, String> {
+        self.log_level.parse::<log::LevelFilter>()
+            .map_err(|_| format!("log_level: expected off, error, warn, info, debug or trace, got {:?}", self.log_level))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let env = vec![("KHAMELEON_LATENCY".to_owned(), "50".to_owned()),
+                       ("KHAMELEON_BIND".to_owned(), "127.0.0.1:9000".to_owned())];
+        let config = ServerConfig::from_sources(&args(&["--latency", "20", "--run-scheduler", "false"]), &env).unwrap();
+
+        assert_eq!(config.latency, 20);
+        assert_eq!(config.bind, "127.0.0.1:9000");
+        assert_eq!(config.run_scheduler, false);
+        assert_eq!(config.bandwidth, ServerConfig::default().bandwidth);
+    }
+
+    #[test]
+    fn test_config_rejects_invalid() {
+        assert!(ServerConfig::from_sources(&args(&["--latncy", "20"]), &[]).is_err());
+        assert!(ServerConfig::from_sources(&args(&["--latency", "fast"]), &[]).is_err());
+        assert!(ServerConfig::from_sources(&args(&["--bandwidth", "0"]), &[]).is_err());
+        assert!(ServerConfig::from_sources(&args(&["--log-level", "loud"]), &[]).is_err());
+
+        let unknown: Result<ServerConfig, _> = serde_json::from_value(serde_json::json!({"runscheduler": true}));
+        assert!(unknown.is_err());
+    }
+}
@@ -2,47 +2,261 @@
  *
  * TimeManager: stores system information that are needed to translate between server time
  *              to client time to enable model querying.
+ * ClockSync: estimates the offset and skew of the client's clock from timestamped exchanges.
+ * BandwidthForecast: recent bandwidth samples, used to forecast block transfer times.
  */
 
-/// local imports
-use crate::apps;
-
 /// public lib
 use serde_json::{Value};
 use serde_derive::{Deserialize, Serialize};
 /// for Message macro
 use actix::prelude::*;
 use crossbeam_utils::atomic::AtomicCell;
+use std::collections::VecDeque;
 use std::sync::{Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::backend::SharedBytes;
 
 #[allow(dead_code)]
 #[derive(Debug, Message)]
 pub enum StreamBlock {
     Binary(Vec<u8>),
+    /// app block whose encoded block is shared with the backend, see apps::stream_blocks
+    Shared(BlockBytes),
+    /// block already framed with its session block id, see manager::session
+    Framed(BlockBytes),
     Stop
 }
 
+/// A block on its way to the client: a small header followed by the encoded block,
+/// which is shared with the backend or the block cache instead of copied. It's only
+/// put together on the socket.
+#[derive(Clone, Debug)]
+pub struct BlockBytes {
+    pub head: Vec<u8>,
+    pub body: SharedBytes,
+}
+
+impl BlockBytes {
+    pub fn len(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the bytes on the wire
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        bytes.extend_from_slice(&self.head);
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+impl From<Vec<u8>> for BlockBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        BlockBytes{ head: Vec::new(), body: SharedBytes::from(bytes) }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PredictorState {
     pub model: String,
     pub data: serde_json::Value,
+    /// client timestamp (ms since epoch) of the prediction, in the client's clock
+    #[serde(default)]
+    pub time: Option<f64>,
 }
 
 impl PredictorState {
     pub fn new(model: &str, data: serde_json::Value) -> Self {
-        PredictorState{model: model.to_owned(), data: data}
+        PredictorState{model: model.to_owned(), data: data, time: None}
+    }
+}
+
+/// current server time in ms since epoch
+pub fn now_ms() -> f64 {
+    let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+    since_the_epoch.as_micros() as f64 / 1000.0
+}
+
+/// one timestamped exchange, server clock: t0 sent, t3 received;
+/// client clock: t1 received, t2 replied. Block acks are exchanges with t1 == t2.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockSample {
+    /// server time halfway through the exchange
+    pub server_ms: f64,
+    /// client clock - server clock
+    pub offset_ms: f64,
+    pub rtt_ms: f64,
+}
+
+impl ClockSample {
+    pub fn new(t0: f64, t1: f64, t2: f64, t3: f64) -> Self {
+        ClockSample{ server_ms: (t0 + t3) / 2.0,
+                     offset_ms: ((t1 - t0) + (t2 - t3)) / 2.0,
+                     rtt_ms: ((t3 - t0) - (t2 - t1)).max(0.0) }
+    }
+}
+
+/// NTP-style estimate of the client clock:
+///     client_ms = server_ms + offset_ms + skew * (server_ms - base_ms)
+///
+/// Only the samples with a round trip close to the minimum are trusted, queuing
+/// delays make the others asymmetric. The skew is the slope of a least squares fit
+/// of their offsets over server time.
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+    capacity: usize,
+    offset_ms: f64,
+    skew: f64,
+    base_ms: f64,
+}
+
+impl ClockSync {
+    pub fn new(capacity: usize) -> Self {
+        ClockSync{ samples: VecDeque::with_capacity(capacity), capacity: capacity,
+                   offset_ms: 0.0, skew: 0.0, base_ms: 0.0 }
+    }
+
+    pub fn add_sample(&mut self, sample: ClockSample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.estimate();
+    }
+
+    fn estimate(&mut self) {
+        let min_rtt = self.samples.iter().map(|s| s.rtt_ms).fold(std::f64::INFINITY, f64::min);
+        let good: Vec<&ClockSample> = self.samples.iter().filter(|s| s.rtt_ms <= min_rtt * 1.5 + 1.0).collect();
+        let n = good.len() as f64;
+        if n == 0.0 {
+            return;
+        }
+
+        let mean_t = good.iter().map(|s| s.server_ms).sum::<f64>() / n;
+        let mean_o = good.iter().map(|s| s.offset_ms).sum::<f64>() / n;
+        let var_t: f64 = good.iter().map(|s| (s.server_ms - mean_t).powi(2)).sum();
+        let cov: f64 = good.iter().map(|s| (s.server_ms - mean_t) * (s.offset_ms - mean_o)).sum();
+
+        // samples too close in time to tell skew from noise
+        self.skew = if good.len() > 1 && var_t > 1.0 { cov / var_t } else { 0.0 };
+        self.offset_ms = mean_o;
+        self.base_ms = mean_t;
+    }
+
+    pub fn has_samples(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    pub fn offset_ms(&self) -> f64 {
+        self.offset_ms
+    }
+
+    pub fn skew(&self) -> f64 {
+        self.skew
+    }
+
+    pub fn server_to_client_ms(&self, server_ms: f64) -> f64 {
+        server_ms + self.offset_ms + self.skew * (server_ms - self.base_ms)
+    }
+
+    pub fn client_to_server_ms(&self, client_ms: f64) -> f64 {
+        (client_ms - self.offset_ms + self.skew * self.base_ms) / (1.0 + self.skew)
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AppState {
-    pub appname: apps::AppType,
+    /// name the app is registered under in apps::AppRegistry
+    pub appname: String,
     pub cachesize: usize,
 
     // app specific initializations
     pub state: Value,
 }
 
+/// Forecast of the time it takes to transfer a block from recent bandwidth samples.
+///
+/// Transfer time is size / bandwidth, so its mean comes from the harmonic mean of the
+/// bandwidth samples, and its spread from the variance of 1 / bandwidth.
+pub struct BandwidthForecast {
+    /// the last `capacity` samples in Mbps, the window is a number of samples
+    history: VecDeque<f64>,
+    capacity: usize,
+}
+
+impl BandwidthForecast {
+    pub fn new(capacity: usize) -> Self {
+        BandwidthForecast{ history: VecDeque::with_capacity(capacity), capacity: capacity }
+    }
+
+    pub fn push(&mut self, bw: f64) {
+        if bw <= 0.0 {
+            return;
+        }
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(bw);
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// harmonic mean of the recent bandwidth samples in Mbps
+    pub fn harmonic_mean(&self) -> Option<f64> {
+        if self.history.is_empty() {
+            return None;
+        }
+
+        let n = self.history.len() as f64;
+        Some(n / self.history.iter().map(|bw| 1.0 / bw).sum::<f64>())
+    }
+
+    /// mean and standard deviation (ms) of the time to transfer `megabits`
+    pub fn transfer_time_ms(&self, megabits: f64) -> Option<(f64, f64)> {
+        if self.history.is_empty() {
+            return None;
+        }
+
+        let n = self.history.len() as f64;
+        let times: Vec<f64> = self.history.iter().map(|bw| megabits / bw * 1000.0).collect();
+        let mean = times.iter().sum::<f64>() / n;
+        let var = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / n;
+
+        Some((mean, var.sqrt()))
+    }
+}
+
+/// standard normal quantile, e.g. 0.95 -> 1.645
+pub fn norm_ppf(percentile: f64) -> f64 {
+    std::f64::consts::SQRT_2 * statrs::function::erf::erf_inv(2.0 * percentile - 1.0)
+}
+
+/// snapshot of the TimeManager parameters, for introspection
+#[derive(Clone, Debug, Serialize)]
+pub struct TimeManagerParams {
+    pub time_block_transfer_ms: usize,
+    pub latency_ms: usize,
+    pub bandwidth_mbps: f64,
+    pub blocksize_megabits: f64,
+    /// harmonic mean of the recent bandwidth samples
+    pub forecast_mbps: Option<f64>,
+    /// mean and std (ms) of one block transfer
+    pub transfer_forecast_ms: Option<(f64, f64)>,
+    pub arrival_z: f64,
+    /// ms since the time of the last distribution
+    pub time_elapsed_ms: Option<u128>,
+    pub clock_offset_ms: Option<f64>,
+    pub clock_skew: Option<f64>,
+}
+
 pub struct TimeManager {
     time_block_transfer_ms: usize,
     /// latency in ms
@@ -50,6 +264,12 @@ pub struct TimeManager {
     bw: Arc<AtomicCell<f64>>,
     blocksize_megabits: f64,
     time: Option<std::time::Instant>,
+    clock: ClockSync,
+    forecast: BandwidthForecast,
+    /// mean and std (ms) of one block transfer, once there are enough bandwidth samples
+    transfer_forecast: Option<(f64, f64)>,
+    /// z-score of the percentile used for arrival times; 0 uses the mean
+    arrival_z: f64,
 }
 
 impl TimeManager {
@@ -59,13 +279,42 @@ impl TimeManager {
 
         TimeManager{ time_block_transfer_ms: time_block_transfer_ms,
                      latency: latency, bw: bw,
-                     blocksize_megabits: 0.0, time: None }
+                     blocksize_megabits: 0.0, time: None,
+                     clock: ClockSync::new(64),
+                     forecast: BandwidthForecast::new(32),
+                     transfer_forecast: None, arrival_z: 0.0 }
     }
 
     // Time it takes to send one block
     pub fn update_transfer_time(&mut self, bw: f64, blocksize_megabits: f64) {
        self.time_block_transfer_ms = ( (blocksize_megabits / bw as f64) * 1000.0).ceil() as usize;
-       debug!("time_block_transfer_ms: {:?}", self.time_block_transfer_ms);
+       self.transfer_forecast = match self.forecast.len() >= 2 {
+           true => self.forecast.transfer_time_ms(blocksize_megabits),
+           false => None,
+       };
+       debug!("time_block_transfer_ms: {:?} forecast: {:?}", self.time_block_transfer_ms, self.transfer_forecast);
+    }
+
+    /// Estimate arrival times at the given percentile of the bandwidth forecast,
+    /// e.g. 0.9 plans as if only 10% of the time blocks arrive later. None uses the mean.
+    pub fn set_arrival_percentile(&mut self, percentile: Option<f64>) {
+        self.arrival_z = match percentile {
+            Some(p) if p > 0.0 && p < 1.0 => norm_ppf(p),
+            _ => 0.0,
+        };
+    }
+
+    /// ms until `slot` blocks are on the network: n transfers of mean m and std s
+    /// take n*m + z*s*sqrt(n) at the configured percentile
+    #[inline]
+    fn transfer_ms(&self, slot: usize) -> usize {
+        match self.transfer_forecast {
+            Some((mean, std)) => {
+                let n = slot as f64;
+                (n * mean + self.arrival_z * std * n.sqrt()).max(0.0).ceil() as usize
+            },
+            None => slot * self.time_block_transfer_ms,
+        }
     }
 
     #[inline]
@@ -74,7 +323,29 @@ impl TimeManager {
             Some(time) => time.elapsed().as_millis() as usize,
             None => 0,
         };
-       (self.latency / 2) + progress  + slot * self.time_block_transfer_ms
+       (self.latency / 2) + progress  + self.transfer_ms(slot)
+    }
+
+    /// inverse of `slot_to_client_delta`: how many slots reach the client
+    /// before `delta` ms in the client's future
+    #[inline]
+    pub fn client_delta_to_slots(&self, delta: usize) -> usize {
+        let start = self.slot_to_client_delta(0);
+        if delta <= start {
+            return 0;
+        }
+
+        let per_block = match self.transfer_forecast {
+            Some((mean, _)) => mean.max(1.0),
+            None => std::cmp::max(1, self.time_block_transfer_ms) as f64,
+        };
+        let mut slots = ((delta - start) as f64 / per_block) as usize;
+        // the percentile makes arrival times grow faster than the mean
+        while slots > 0 && self.slot_to_client_delta(slots) > delta {
+            slots -= 1;
+        }
+
+        slots
     }
 
     pub fn update_blocksize_megabits(&mut self, bsize_megabits: f64) {
@@ -86,11 +357,23 @@ impl TimeManager {
         self.latency = latency;
     }
 
+    /// a measured bandwidth sample, see manager::SystemStat
     pub fn update_bandwidth(&mut self, bw: f64) {
+        self.bw.store(bw);
+        self.forecast.push(bw);
+        self.update_transfer_time(bw, self.blocksize_megabits);
+    }
+
+    /// the configured bandwidth, used until samples come in; it isn't one of them
+    pub fn set_bandwidth_prior(&mut self, bw: f64) {
         self.bw.store(bw);
         self.update_transfer_time(bw, self.blocksize_megabits);
     }
 
+    pub fn get_forecast(&self) -> &BandwidthForecast {
+        &self.forecast
+    }
+
     pub fn get_ref_bw(&self) -> Arc<AtomicCell<f64>> {
         self.bw.clone()
     }
@@ -98,5 +381,92 @@ impl TimeManager {
     pub fn update_time(&mut self, time: std::time::Instant) {
         self.time = Some(time);
     }
+
+    pub fn update_clock(&mut self, sample: ClockSample) {
+        self.clock.add_sample(sample);
+        debug!("clock offset: {:.1}ms skew: {:e} rtt: {:.1}ms", self.clock.offset_ms(), self.clock.skew(), sample.rtt_ms);
+    }
+
+    pub fn get_clock(&self) -> &ClockSync {
+        &self.clock
+    }
+
+    pub fn params(&self) -> TimeManagerParams {
+        let synced = self.clock.has_samples();
+        TimeManagerParams{ time_block_transfer_ms: self.time_block_transfer_ms,
+                           latency_ms: self.latency,
+                           bandwidth_mbps: self.bw.load(),
+                           blocksize_megabits: self.blocksize_megabits,
+                           forecast_mbps: self.forecast.harmonic_mean(),
+                           transfer_forecast_ms: self.transfer_forecast,
+                           arrival_z: self.arrival_z,
+                           time_elapsed_ms: self.time.map(|t| t.elapsed().as_millis()),
+                           clock_offset_ms: match synced { true => Some(self.clock.offset_ms()), false => None },
+                           clock_skew: match synced { true => Some(self.clock.skew()), false => None },
+        }
+    }
+
+    /// server Instant matching a timestamp (ms since epoch) in the client's clock;
+    /// None until the clock is synchronized
+    pub fn client_time_to_instant(&self, client_ms: f64) -> Option<Instant> {
+        if !self.clock.has_samples() {
+            return None;
+        }
+
+        let age_ms = now_ms() - self.clock.client_to_server_ms(client_ms);
+        if age_ms <= 0.0 {
+            return Some(Instant::now());
+        }
+
+        Instant::now().checked_sub(Duration::from_micros((age_ms * 1000.0) as u64))
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_sync_offset_and_skew() {
+        let mut clock = ClockSync::new(16);
+        // client clock is 500ms ahead and runs 1ms/s fast, 20ms one way delay
+        let client = |server_ms: f64| server_ms + 500.0 + 0.001 * server_ms;
+        for i in 0..10 {
+            let t0 = 1000.0 * i as f64;
+            let t1 = client(t0 + 20.0);
+            let t2 = client(t0 + 21.0);
+            let t3 = t0 + 41.0;
+            clock.add_sample(ClockSample::new(t0, t1, t2, t3));
+        }
+
+        assert!((clock.skew() - 0.001).abs() < 1e-4);
+        assert!((clock.server_to_client_ms(5000.0) - client(5000.0)).abs() < 1.0);
+        assert!((clock.client_to_server_ms(client(7000.0)) - 7000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_bandwidth_forecast_arrival() {
+        let mut tm = TimeManager::new(1, 0, 10.0);
+        tm.update_blocksize_megabits(1.0);
+        // 1 megabit blocks at 10 Mbps: 100ms per block
+        assert_eq!(tm.slot_to_client_delta(5), 500);
+
+        // the configured bandwidth isn't a sample, the forecast waits for real ones
+        tm.set_bandwidth_prior(10.0);
+        tm.set_bandwidth_prior(10.0);
+        assert_eq!(tm.get_forecast().len(), 0);
+        assert_eq!(tm.slot_to_client_delta(5), 500);
+
+        // fluctuating link: harmonic mean is 8 Mbps
+        tm.update_bandwidth(5.0);
+        tm.update_bandwidth(20.0);
+        assert!((tm.get_forecast().harmonic_mean().unwrap() - 8.0).abs() < 1e-9);
+        let mean_arrival = tm.slot_to_client_delta(10);
+
+        tm.set_arrival_percentile(Some(0.95));
+        let conservative = tm.slot_to_client_delta(10);
+        assert!(conservative > mean_arrival);
+        assert!(tm.slot_to_client_delta(tm.client_delta_to_slots(1000)) <= 1000);
+    }
+}
@@ -0,0 +1,75 @@
+/*
+ * KhameleonError: errors returned by the manager and the webserver handlers.
+ *
+ * Each variant maps to an HTTP status; the response body is JSON:
+ *     {"error": "bad_request", "message": "..."}
+ * and the same body is sent as a text frame on websocket errors.
+ */
+use actix_web::{HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KhameleonError {
+    /// malformed input from the client
+    BadRequest(String),
+    /// the client asked for an app that isn't registered
+    UnknownApp(String),
+    /// the request needs a step the client didn't do yet, e.g. /initapp or connecting the websocket
+    NotInitialized(String),
+    /// server side failure
+    Internal(String),
+}
+
+impl KhameleonError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KhameleonError::BadRequest(_) => "bad_request",
+            KhameleonError::UnknownApp(_) => "unknown_app",
+            KhameleonError::NotInitialized(_) => "not_initialized",
+            KhameleonError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            KhameleonError::BadRequest(msg) |
+            KhameleonError::UnknownApp(msg) |
+            KhameleonError::NotInitialized(msg) |
+            KhameleonError::Internal(msg) => msg,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            KhameleonError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            KhameleonError::UnknownApp(_) => StatusCode::NOT_FOUND,
+            KhameleonError::NotInitialized(_) => StatusCode::CONFLICT,
+            KhameleonError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// JSON body sent to the client
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({"error": self.kind(), "message": self.message()})
+    }
+}
+
+impl std::fmt::Display for KhameleonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for KhameleonError {}
+
+impl From<serde_json::Error> for KhameleonError {
+    fn from(err: serde_json::Error) -> Self {
+        KhameleonError::BadRequest(format!("invalid JSON: {}", err))
+    }
+}
+
+impl ResponseError for KhameleonError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(self.to_json())
+    }
+}
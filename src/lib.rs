@@ -0,0 +1,45 @@
+//! Khameleon: prefetches progressively encoded responses to the client, using the
+//! client's predicted distribution over future requests to schedule which blocks to push.
+//!
+//! The `khameleon` binary is a thin wrapper over `server::Server`; apps written
+//! outside this crate implement `apps::AppTrait` and are passed to the server builder.
+
+/// local imports
+pub mod ds;
+pub mod scheduler;
+pub mod manager;
+pub mod webserver;
+pub mod backend;
+pub mod apps;
+pub mod server;
+pub mod config;
+pub mod error;
+pub mod metrics;
+
+/// public libs
+extern crate lp_modeler;
+extern crate csv;
+extern crate crossbeam;
+extern crate crossbeam_utils;
+
+#[macro_use]
+extern crate indexmap;
+
+#[macro_use]
+extern crate actix_web;
+
+#[macro_use]
+extern crate log;
+
+extern crate rand;
+
+extern crate chrono;
+
+#[macro_use]
+extern crate ndarray;
+
+pub use apps::{AppTrait, AppFactory, AppRegistry};
+pub use server::Server;
+pub use config::ServerConfig;
+pub use error::KhameleonError;
+pub use metrics::Metrics;
@@ -1,43 +1,26 @@
-/// local imports
-mod ds;
-mod scheduler;
-mod manager;
-mod webserver;
-mod backend;
-mod apps;
-
-/// public libs
-extern crate lp_modeler;
-extern crate csv;
-extern crate crossbeam;
-extern crate crossbeam_utils;
-
-#[macro_use]
-extern crate indexmap;
-
-#[macro_use]
-extern crate actix_web;
-
+/// khameleon server binary: sets up logging, reads the config and runs khameleon::Server
 #[macro_use]
 extern crate log;
 extern crate fern;
-
-extern crate rand;
-
-use fern::colors::{Color, ColoredLevelConfig};
-
 extern crate chrono;
 
-#[macro_use]
-extern crate ndarray;
-
-use serde_json::json;
-
-use actix_web::{App, HttpServer, middleware};
-use actix_session::{CookieSession};
-use actix::prelude::*;
+use fern::colors::{Color, ColoredLevelConfig};
 
 fn main() -> std::io::Result<()> {
+    // Read command line arguments and environment: config file name and overrides
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", khameleon::config::usage());
+        return Ok(());
+    }
+    let config = match khameleon::ServerConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
     // setup logging environment
     // 1) create `log` directory if it doesnt exist
     std::fs::create_dir_all("./log/")?;
@@ -56,8 +39,7 @@ fn main() -> std::io::Result<()> {
     // since almost all of them are the some as the color for the whole line, we
     // just clone `colors_line` and overwrite our changes
     let colors_level = colors_line.clone();
-    let log_level = log::LevelFilter::Debug;
-    //let log_level = log::LevelFilter::Info;
+    let log_level = config.log_level_filter().unwrap();
 
     fern::Dispatch::new()
         // Perform allocation-free log formatting
@@ -94,38 +76,9 @@ fn main() -> std::io::Result<()> {
         // Apply globally
         .apply().unwrap();
 
-    // Read command line arguments: config file name
-    let args: Vec<String> = std::env::args().collect();
     debug!("command line arguments: {:?}", args);
-    let config: serde_json::Value = match args.len() > 1 {
-        true => {
-            // pass this as argument
-            let fname = &args[1];
-            let file = std::fs::File::open(fname).expect("file should open read only");
-            let config: serde_json::Value = serde_json::from_reader(file).expect("JSON was not well-formatted");
-            debug!("config: {:?} {:?}", fname, config);
-            config
-        }, false => json!({}), // empty config file
-    };
-
-    info!("start server");
-    let sys = actix_rt::System::new("khameleon-actix");
-
-    // 2) Start Manager Thread/Actor
-    let imanager = manager::Manager::new(config);
-    let manager_addr = imanager.start();
-
+    debug!("config: {:?}", config);
 
-    // 3) Initialize &start server and websocket
-    HttpServer::new(move || {
-        App::new()
-            .data(manager_addr.clone())
-            .configure(webserver::appconfig::config_app)
-            // enable logger
-            .wrap(middleware::Logger::default())
-            .wrap(CookieSession::signed(&[0;32]).secure(false))
-    })
-    .bind("0.0.0.0:8080")?
-    .start();
-    sys.run()
+    khameleon::Server::new(config)
+        .run()
 }
@@ -0,0 +1,138 @@
+/*
+ * BlockCache: the app's encoded blocks, ready to send, keyed by (query, block index).
+ *
+ * Apps read a query's blocks from their backend and encode them on every call; the
+ * sender asks this cache first. It is owned by the manager, so it outlives sessions
+ * and reconnects, and is cleared when a new app is created. Least recently used
+ * blocks are evicted once the total size goes over `capacity` bytes. Blocks are
+ * shared with the sender, hits don't copy them.
+ */
+use std::collections::{BTreeMap, HashMap};
+
+use crate::ds::BlockBytes;
+
+struct Entry {
+    bytes: BlockBytes,
+    /// last use, key of the entry in `recency`
+    tick: u64,
+}
+
+pub struct BlockCache {
+    entries: HashMap<(usize, usize), Entry>,
+    /// entries by last use, oldest first
+    recency: BTreeMap<u64, (usize, usize)>,
+    tick: u64,
+    /// bytes held
+    size: usize,
+    /// max bytes held, 0 disables the cache
+    capacity: usize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache{ entries: HashMap::new(), recency: BTreeMap::new(), tick: 0, size: 0, capacity: capacity }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// encoded block `index` of query `qid`, marked as most recently used
+    pub fn get(&mut self, qid: usize, index: usize) -> Option<BlockBytes> {
+        self.tick += 1;
+        let entry = self.entries.get_mut(&(qid, index))?;
+        self.recency.remove(&entry.tick);
+        entry.tick = self.tick;
+        self.recency.insert(self.tick, (qid, index));
+        Some(entry.bytes.clone())
+    }
+
+    /// Keep block `index` of query `qid`, evicting the least recently used blocks to
+    /// make room. Returns how many blocks were evicted; blocks larger than the whole
+    /// cache aren't kept.
+    pub fn insert(&mut self, qid: usize, index: usize, bytes: BlockBytes) -> usize {
+        if bytes.len() > self.capacity {
+            return 0;
+        }
+        self.remove(qid, index);
+
+        let mut evicted = 0;
+        while self.size + bytes.len() > self.capacity {
+            let (&tick, &key) = match self.recency.iter().next() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.recency.remove(&tick);
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.bytes.len();
+                evicted += 1;
+            }
+        }
+
+        self.tick += 1;
+        self.size += bytes.len();
+        self.recency.insert(self.tick, (qid, index));
+        self.entries.insert((qid, index), Entry{ bytes: bytes, tick: self.tick });
+        evicted
+    }
+
+    fn remove(&mut self, qid: usize, index: usize) {
+        if let Some(entry) = self.entries.remove(&(qid, index)) {
+            self.recency.remove(&entry.tick);
+            self.size -= entry.bytes.len();
+        }
+    }
+
+    /// the blocks belong to another app
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(cache: &mut BlockCache, qid: usize, index: usize) -> Option<Vec<u8>> {
+        cache.get(qid, index).map(|b| b.to_vec())
+    }
+
+    #[test]
+    fn test_block_cache_lru() {
+        let mut cache = BlockCache::new(10);
+        assert_eq!(cache.insert(0, 0, vec![0u8; 4].into()), 0);
+        assert_eq!(cache.insert(0, 1, vec![1u8; 4].into()), 0);
+        assert_eq!(get(&mut cache, 0, 0), Some(vec![0; 4]));
+
+        // (0, 1) is the least recently used
+        assert_eq!(cache.insert(1, 0, vec![2u8; 4].into()), 1);
+        assert_eq!(get(&mut cache, 0, 1), None);
+        assert_eq!(get(&mut cache, 0, 0), Some(vec![0; 4]));
+        assert_eq!((cache.len(), cache.size()), (2, 8));
+
+        // replacing a block doesn't count it twice, too large blocks aren't kept
+        cache.insert(1, 0, vec![3u8; 2].into());
+        assert_eq!(cache.size(), 6);
+        assert_eq!(cache.insert(2, 0, vec![0u8; 11].into()), 0);
+        assert_eq!(get(&mut cache, 2, 0), None);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+        assert_eq!(BlockCache::new(0).insert(0, 0, vec![0u8].into()), 0);
+    }
+}
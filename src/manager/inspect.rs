@@ -0,0 +1,141 @@
+/*
+ * Read-only view of what the server believes about the running session:
+ * the simulated client cache, the schedule being streamed, the last decoded
+ * distribution and the TimeManager parameters.
+ */
+use crate::ds;
+use crate::scheduler;
+use crate::error::KhameleonError;
+
+use serde_derive::{Serialize};
+use std::time::{Instant};
+use actix::prelude::*;
+
+/// written by the scheduling thread every round, read by `Inspect`
+#[derive(Clone, Debug)]
+pub struct SessionTrace {
+    pub round: usize,
+    /// last schedule sent to the sender thread, as query indices
+    pub schedule: Vec<usize>,
+    pub schedule_time: Option<Instant>,
+    /// last distribution decoded by the app
+    pub prob: Option<scheduler::Prob>,
+}
+
+impl SessionTrace {
+    pub fn new() -> Self {
+        SessionTrace{ round: 0, schedule: Vec::new(), schedule_time: None, prob: None }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryBlocks {
+    pub key: String,
+    pub blocks: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheView {
+    pub head: usize,
+    pub capacity: usize,
+    pub occupancy: usize,
+    /// queries with at least one block in the cache
+    pub queries: Vec<QueryBlocks>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QueryProb {
+    pub key: String,
+    pub prob: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeltaView {
+    pub delta_ms: usize,
+    pub top: Vec<QueryProb>,
+    /// probability of each query that isn't listed explicitly in the model
+    pub rest: f32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbView {
+    pub age_ms: u128,
+    pub deltas: Vec<DeltaView>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScheduleView {
+    pub age_ms: Option<u128>,
+    /// query keys in the order they are streamed, one entry per block
+    pub keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Inspection {
+    pub appname: String,
+    pub round: usize,
+    pub cache: CacheView,
+    pub schedule: ScheduleView,
+    pub prob: Option<ProbView>,
+    pub time_manager: ds::TimeManagerParams,
+}
+
+/// ask the manager for a snapshot of the current session;
+/// `top_k` bounds the queries listed per delta of the distribution
+#[derive(Message)]
+#[rtype(result = "Result<Inspection, KhameleonError>")]
+pub struct Inspect {
+    pub top_k: usize,
+}
+
+impl Handler<Inspect> for super::Manager {
+    type Result = Result<Inspection, KhameleonError>;
+
+    fn handle(&mut self, msg: Inspect, _: &mut Self::Context) -> Self::Result {
+        let state = match &self.state {
+            Some(state) => state,
+            None => return Err(KhameleonError::NotInitialized("no session, call /initapp first".to_owned())),
+        };
+
+        let (queries_blcount, _) = state.app.lock()
+            .map_err(|e| KhameleonError::Internal(format!("app lock {:?}", e)))?
+            .get_scheduler_config();
+        let key = |qid: usize| match queries_blcount.get_index(qid) {
+            Some((k, _)) => k.clone(),
+            None => format!("#{}", qid),
+        };
+
+        let cache = {
+            let cache_sim = state.cache_sim.read()
+                .map_err(|e| KhameleonError::Internal(format!("cache lock {:?}", e)))?;
+            let queries = cache_sim.cache_per_query.iter().enumerate()
+                .filter(|(_, &blocks)| blocks > 0)
+                .map(|(qid, &blocks)| QueryBlocks{ key: key(qid), blocks: blocks })
+                .collect();
+            CacheView{ head: cache_sim.head, capacity: cache_sim.cachesize,
+                       occupancy: cache_sim.occupancy(), queries: queries }
+        };
+
+        let trace = state.trace.read()
+            .map_err(|e| KhameleonError::Internal(format!("trace lock {:?}", e)))?
+            .clone();
+
+        let schedule = ScheduleView{ age_ms: trace.schedule_time.map(|t| t.elapsed().as_millis()),
+                                     keys: trace.schedule.iter().map(|&qid| key(qid)).collect() };
+
+        let prob = trace.prob.map(|prob| {
+            let deltas = prob.top_k(msg.top_k).into_iter().map(|(delta, top, rest)| {
+                let top = top.into_iter().map(|(qid, p)| QueryProb{ key: key(qid), prob: p }).collect();
+                DeltaView{ delta_ms: delta, top: top, rest: rest }
+            }).collect();
+            ProbView{ age_ms: prob.time.elapsed().as_millis(), deltas: deltas }
+        });
+
+        let time_manager = state.tm.read()
+            .map_err(|e| KhameleonError::Internal(format!("time manager lock {:?}", e)))?
+            .params();
+
+        Ok(Inspection{ appname: state.appstate.appname.clone(), round: trace.round,
+                       cache: cache, schedule: schedule, prob: prob, time_manager: time_manager })
+    }
+}
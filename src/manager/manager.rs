@@ -4,14 +4,18 @@
  */
 /// local imports
 use crate::apps;
+use crate::config;
 use crate::ds;
+use crate::metrics;
 use crate::scheduler;
+use crate::error::KhameleonError;
 
 /// public lib
 use serde_derive::{Deserialize, Serialize};
 use crossbeam_utils::atomic::AtomicCell;
 use std::io::prelude::*;
 
+use std::collections::HashSet;
 use std::sync::mpsc::{self, TrySendError};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
@@ -57,6 +61,8 @@ pub struct SharedState {
     pub request_count: usize,
     pub timestamp: std::time::Instant,
     pub cache_sim: Arc<RwLock<super::CacheSimulator>>,
+    /// last schedule and distribution, for introspection
+    pub trace: Arc<RwLock<super::SessionTrace>>,
 }
 
 impl SharedState {
@@ -99,6 +105,7 @@ impl SharedState {
                     request_count: 0,
                     timestamp: timestamp,
                     cache_sim: cache_sim,
+                    trace: Arc::new(RwLock::new(super::SessionTrace::new())),
         }
     }
 }
@@ -106,12 +113,24 @@ impl SharedState {
 pub struct Manager {
     pub state: Option<SharedState>,
     pub ws_addr: Option<Recipient<ds::StreamBlock>>,
+    /// websocket the sender thread streams to, swapped when the client reconnects
+    pub ws_slot: Arc<RwLock<Option<Recipient<ds::StreamBlock>>>>,
+    /// id of the current websocket connection
+    connection: usize,
+    /// blocks on the wire to the client, kept across reconnects
+    pub session: Arc<Mutex<super::Session>>,
+    /// encoded blocks of the app, kept across sessions
+    pub block_cache: Arc<Mutex<super::BlockCache>>,
     pub manager_addr: Option<Addr<Manager>>,
     dist_counter: usize,
     instance: usize,
 
-    pub config: serde_json::Value,
+    pub config: config::ServerConfig,
     pub congestion: Option<Arc<AtomicCell<u128>>>,
+    /// apps the client can initialize, by name
+    pub registry: apps::AppRegistry,
+    /// runtime stats, updated by the scheduling and sender threads
+    pub metrics: Arc<metrics::Metrics>,
 }
 
 impl Actor for Manager {
@@ -140,19 +159,37 @@ impl Actor for Manager {
     }
 }
 
+/// sent by a reconnecting client: its session token and the last block id it acked
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Resume {
+    pub session: String,
+    pub last_ack: u32,
+}
+
+#[derive(MessageResponse)]
+pub struct ConnectData {
+    pub connection: usize,
+    pub session: Arc<Mutex<super::Session>>,
+    pub token: String,
+    pub resumed: bool,
+    /// unacked blocks still useful to the client, sent before anything else
+    pub replay: Vec<ds::StreamBlock>,
+}
+
 /// Actor Model using acitx
 /// This message struct to pass websocket address from server to manager
 #[derive(Message)]
-#[rtype(bool)]
+#[rtype(result = "ConnectData")]
 pub struct Connect {
     pub ws_addr: Recipient<ds::StreamBlock>,
     pub congestion: Arc<AtomicCell<u128>>,
+    pub resume: Option<Resume>,
 }
 
 /// implementation of actor model for `Connect` Message
 /// start communication threads to scheduler and stream data to client
 impl Handler<Connect> for Manager {
-    type Result = bool;
+    type Result = ConnectData;
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
         match &self.ws_addr {
@@ -165,9 +202,78 @@ impl Handler<Connect> for Manager {
             None => (),
         }
 
-        self.ws_addr = Some(msg.ws_addr);
+        self.ws_addr = Some(msg.ws_addr.clone());
         self.congestion = Some(msg.congestion);
+        self.connection += 1;
+
+        let mut session = self.session.lock().unwrap();
+        let mut replay = Vec::new();
+        let resumed = match msg.resume {
+            Some(ref resume) if resume.session == session.token => {
+                // replay blocks of queries the scheduler still plans for, or explicitly requested
+                let schedule: HashSet<usize> = match &self.state {
+                    Some(state) => state.trace.read().unwrap().schedule.iter().cloned().collect(),
+                    None => HashSet::new(),
+                };
+                let (blocks, dropped) = session.resume(resume.last_ack, |b| match b.qid {
+                    Some(qid) => schedule.is_empty() || schedule.contains(&qid),
+                    None => true,
+                });
+
+                // the cache model only keeps what the client will have
+                if let Some(state) = &self.state {
+                    let mut cache_sim = state.cache_sim.write().unwrap();
+                    for block in dropped.iter().rev() {
+                        if let Some(qid) = block.qid {
+                            cache_sim.remove(qid);
+                        }
+                    }
+                }
+
+                info!("resumed session after block {}: replay {} dropped {}", resume.last_ack, blocks.len(), dropped.len());
+                replay = blocks;
+                true
+            },
+            _ => {
+                if let Some(resume) = &msg.resume {
+                    warn!("unknown session {:?}, starting a new one", resume.session);
+                }
+                session.restart(super::session::new_token());
+
+                // a new client starts with an empty cache
+                if let Some(state) = &self.state {
+                    state.cache_sim.write().unwrap().reset();
+                }
+                false
+            },
+        };
+
+        *self.ws_slot.write().unwrap() = Some(msg.ws_addr);
+
+        ConnectData{ connection: self.connection, session: self.session.clone(),
+                     token: session.token.clone(), resumed: resumed, replay: replay }
+    }
+}
+
+/// the websocket of connection `connection` closed
+#[derive(Message)]
+#[rtype(bool)]
+pub struct Disconnect {
+    pub connection: usize,
+}
+
+impl Handler<Disconnect> for Manager {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
+        if msg.connection != self.connection {
+            // an older socket, already replaced
+            return false;
+        }
 
+        // the sender waits until the client resumes
+        self.ws_addr = None;
+        *self.ws_slot.write().unwrap() = None;
         true
     }
 }
@@ -203,17 +309,41 @@ impl Handler<SystemStat> for Manager {
     }
 }
 
+/// timestamps of a ping exchange or block ack, used to synchronize with the client's clock
+#[derive(Message, Clone, Debug)]
+#[rtype(bool)]
+pub struct ClockSample {
+    pub sample: ds::ClockSample,
+}
+
+impl Handler<ClockSample> for Manager {
+    type Result = bool;
+
+    fn handle(&mut self, msg: ClockSample, _: &mut Self::Context) -> Self::Result {
+        match &self.state {
+            Some(state) => {
+                match state.tm.write() {
+                    Ok(mut tm) => tm.update_clock(msg.sample),
+                    Err(e)=> error!("couldn't update clock, {:?}", e),
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "Result<usize, KhameleonError>")]
 pub struct Distributions {
-    pub data: String,
+    pub userstate: ds::PredictorState,
 }
 
 impl Handler<Distributions> for Manager {
-    type Result = usize;
+    type Result = Result<usize, KhameleonError>;
 
     fn handle(&mut self, msg: Distributions, _: &mut Self::Context) -> Self::Result {
-        let userstate: ds::PredictorState = serde_json::from_str(&msg.data).unwrap();
+        let userstate = msg.userstate;
 
         if let Some(state) = &self.state {
             self.dist_counter += 1;
@@ -240,14 +370,40 @@ impl Handler<Distributions> for Manager {
                     error!("couldn't get hold of channel dist_tx");
                 }
             };
+        } else {
+            return Err(KhameleonError::NotInitialized("distribution received before /initapp".to_owned()));
         }
 
-        self.dist_counter
+        Ok(self.dist_counter)
+    }
+}
+
+/// new client layout, {key: {x, y, w, h}}
+#[derive(Message)]
+#[rtype(result = "Result<usize, KhameleonError>")]
+pub struct UpdateLayout {
+    pub layout: serde_json::Value,
+}
+
+/// the app lays its queries out again and flags the change to the scheduler,
+/// returns how many queries are laid out
+impl Handler<UpdateLayout> for Manager {
+    type Result = Result<usize, KhameleonError>;
+
+    fn handle(&mut self, msg: UpdateLayout, _: &mut Self::Context) -> Self::Result {
+        match &self.state {
+            Some(state) => {
+                let placed = state.app.lock().unwrap().update_layout(msg.layout)?;
+                info!("layout updated: {} queries laid out", placed);
+                Ok(placed)
+            },
+            None => Err(KhameleonError::NotInitialized("layout received before /initapp".to_owned())),
+        }
     }
 }
 
 #[derive(Message, Debug, Serialize, Deserialize)]
-#[rtype(bool)]
+#[rtype(result = "Result<bool, KhameleonError>")]
 pub struct Request {
     /// currently, json encoded strings are only supported as queries
     pub query: serde_json::Value,
@@ -256,7 +412,7 @@ pub struct Request {
 }
 
 impl Handler<Request> for Manager {
-    type Result = bool;
+    type Result = Result<bool, KhameleonError>;
 
     fn handle(&mut self, msg: Request, _: &mut Self::Context) -> Self::Result {
         debug!("====> Manager Actor got new direct request {:?} {:?}", msg.query, msg.rtype);
@@ -265,7 +421,7 @@ impl Handler<Request> for Manager {
         // available bandwidth
         let ws_addr = match self.ws_addr.clone() {
             Some(addr) => addr,
-            None => panic!("websocket address wasn't initialized"),
+            None => return Err(KhameleonError::NotInitialized("websocket isn't connected".to_owned())),
         };
         
         
@@ -273,11 +429,11 @@ impl Handler<Request> for Manager {
             Some(state) => {
                 let mut queries = vec![];
                 if msg.rtype == true {
-                    let mut a: Vec<String> = serde_json::from_value(msg.query).unwrap();
+                    let mut a: Vec<String> = serde_json::from_value(msg.query)?;
                     queries.append(& mut a);
 
                 } else {
-                    let q: String = serde_json::from_value(msg.query).unwrap();
+                    let q: String = serde_json::from_value(msg.query)?;
                     queries.push(q);
                 }
 
@@ -299,12 +455,9 @@ impl Handler<Request> for Manager {
                     }
                 }
 
-                ret
-                
-
-
+                Ok(ret)
             },
-            None => panic!("state is not initialized"),
+            None => Err(KhameleonError::NotInitialized("request received before /initapp".to_owned())),
         }
     }
 }
@@ -316,16 +469,16 @@ pub struct InitAppData {
 }
 
 #[derive(Message)]
-#[rtype(InitAppData)]
+#[rtype(result = "Result<InitAppData, KhameleonError>")]
 pub struct InitApp {
     pub state: String,
 }
 
 impl Handler<InitApp> for Manager {
-    type Result = InitAppData;
+    type Result = Result<InitAppData, KhameleonError>;
 
     fn handle(&mut self, msg: InitApp, _: &mut Self::Context) -> Self::Result {
-        match serde_json::from_str(&msg.state) {
+        match serde_json::from_str::<ds::AppState>(&msg.state) {
             Ok(appstate) => {
                 info!("====> Manager Actor to initialize app {:?}", appstate);
                 // these should be initialized by the client
@@ -339,15 +492,29 @@ impl Handler<InitApp> for Manager {
                         // 2) join thread handles
                         for worker in &mut state.threads {
                             if let Some(thread) = worker.take() {
-                                thread.join().unwrap();
-                                debug!("joined thread");
+                                match thread.join() {
+                                    Ok(_) => debug!("joined thread"),
+                                    Err(e) => error!("thread panicked {:?}", e),
+                                }
                             }
                         }
 
-                        let state_change_flag = Arc::new(RwLock::new(false));
-                        let app = state.app.clone();
-                        let shstate= SharedState::new(appstate, app, state_change_flag);
-                        self.state = Some(shstate);
+                        if state.appstate.appname == appstate.appname {
+                            // the app is kept and still sets the flag it was created with
+                            let state_change_flag = state.state_change_flag.clone();
+                            *state_change_flag.write().unwrap() = false;
+                            let app = state.app.clone();
+                            let shstate= SharedState::new(appstate, app, state_change_flag);
+                            self.state = Some(shstate);
+                        } else {
+                            info!("switching from app {:?} to {:?}", state.appstate.appname, appstate.appname);
+                            // the old app and its backend go before the new one opens its own
+                            self.state = None;
+                            let shstate = self.create_state(appstate)?;
+                            self.clear_block_cache();
+                            self.state = Some(shstate);
+                            self.instance += 1;
+                        }
                     }, 
                     None => {
                         debug!("initializing new state");
@@ -355,85 +522,67 @@ impl Handler<InitApp> for Manager {
                         //       let the user connect to this specific app
                         //       query initialization state
                         //       update cache size available at client side
-                        let state_change_flag = Arc::new(RwLock::new(false));
-
-                        let app = Arc::new(Mutex::new(apps::new(&appstate, self.config.clone(), state_change_flag.clone())));
-                        let shstate = SharedState::new(appstate, app, state_change_flag);
-
-                        match shstate.tm.write() {
-                            Ok(mut tm) => {
-                                let latency: usize = match self.config["latency"].as_u64() {
-                                    Some(l) => l as usize,
-                                    None => 100
-                                };
-                                let bw  = match self.config["bandwidth"].as_f64() {
-                                    Some(l) => l,
-                                    None => 10.0
-                                };
-                                tm.update_bandwidth(bw);
-                                tm.update_latency(latency as usize);
-                            }
-                            Err(e)=> error!("couldn't update bandwidth, {:?}", e),
-                        }
-
-
+                        let shstate = self.create_state(appstate)?;
                         self.state = Some(shstate);
                         self.instance += 1;
                     }
                 }
             }
-            Err(err) => panic!("invalid app state {:?}", err),
+            Err(err) => return Err(KhameleonError::BadRequest(format!("invalid app state: {}", err))),
         };
 
         let state = match &mut self.state {
             Some(state) => state,
-            None => panic!("no state initialized"),
+            None => return Err(KhameleonError::Internal("no state initialized".to_owned())),
         };
         
         let appinit = state.app.lock().unwrap().get_initstate();
 
+        // the client starts over: blocks on the wire are dropped, the app may have changed
+        match self.session.lock() {
+            Ok(mut session) => {
+                session.clear();
+                session.set_capacity(state.appstate.cachesize);
+            },
+            Err(e) => error!("couldn't reset session, {:?}", e),
+        }
+
 
         debug!("running {} threads", state.threads.len());
-        InitAppData{instance: self.instance, data: appinit}
+        Ok(InitAppData{instance: self.instance, data: appinit})
     }
 }
 
 #[derive(Message)]
-#[rtype(bool)]
+#[rtype(result = "Result<bool, KhameleonError>")]
 pub struct StartThreads;
 
 impl Handler<StartThreads> for Manager {
-    type Result = bool;
+    type Result = Result<bool, KhameleonError>;
 
     fn handle(&mut self, _msg: StartThreads, _: &mut Self::Context) -> Self::Result {
-        let run_scheduler: bool = match self.config["runScheduler"].as_bool() {
-            Some(flag) => flag,
-            None => {
-                error!("runScheduler wasn't initialized; use default {:?}", self.config);
-                true
-            }, // by default start scheduler and sender threads
-        };
+        let run_scheduler = self.config.run_scheduler;
 
         debug!("run_scheduler: {:?}", run_scheduler);
 
         if run_scheduler {
-            let ws_addr = match self.ws_addr.clone() {
-                Some(addr) => addr,
-                None => panic!("websocket address wasn't initialized"),
-            };
+            if self.ws_addr.is_none() {
+                return Err(KhameleonError::NotInitialized("websocket isn't connected".to_owned()));
+            }
 
             let state = match &mut self.state {
                 Some(state) => state,
-                None => panic!("no state initialized"),
+                None => return Err(KhameleonError::NotInitialized("threads started before /initapp".to_owned())),
             };
             let congestion_flag = match self.congestion.clone() {
                 Some(v) => v,
-                None => panic!("congestion flag isn't set"),
+                None => return Err(KhameleonError::NotInitialized("congestion flag isn't set".to_owned())),
             };
-            Manager::start_threads(state, ws_addr, congestion_flag, &self.config);
+            Manager::start_threads(state, self.ws_slot.clone(), self.session.clone(), self.block_cache.clone(),
+                                   congestion_flag, &self.config, self.metrics.clone());
         }
 
-        run_scheduler
+        Ok(run_scheduler)
     }
 }
 
@@ -441,20 +590,65 @@ impl Manager {
     // start scheduler thread
     // start streaming thread
 
-    pub fn new(config: serde_json::Value) -> Self {
+    pub fn new(config: config::ServerConfig, registry: apps::AppRegistry, metrics: Arc<metrics::Metrics>) -> Self {
 
         Manager{ws_addr: None,
+                ws_slot: Arc::new(RwLock::new(None)),
+                connection: 0,
+                session: Arc::new(Mutex::new(super::Session::new(super::session::new_token(), 1024))),
+                block_cache: Arc::new(Mutex::new(super::BlockCache::new(config.block_cache_mb * 1024 * 1024))),
                 manager_addr: None,
                 state: None,
                 dist_counter: 0,
                 instance: 0,
                 congestion: None,
                 config: config,
+                registry: registry,
+                metrics: metrics,
                 }
     }
 
-    pub fn start_threads(state: &mut SharedState, ws_addr: Recipient<ds::StreamBlock>,
-                         congestion_flag: Arc<AtomicCell<u128>>, config: &serde_json::Value) {
+    /// state of a new instance of the app `appstate` names, see `apps::AppRegistry::create`
+    fn create_state(&self, appstate: ds::AppState) -> Result<SharedState, KhameleonError> {
+        let state_change_flag = Arc::new(RwLock::new(false));
+
+        let app = match self.registry.create(&appstate, self.config.app.clone(), state_change_flag.clone()) {
+            Ok(app) => app,
+            Err(err) => {
+                error!("{}", err);
+                return Err(err);
+            }
+        };
+        let app = Arc::new(Mutex::new(app));
+        let shstate = SharedState::new(appstate, app, state_change_flag);
+
+        match shstate.tm.write() {
+            Ok(mut tm) => {
+                tm.set_bandwidth_prior(self.config.bandwidth);
+                tm.update_latency(self.config.latency);
+            }
+            Err(e)=> error!("couldn't update bandwidth, {:?}", e),
+        }
+
+        Ok(shstate)
+    }
+
+    /// the cache is keyed by (query, block index): its blocks mean nothing to another app
+    fn clear_block_cache(&self) {
+        match self.block_cache.lock() {
+            Ok(mut cache) => {
+                cache.clear();
+                self.metrics.block_cache_bytes.set(0.0);
+            },
+            Err(e) => error!("couldn't clear the block cache, {:?}", e),
+        }
+    }
+
+    pub fn start_threads(state: &mut SharedState, ws_slot: Arc<RwLock<Option<Recipient<ds::StreamBlock>>>>,
+                         session: Arc<Mutex<super::Session>>,
+                         block_cache: Arc<Mutex<super::BlockCache>>,
+                         congestion_flag: Arc<AtomicCell<u128>>, config: &config::ServerConfig,
+                         metrics: Arc<metrics::Metrics>) {
         info!("--> Start Scheduling/streaming Threads");
         let kill_thread_th1 = state.kill_thread_flag.clone();
         let kill_thread_th2 = state.kill_thread_flag.clone();
@@ -470,50 +664,67 @@ impl Manager {
 
         let (queries_blcount, utility)  = app1.lock().unwrap().get_scheduler_config();
         let total_queries = queries_blcount.len();
+        let app_decay = app1.lock().unwrap().get_decay();
 
         let state_change_flag = state.state_change_flag.clone();
+
+        // apps that generate blocks on demand
+        let producer = app1.lock().unwrap().get_producer();
+        let pool = producer.map(|producer| {
+            let (pool, handles) = super::ProducerPool::start(producer, total_queries, config.producer_workers,
+                                                             config.producer_latency_ms,
+                                                             state.kill_thread_flag.clone(), metrics.clone());
+            state.threads.extend(handles.into_iter().map(Some));
+            Arc::new(pool)
+        });
+        let pool_th1 = pool.clone();
+        let pool_th2 = pool;
         
         let cachesize = state.appstate.cachesize;
         let cache_sim_th1 = state.cache_sim.clone();
         let cache_sim_th2 = cache_sim_th1.clone();
+        let trace = state.trace.clone();
 
         let tm = state.tm.clone();
         let tm_th1 = tm.clone();
         let tm_th2 = tm.clone();
-        
-        let latency: usize = match config["latency"].as_u64() {
-            Some(l) => l as usize,
-            None => 100
-        };
 
-        let rate: usize = match config["rate"].as_u64() {
-            Some(r) => r as usize,
-            None => 0,
-        };
-
-        let mut bw = match config["bandwidth"].as_f64() {
-            Some(bw) => bw,
-            None => 10.0,
-        };
+        let metrics_th1 = metrics.clone();
+        let metrics_th2 = metrics;
+        
+        let latency = config.latency;
+        let rate = config.rate;
+        let mut bw = config.bandwidth;
 
         if rate > 0 {
             bw = rate as f64;
         }
 
-        let min_wait: usize = match config["min_wait"].as_u64() {
-            Some(w) => w as usize,
-            None => 0,
-        };
+        let min_wait = config.min_wait;
+
+        let mut sched_options = config.scheduler.clone();
+        if let Some(decay) = app_decay {
+            sched_options.decay = decay;
+        }
+
+        // plan with arrival times at this percentile of the bandwidth forecast
+        let bw_percentile = config.bw_percentile;
 
         match state.tm.write() {
             Ok(mut tm) => {
-                tm.update_bandwidth(bw);
+                tm.set_bandwidth_prior(bw);
                 tm.update_latency(latency as usize);
+                tm.set_arrival_percentile(bw_percentile);
             }
             Err(e)=> error!("couldn't update bandwidth, {:?}", e),
         }
 
-        info!("bw: {} rate: {} latency: {}",  bw, rate, latency);
+        info!("bw: {} rate: {} latency: {} bw_percentile: {:?}",  bw, rate, latency, bw_percentile);
+        info!("scheduler options: {:?}", sched_options);
+
+        // (schedules the sender received, entries of the last one it took)
+        let plan_progress = Arc::new(AtomicCell::new((0usize, 0usize)));
+        let plan_progress_th2 = plan_progress.clone();
 
         // 2) Start a Scheduler Threed, that checks queue
         //    for latest recevied model from client, or use
@@ -525,37 +736,38 @@ impl Manager {
         let worker1 = thread::spawn(move || {
             let blocks_per_query :Vec<usize> = queries_blcount.iter().map(|(_k, &v)| v ).collect();
             let continues = false;
-            let schedtype = scheduler::SchedulerType::Greedy;
+            let schedtype = sched_options.stype;
             let time_to_converge = 300;
             let batch = 100;
             let sched = scheduler::new(&schedtype,
                                        batch,
                                        cachesize,
                                        utility,
-                                       blocks_per_query, Some(tm.clone()));
+                                       blocks_per_query, Some(tm.clone()),
+                                       &sched_options);
         
             super::scheduling::start( // objects
-                                     app1, cache_sim_th1, sched, tm_th1,
+                                     app1, cache_sim_th1, sched, tm_th1, metrics_th1,
                                       // config
                                       continues, time_to_converge, total_queries,
                                       // flags
-                                      kill_thread_th1, state_change_flag,
+                                      kill_thread_th1, state_change_flag, trace, pool_th1,
                                       // channels
-                                      dist_rx, schedule_tx, schedule_rx_th1,
+                                      dist_rx, schedule_tx, schedule_rx_th1, plan_progress,
                                   );
         });
         state.threads.push(Some(worker1));
         // receive scheduler's decisions and stream them to end user
         let worker2 = thread::spawn(move || {
             super::sender::start( // object
-                                  app2, cache_sim_th2, ws_addr, tm_th2,
+                                  app2, cache_sim_th2, ws_slot, session, tm_th2, metrics_th2, pool_th2, block_cache,
                                   congestion_flag,
                                   // flags
                                   kill_thread_th2,
 
                                   min_wait,
                                   // channels
-                                  schedule_rx_th2,
+                                  schedule_rx_th2, plan_progress_th2,
                                 );
         });
         state.threads.push(Some(worker2));
@@ -1,16 +1,28 @@
 pub mod sender;
 pub mod scheduling;
 pub mod manager;
+pub mod inspect;
+pub mod session;
+pub mod producer;
+pub mod blockcache;
 
 // export
-pub use manager::{Manager, SystemStat, Request, Connect, Distributions, InitApp};
+pub use manager::{Manager, SystemStat, Request, Connect, ConnectData, Disconnect, Resume, Distributions, UpdateLayout, InitApp, ClockSample};
+pub use inspect::{Inspect, Inspection, SessionTrace};
+pub use session::{Session};
+pub use producer::{ProducerPool};
+pub use blockcache::{BlockCache};
 
 extern crate ndarray;
 use ndarray::{Array1};
+use std::collections::HashMap;
 
 #[derive(Clone, Debug)]
 pub struct CacheSimulator {
     pub cache_per_query: Array1<usize>,
+    /// erasure coded apps: blocks sent per query and level since the client last had
+    /// none of the query, lost ones included
+    pub sent_per_level: HashMap<usize, Vec<usize>>,
     pub cache: Vec<i32>,
     pub cachesize: usize,
     pub head: usize,
@@ -26,10 +38,16 @@ impl CacheSimulator {
 
         CacheSimulator{ cachesize: cachesize, cache: cache,
                         cache_per_query: cache_per_query,
+                        sent_per_level: HashMap::new(),
                         head: head,
                         }
     }
 
+    /// blocks currently in the cache; the ring buffer is cleared whenever it wraps
+    pub fn occupancy(&self) -> usize {
+        self.head
+    }
+
     pub fn get_state(&self) -> (usize, Array1<usize>) {
         // make cache state actual cache
         // and and block per query
@@ -43,11 +61,42 @@ impl CacheSimulator {
         }
     }
 
+    /// blocks of `qid` sent per level, for erasure coded apps to pick a block the client doesn't have
+    fn sent(&self, qid: usize) -> &[usize] {
+        self.sent_per_level.get(&qid).map(|s| s.as_slice()).unwrap_or(&[])
+    }
+
+    /// a coded block of `level` of `qid` was sent, see `sent`
+    fn sent_to_level(&mut self, qid: usize, level: usize) {
+        let sent = self.sent_per_level.entry(qid).or_insert_with(Vec::new);
+        if sent.len() <= level {
+            sent.resize(level + 1, 0);
+        }
+        sent[level] += 1;
+    }
+
     fn reset(&mut self) {
         debug!("reset ------ {:?} {:?}", self.cache, self.head);
         self.head = 0;
         self.cache = vec![-1; self.cachesize];
         self.cache_per_query.fill(0);
+        self.sent_per_level.clear();
+    }
+
+    /// the client lost the most recent block of `qid`
+    pub fn remove(&mut self, qid: usize) {
+        if self.cache_per_query.get(qid).cloned().unwrap_or(0) == 0 {
+            return;
+        }
+
+        self.cache_per_query[qid] -= 1;
+        if self.cache_per_query[qid] == 0 {
+            self.sent_per_level.remove(&qid);
+        }
+        let slot = (0..self.head).rev().find(|&i| self.cache[i] == qid as i32);
+        if let Some(i) = slot {
+            self.cache[i] = -1;
+        }
     }
 
     fn add(&mut self, qid: usize) {
@@ -55,6 +104,9 @@ impl CacheSimulator {
         let cur_qid = self.cache[self.head];
         if cur_qid >= 0 {
             self.cache_per_query[cur_qid as usize] -= 1;
+            if self.cache_per_query[cur_qid as usize] == 0 {
+                self.sent_per_level.remove(&(cur_qid as usize));
+            }
         }
 
         self.cache[self.head] = qid as i32;
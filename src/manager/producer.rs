@@ -0,0 +1,204 @@
+/*
+ * ProducerPool: worker threads that run an app's `Producer` for queries whose
+ * blocks aren't generated yet.
+ *
+ * The scheduling thread requests the queries it plans for and the likely ones, and
+ * tells the scheduler how long each query waits before its blocks can be sent; the
+ * sender skips the queries that aren't ready when their slot comes up.
+ */
+use crate::apps;
+use crate::metrics;
+
+use crossbeam::channel::{self, RecvTimeoutError};
+use crossbeam_utils::atomic::AtomicCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// smoothing of the generation latency estimate
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+pub struct ProducerPool {
+    queue: channel::Sender<usize>,
+    /// blocks of each query are generated
+    ready: Arc<RwLock<Vec<bool>>>,
+    /// queued queries, with the time a worker started generating them
+    pending: Arc<Mutex<HashMap<usize, Option<Instant>>>>,
+    /// moving average of the time to generate a query, in ms
+    latency_ms: Arc<AtomicCell<f64>>,
+    workers: usize,
+}
+
+impl ProducerPool {
+    /// Start `workers` threads generating blocks with `producer`, until `kill_thread` is set.
+    /// `latency_ms` is the generation latency assumed until the first query is generated.
+    pub fn start(producer: Arc<dyn apps::Producer>, total_queries: usize, workers: usize,
+                 latency_ms: f64, kill_thread: Arc<AtomicCell<bool>>,
+                 metrics: Arc<metrics::Metrics>) -> (Self, Vec<thread::JoinHandle<()>>) {
+        let ready: Vec<bool> = (0..total_queries).map(|qid| producer.is_ready(qid)).collect();
+        info!("producer: {} of {} queries ready, {} workers", ready.iter().filter(|&&r| r).count(), total_queries, workers);
+
+        let (tx, rx) = channel::unbounded::<usize>();
+        let pool = ProducerPool{ queue: tx,
+                                 ready: Arc::new(RwLock::new(ready)),
+                                 pending: Arc::new(Mutex::new(HashMap::new())),
+                                 latency_ms: Arc::new(AtomicCell::new(latency_ms)),
+                                 workers: std::cmp::max(1, workers) };
+
+        let handles = (0..pool.workers).map(|i| {
+            let rx = rx.clone();
+            let producer = producer.clone();
+            let ready = pool.ready.clone();
+            let pending = pool.pending.clone();
+            let latency = pool.latency_ms.clone();
+            let kill_thread = kill_thread.clone();
+            let metrics = metrics.clone();
+            thread::spawn(move || {
+                loop {
+                    if kill_thread.load() {
+                        debug!("Terminating producer worker {}", i);
+                        break;
+                    }
+
+                    let qid = match rx.recv_timeout(Duration::from_millis(10)) {
+                        Ok(qid) => qid,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+
+                    let start = Instant::now();
+                    pending.lock().unwrap().insert(qid, Some(start));
+                    let result = producer.produce(qid);
+                    let elapsed = start.elapsed().as_micros() as f64 / 1000.0;
+
+                    if result.is_ok() {
+                        if let Some(r) = ready.write().unwrap().get_mut(qid) {
+                            *r = true;
+                        }
+                        let estimate = latency.load();
+                        latency.store((1.0 - LATENCY_SMOOTHING) * estimate + LATENCY_SMOOTHING * elapsed);
+                    }
+                    let queued = {
+                        let mut pending = pending.lock().unwrap();
+                        pending.remove(&qid);
+                        pending.len()
+                    };
+                    metrics.generation_pending.set(queued as f64);
+
+                    match result {
+                        Ok(()) => {
+                            metrics.queries_generated.inc();
+                            metrics.generation_latency_ms.observe(elapsed);
+                        },
+                        Err(err) => {
+                            // requested again the next time it's scheduled
+                            error!("producer: query {} failed: {}", qid, err);
+                            metrics.generation_errors.inc();
+                        }
+                    }
+                }
+            })
+        }).collect();
+
+        (pool, handles)
+    }
+
+    pub fn is_ready(&self, qid: usize) -> bool {
+        self.ready.read().unwrap().get(qid).cloned().unwrap_or(false)
+    }
+
+    /// queue `qid` unless it's ready or already queued, returns whether it was queued
+    pub fn request(&self, qid: usize) -> bool {
+        if self.is_ready(qid) {
+            return false;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains_key(&qid) {
+            return false;
+        }
+        pending.insert(qid, None);
+        self.queue.send(qid).is_ok()
+    }
+
+    pub fn latency_ms(&self) -> f64 {
+        self.latency_ms.load()
+    }
+
+    /// Per query, the ms until its blocks can be sent: 0 if ready, what's left of the
+    /// latency estimate if it's being generated, and the estimate plus the queue ahead
+    /// of it otherwise.
+    pub fn wait_ms(&self) -> Vec<usize> {
+        let latency = self.latency_ms();
+        let pending = self.pending.lock().unwrap();
+        let queued = pending.values().filter(|started| started.is_none()).count();
+        let backlog = latency * (queued / self.workers) as f64;
+
+        self.ready.read().unwrap().iter().enumerate().map(|(qid, &ready)| {
+            if ready {
+                return 0;
+            }
+            let wait = match pending.get(&qid) {
+                Some(Some(started)) => latency - started.elapsed().as_millis() as f64,
+                Some(None) => latency + backlog,
+                None => latency + backlog,
+            };
+            std::cmp::max(1, wait.ceil() as i64) as usize
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// query i is ready after its first generation, odd queries fail
+    struct EvenProducer {
+        generated: Mutex<Vec<usize>>,
+    }
+
+    impl apps::Producer for EvenProducer {
+        fn is_ready(&self, index: usize) -> bool {
+            index == 0 || self.generated.lock().unwrap().contains(&index)
+        }
+
+        fn produce(&self, index: usize) -> Result<(), String> {
+            if index % 2 == 1 {
+                return Err("odd".to_owned());
+            }
+            self.generated.lock().unwrap().push(index);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_producer_pool() {
+        let producer = Arc::new(EvenProducer{ generated: Mutex::new(Vec::new()) });
+        let kill = Arc::new(AtomicCell::new(false));
+        let metrics = Arc::new(metrics::Metrics::new());
+        let (pool, handles) = ProducerPool::start(producer.clone(), 4, 2, 50.0, kill.clone(), metrics.clone());
+
+        assert_eq!(pool.wait_ms()[0], 0);
+        assert!(pool.wait_ms()[2] >= 50);
+        assert!(!pool.request(0));
+        assert!(pool.request(2));
+        assert!(pool.request(3));
+
+        let start = Instant::now();
+        while (metrics.queries_generated.get() == 0 || metrics.generation_errors.get() == 0) && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(pool.is_ready(2));
+        assert!(!pool.is_ready(3));
+        assert_eq!(pool.wait_ms()[2], 0);
+        assert_eq!(metrics.queries_generated.get(), 1);
+        // a failed query can be requested again
+        assert!(pool.request(3));
+
+        kill.store(true);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::ds;
 use crate::apps;
+use crate::metrics;
 use crate::scheduler;
 
 use std::collections::HashMap;
@@ -12,6 +13,7 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             cache_sim: Arc<RwLock<super::CacheSimulator>>,
             mut sched: Box<dyn scheduler::SchedulerTrait>,
             tm: Arc<RwLock<ds::TimeManager>>,
+            metrics: Arc<metrics::Metrics>,
 
             // config
             continues: bool,
@@ -21,11 +23,15 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             // flags
             kill_thread: Arc<AtomicCell<bool>>,
             state_change_flag: Arc<RwLock<bool>>,
+            trace: Arc<RwLock<super::SessionTrace>>,
+            pool: Option<Arc<super::ProducerPool>>,
             
             // channels
             dist_rx: Arc<Mutex<mpsc::Receiver<ds::PredictorState>>>,
             schedule_tx: Arc<Mutex<mpsc::SyncSender<Vec<usize>>>>,
             schedule_rx_th1: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>,
+            // (schedules the sender received, entries of the last one it took)
+            plan_progress: Arc<AtomicCell<(usize, usize)>>,
             )
     {
 
@@ -43,6 +49,8 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
     }
 
     let mut last_new_dist = Instant::now();
+    // schedules that reach the sender, the ones replaced in the channel don't
+    let mut schedules_sent: usize = 0;
     let debug_cache = false;
     loop {
         
@@ -57,9 +65,18 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             match dist_rx.lock().unwrap().try_recv() {
                 Ok(dist) => {
                     // new distribution
-                    let dist = app.lock().unwrap().decode_dist(dist);
+                    let client_time = dist.time;
+                    let mut dist = app.lock().unwrap().decode_dist(dist);
+                    // deltas are relative to when the client made the prediction
+                    if let Some(client_time) = client_time {
+                        if let Some(time) = tm.read().unwrap().client_time_to_instant(client_time) {
+                            dist.time = time;
+                        }
+                    }
                     decoded_dist_copy = dist.clone();
                     last_new_dist = Instant::now();
+                    metrics.distributions_decoded.inc();
+                    trace.write().unwrap().prob = Some(dist.clone());
                     tm.write().unwrap().update_time(dist.time.clone());
                     
                     dist
@@ -86,19 +103,23 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         
         let duration = start.elapsed();
         info!("decoding elapsed time {:?}", duration);
+        metrics.decode_latency_ms.observe(duration.as_micros() as f64 / 1000.0);
+        if let Some(bw) = tm.read().unwrap().get_forecast().harmonic_mean() {
+            metrics.bandwidth_forecast_mbps.set(bw);
+        }
 
         
         // 2) get the current state from the sender:
+        // the app sets the flag when its layout changes: the client's state is reinitialized.
+        // check and clear it under one lock, so a change made in between isn't lost
         {
-            let state_changed = state_change_flag.read().unwrap();
+            let mut state_changed = state_change_flag.write().unwrap();
             if *state_changed {
+               info!("app state changed, reset the cache model");
                cache_sim.write().unwrap().reset();
+               *state_changed = false;
             }
         }
-        {
-            let mut flag = state_change_flag.write().unwrap();
-            *flag = false;
-        }
         let (cache_head, cache_state) = cache_sim.read().unwrap().get_state();
 
         debug!("schedule for {:?}", cache_head);
@@ -116,12 +137,30 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             debug!("schedule content nblocks:index {:?}", cache_content);
         }
         
-        // 4) start scheduling
+        // 3) queries the app generates on demand: start on the likely ones,
+        //    and tell the scheduler how long the others wait
+        if let Some(pool) = &pool {
+            for qid in decoded_dist.get_k() {
+                pool.request(qid);
+            }
+            sched.set_wait(Some(pool.wait_ms()));
+        }
+
+        // 4) start scheduling, after what the sender took of the last plan:
+        //    none of it if the sender hasn't picked it up yet
+        let (received, consumed) = plan_progress.load();
+        sched.set_consumed(Some(if received == schedules_sent { consumed } else { 0 }));
         let start = Instant::now();
         let decision = sched.run_scheduler(decoded_dist, cache_state, cache_head);
         let duration = start.elapsed();
         
         info!("decisions elapsed time {:?}", duration);
+        metrics.scheduling_latency_ms.observe(duration.as_micros() as f64 / 1000.0);
+        metrics.scheduler_rounds.inc();
+        if let Some(delta) = sched.last_plan_delta() {
+            info!("plan delta: reused {} replanned {} changed {} of {} previous",
+                  delta.reused, delta.replanned, delta.changed, delta.previous);
+        }
 
         round += 1;
         if decision.len() == 0 {
@@ -129,14 +168,31 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             continue;
         }
 
+        if let Some(pool) = &pool {
+            for &qid in decision.iter() {
+                pool.request(qid);
+            }
+        }
+
+        {
+            let mut trace = trace.write().unwrap();
+            trace.round = round;
+            trace.schedule = decision.clone();
+            trace.schedule_time = Some(Instant::now());
+        }
+
         // write result to sender thread
         let local_schedule_tx = schedule_tx.lock().unwrap();
         match local_schedule_tx.try_send(decision) {
             Err(TrySendError::Full(data)) => {
-                let _ = schedule_rx_th1.lock().unwrap().try_recv();
+                // the previous schedule is replaced, unless the sender took it meanwhile
+                if schedule_rx_th1.lock().unwrap().try_recv().is_err() {
+                    schedules_sent += 1;
+                }
                 local_schedule_tx.try_send(data).unwrap();
             },
-            _ => {},
+            Ok(()) => schedules_sent += 1,
+            Err(TrySendError::Disconnected(_)) => {},
         }
     }
 }
@@ -1,5 +1,6 @@
 use crate::ds;
 use crate::apps;
+use crate::metrics;
 
 use actix::prelude::*;
 extern crate ndarray;
@@ -16,10 +17,16 @@ use crossbeam_utils::atomic::AtomicCell;
  *
  *   should kill self?
  *     kill self
+ *   websocket disconnected?
+ *     wait for the client to resume
  *   should check for new schedule?
  *     update/check schedule
  *   
+ *   block not generated yet?
+ *     ask the producer pool, skip it
  *   select which block for request based on cache simulator
+ *     (erasure coded apps: a coded block the client doesn't have)
+ *   block cache, or app: get the encoded block
  *   cachesimulator.update
  *   ws.send(block)
  *   if should sleep to manage bandwidth:
@@ -31,12 +38,18 @@ use crossbeam_utils::atomic::AtomicCell;
 
 pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
              cache_sim: Arc<RwLock<super::CacheSimulator>>,
-             ws_addr: Recipient<ds::StreamBlock>,
+             ws_slot: Arc<RwLock<Option<Recipient<ds::StreamBlock>>>>,
+             session: Arc<Mutex<super::Session>>,
              tm: Arc<RwLock<ds::TimeManager>>,
+             metrics: Arc<metrics::Metrics>,
+             pool: Option<Arc<super::ProducerPool>>,
+             block_cache: Arc<Mutex<super::BlockCache>>,
              _congestion: Arc<AtomicCell<u128>>,
              kill_thread: Arc<AtomicCell<bool>>,
              min_wait: usize,
-             schedule_rx: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>) {
+             schedule_rx: Arc<Mutex<mpsc::Receiver<Vec<usize>>>>,
+             // (schedules received, entries of the last one taken), for the scheduler
+             plan_progress: Arc<AtomicCell<(usize, usize)>>) {
     // stats
     let mut round: usize = 1;
     let mut total_blocks: usize = 1;
@@ -48,9 +61,12 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
     
     // for bw control
     let block_size = app.lock().unwrap().get_block_size(); // bytes
+    // erasure coded apps: which coded block to send next
+    let erasure = app.lock().unwrap().get_erasure();
     let size_megabits = (block_size as f64* 8.0) / (1024.0 * 1024.0);
     let bandwidth = tm.read().unwrap().get_ref_bw();
     info!("block_size: {:?} size_megabits: {:?}", block_size, size_megabits);
+    metrics.cache_capacity_blocks.set(cache_sim.read().unwrap().cachesize as f64);
 
     let mut start = Instant::now();
     loop {
@@ -60,12 +76,18 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
             break;
         }
 
-        
+        // the socket is swapped when the client reconnects
+        if ws_slot.read().unwrap().is_none() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            continue
+        }
 
         schedule_iter = match schedule_rx.lock().unwrap().try_recv() {
             Ok(schedule) => {
                 debug!("scheduler: {:?}", schedule);
                 schedule_pt = schedule;
+                let (received, _) = plan_progress.load();
+                plan_progress.store((received + 1, 0));
 
                 // submit this to app
                 app.lock().unwrap().prepare_schedule(&schedule_pt);
@@ -78,22 +100,102 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
 
         match schedule_iter.next() {
             Some(&qid) => {
+                // taken, whether it's sent or skipped below
+                let (received, consumed) = plan_progress.load();
+                plan_progress.store((received, consumed + 1));
+                // blocks generated on demand may not be there yet, the slot is lost
+                if let Some(pool) = &pool {
+                    if !pool.is_ready(qid) {
+                        pool.request(qid);
+                        metrics.blocks_not_ready.inc();
+                        continue
+                    }
+                }
+
                 // get how many blocks in cache, and update cache
-                let incache = cache_sim.read().unwrap().get(qid);
+                let layout = erasure.as_ref().and_then(|layouts| layouts.get(qid));
+                let index = {
+                    let cache = cache_sim.read().unwrap();
+                    // coded apps get a coded block the client doesn't have, prefix apps the next block
+                    match layout {
+                        Some(layout) => layout.next(cache.get(qid), cache.sent(qid)),
+                        None => Some(cache.get(qid)),
+                    }
+                };
+                let index = match index {
+                    Some(index) => index,
+                    None => continue, // every level can be decoded
+                };
                 let cache_start = Instant::now();
-                cache_sim.write().unwrap().add(qid);
+                let occupancy = {
+                    let mut cache = cache_sim.write().unwrap();
+                    if let Some((level, _)) = layout.and_then(|layout| layout.locate(index)) {
+                        cache.sent_to_level(qid, level);
+                    }
+                    cache.add(qid);
+                    cache.occupancy()
+                };
+                metrics.cache_occupancy_blocks.set(occupancy as f64);
                 let cache_update_time = cache_start.elapsed().as_millis() as u64;
                 let retrieval_start = Instant::now();
                 let count = 1;
-                match app.lock().unwrap().get_nblocks_byindex(qid, count, incache) {
+                let cached = block_cache.lock().unwrap().get(qid, index);
+                let blocks = match cached {
+                    Some(bytes) => {
+                        metrics.block_cache_hits.inc();
+                        Some(vec![ds::StreamBlock::Shared(bytes)])
+                    },
+                    None => {
+                        metrics.block_cache_misses.inc();
+                        // owned blocks move to the cache, the sender and the cache share them
+                        let blocks = app.lock().unwrap().get_nblocks_byindex(qid, count, index).map(|blocks| {
+                            blocks.into_iter().map(|b| match b {
+                                ds::StreamBlock::Binary(bytes) => ds::StreamBlock::Shared(bytes.into()),
+                                other => other,
+                            }).collect::<Vec<_>>()
+                        });
+                        if let Some(blocks) = &blocks {
+                            let mut cache = block_cache.lock().unwrap();
+                            for (i, b) in blocks.iter().enumerate() {
+                                if let ds::StreamBlock::Shared(bytes) = b {
+                                    let evicted = cache.insert(qid, index + i, bytes.clone());
+                                    metrics.block_cache_evictions.add(evicted as u64);
+                                }
+                            }
+                            metrics.block_cache_bytes.set(cache.size() as f64);
+                        }
+                        blocks
+                    },
+                };
+                match blocks {
                     Some(blocks) => {
                         if blocks.len() == 0 {
                             // todo: give scheduler max blocks per query
-                            error!("get_nblocks no blocks: {:?} {:?} {:?} <- happens when we have var # of blocks", qid, count, incache);
+                            error!("get_nblocks no blocks: {:?} {:?} {:?} <- happens when we have var # of blocks", qid, count, index);
                             continue
                         }
 
                         for b in blocks {
+                            // blocks not acked by the client are replayed when it resumes.
+                            // frame and pick the socket under the session lock, so a resume
+                            // either sees this block or we send it to the new socket
+                            let (ws_addr, b) = {
+                                let mut session = session.lock().unwrap();
+                                let ws_addr = ws_slot.read().unwrap().clone();
+                                let b = match b {
+                                    ds::StreamBlock::Binary(bytes) => session.frame(Some(qid), bytes.into()),
+                                    ds::StreamBlock::Shared(bytes) => session.frame(Some(qid), bytes),
+                                    other => other,
+                                };
+                                (ws_addr, b)
+                            };
+                            let ws_addr = match ws_addr {
+                                Some(addr) => addr,
+                                None => {
+                                    debug!("websocket closed, block {:?} kept for resume", qid);
+                                    continue;
+                                }
+                            };
 
                             let retrieval_time = retrieval_start.elapsed().as_millis();
                             let sending_start = Instant::now();
@@ -102,13 +204,16 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
                             match w {
                                 Ok(_) => {
                                     total_blocks += 1;
+                                    metrics.blocks_sent.inc();
                                     debug!("sending took: {:?} retrieval: {:?} cache_update: {:?}", sending_start.elapsed(), retrieval_time, cache_update_time);
                                     if sending_start.elapsed().as_millis() > 1 {
                                         error!("congestion {:?}", sending_start.elapsed());
+                                        metrics.send_congestion.inc();
                                     }
 
                                 }, Err(e) => {
                                     error!("websocket senderror {:?}", e);
+                                    metrics.send_errors.inc();
                                     continue;
                                 }
                             }
@@ -117,7 +222,7 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
 
                     },
                     None => {
-                        error!("get_nblocks None: {:?} {:?} {:?}", qid, count, incache);
+                        error!("get_nblocks None: {:?} {:?} {:?}", qid, count, index);
                         continue
                     }
                 }
@@ -127,6 +232,7 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         };
         
         let bw = bandwidth.load();
+        metrics.bandwidth_estimate_mbps.set(bw);
 
         // wait for as long as what we have put on network
         let elapsed = start.elapsed();
@@ -142,6 +248,7 @@ pub fn start(app: Arc<Mutex<Box<dyn apps::AppTrait>>>,
         let wait = sending_time_ns  as i64 - elapsed_ns as i64;
         info!("wait {:?}", wait as f64 / 1000000.0);
         let wait = std::cmp::max(wait, min_wait as i64);
+        metrics.sender_wait_ms.set(wait as f64 / 1000000.0);
         std::thread::sleep(std::time::Duration::from_nanos(wait as u64));
 
         start = Instant::now(); // before sleep to count for that time
@@ -0,0 +1,170 @@
+/*
+ * Session: the blocks streamed to one client, across websocket reconnects.
+ *
+ * Every block is framed with a session wide block id (bid) before it goes on
+ * the socket, and kept until the client acks it. When the client reconnects with
+ * the session token and the last bid it acked, the blocks after it are either
+ * replayed, or dropped from the cache model if they're no longer useful.
+ */
+use crate::ds;
+
+use rand::Rng;
+use std::collections::BTreeMap;
+
+/// a block on the wire, waiting for its ack
+#[derive(Clone, Debug)]
+pub struct SentBlock {
+    /// query the block belongs to, None for direct requests
+    pub qid: Option<usize>,
+    /// framed block: bid followed by the app's block, which is shared, not copied
+    pub bytes: ds::BlockBytes,
+    /// server time (ms since epoch) the block was last sent
+    pub sent_ms: u128,
+}
+
+pub struct Session {
+    pub token: String,
+    next_bid: u32,
+    last_acked: u32,
+    unacked: BTreeMap<u32, SentBlock>,
+    /// unacked blocks kept for replay; older ones are out of the client's cache anyway
+    capacity: usize,
+}
+
+pub fn new_token() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+fn now_ms() -> u128 {
+    ds::now_ms() as u128
+}
+
+impl Session {
+    pub fn new(token: String, capacity: usize) -> Self {
+        Session{ token: token, next_bid: 1, last_acked: 0, unacked: BTreeMap::new(), capacity: capacity }
+    }
+
+    /// start over with a new token, the client has none of the blocks sent so far
+    pub fn restart(&mut self, token: String) {
+        self.token = token;
+        self.clear();
+    }
+
+    /// forget the blocks on the wire, e.g. the app was reinitialized
+    pub fn clear(&mut self) {
+        self.unacked.clear();
+        self.last_acked = self.next_bid - 1;
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = std::cmp::max(1, capacity);
+    }
+
+    pub fn last_acked(&self) -> u32 {
+        self.last_acked
+    }
+
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// assign the next bid to `block` and keep it until it's acked
+    pub fn frame(&mut self, qid: Option<usize>, block: ds::BlockBytes) -> ds::StreamBlock {
+        let bid = self.next_bid;
+        self.next_bid += 1;
+
+        let mut head = bincode::serialize(&bid).unwrap();
+        head.extend(block.head);
+        let bytes = ds::BlockBytes{ head: head, body: block.body };
+
+        self.unacked.insert(bid, SentBlock{ qid: qid, bytes: bytes.clone(), sent_ms: now_ms() });
+        while self.unacked.len() > self.capacity {
+            let oldest = *self.unacked.keys().next().unwrap();
+            self.unacked.remove(&oldest);
+        }
+
+        ds::StreamBlock::Framed(bytes)
+    }
+
+    /// the client received `bid`, returns when it was sent
+    pub fn ack(&mut self, bid: u32) -> Option<u128> {
+        let block = self.unacked.remove(&bid)?;
+        self.last_acked = std::cmp::max(self.last_acked, bid);
+        Some(block.sent_ms)
+    }
+
+    /// The client reconnected after receiving every block up to `last_acked`.
+    /// Blocks after it are replayed if `useful` says so, in the order they were sent,
+    /// and returned as dropped otherwise.
+    ///
+    /// Returns (blocks to replay, blocks the client won't get).
+    pub fn resume<F>(&mut self, last_acked: u32, useful: F) -> (Vec<ds::StreamBlock>, Vec<SentBlock>)
+        where F: Fn(&SentBlock) -> bool {
+        let acked: Vec<u32> = self.unacked.range(..=last_acked).map(|(&bid, _)| bid).collect();
+        for bid in acked {
+            self.unacked.remove(&bid);
+        }
+        self.last_acked = std::cmp::max(self.last_acked, last_acked);
+
+        let mut replay = Vec::new();
+        let mut dropped = Vec::new();
+        let now = now_ms();
+        let unacked = std::mem::replace(&mut self.unacked, BTreeMap::new());
+        for (bid, mut block) in unacked {
+            if useful(&block) {
+                block.sent_ms = now;
+                replay.push(ds::StreamBlock::Framed(block.bytes.clone()));
+                self.unacked.insert(bid, block);
+            } else {
+                dropped.push(block);
+            }
+        }
+
+        (replay, dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bid(block: &ds::StreamBlock) -> u32 {
+        match block {
+            ds::StreamBlock::Framed(bytes) => bincode::deserialize(&bytes.head[..4]).unwrap(),
+            _ => panic!("unframed block"),
+        }
+    }
+
+    #[test]
+    fn test_session_resume() {
+        let mut session = Session::new(new_token(), 10);
+        let blocks: Vec<ds::StreamBlock> = (0..5).map(|i| session.frame(Some(i % 2), vec![i as u8].into())).collect();
+        let bids: Vec<u32> = blocks.iter().map(bid).collect();
+        assert_eq!(bids, vec![1, 2, 3, 4, 5]);
+
+        // 1 and 2 arrived, 3 got acked out of order before the socket dropped
+        assert!(session.ack(3).is_some());
+        assert!(session.ack(3).is_none());
+
+        // the client only cares about query 0 now
+        let (replay, dropped) = session.resume(2, |b| b.qid == Some(0));
+        assert_eq!(replay.iter().map(bid).collect::<Vec<u32>>(), vec![5]);
+        assert_eq!(dropped.iter().map(|b| b.qid).collect::<Vec<_>>(), vec![Some(1)]);
+        assert_eq!(session.last_acked(), 3);
+        assert_eq!(session.unacked(), 1);
+
+        // bids keep increasing across reconnects
+        assert_eq!(bid(&session.frame(None, Vec::<u8>::new().into())), 6);
+    }
+
+    #[test]
+    fn test_session_capacity() {
+        let mut session = Session::new(new_token(), 2);
+        for i in 0..5 {
+            session.frame(Some(i), Vec::<u8>::new().into());
+        }
+        let (replay, dropped) = session.resume(0, |_| true);
+        assert_eq!(replay.iter().map(bid).collect::<Vec<u32>>(), vec![4, 5]);
+        assert!(dropped.is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+/*
+ * Metrics: runtime statistics of the scheduling thread, the sender thread
+ * and the websocket, shared by all sessions of the server.
+ *
+ * Exposed by the webserver as Prometheus text on /metrics and as JSON on /stats.
+ */
+use crossbeam_utils::atomic::AtomicCell;
+use serde_derive::{Serialize};
+use std::sync::{Mutex};
+use std::fmt::Write;
+
+/// latency buckets in ms, upper bounds
+const LATENCY_BUCKETS_MS: [f64; 13] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0,
+                                        500.0, 1000.0, 2000.0, 5000.0, 10000.0];
+
+/// monotonically increasing count
+#[derive(Default)]
+pub struct Counter(AtomicCell<u64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load()
+    }
+}
+
+/// last observed value
+#[derive(Default)]
+pub struct Gauge(AtomicCell<f64>);
+
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.0.load()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HistogramSnapshot {
+    /// upper bound of each bucket, the last bucket (+Inf) is implied by count
+    pub bounds: Vec<f64>,
+    /// observations per bucket, not cumulative; one more entry than bounds for +Inf
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            n => Some(self.sum / n as f64),
+        }
+    }
+}
+
+/// distribution of observations over fixed buckets
+pub struct Histogram {
+    data: Mutex<HistogramSnapshot>,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        let data = HistogramSnapshot{ bounds: bounds.to_vec(), counts: vec![0; bounds.len() + 1],
+                                      sum: 0.0, count: 0 };
+        Histogram{ data: Mutex::new(data) }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let mut data = match self.data.lock() {
+            Ok(data) => data,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let idx = data.bounds.iter().position(|&b| v <= b).unwrap_or(data.bounds.len());
+        data.counts[idx] += 1;
+        data.sum += v;
+        data.count += 1;
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        match self.data.lock() {
+            Ok(data) => data.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+enum MetricRef<'a> {
+    Counter(&'a Counter),
+    Gauge(&'a Gauge),
+    Histogram(&'a Histogram),
+}
+
+pub struct Metrics {
+    // scheduling thread
+    pub scheduler_rounds: Counter,
+    pub distributions_decoded: Counter,
+    pub decode_latency_ms: Histogram,
+    pub scheduling_latency_ms: Histogram,
+    pub bandwidth_forecast_mbps: Gauge,
+
+    // sender thread
+    pub blocks_sent: Counter,
+    pub send_errors: Counter,
+    pub send_congestion: Counter,
+    pub sender_wait_ms: Gauge,
+    pub bandwidth_estimate_mbps: Gauge,
+    pub cache_occupancy_blocks: Gauge,
+    pub cache_capacity_blocks: Gauge,
+
+    // websocket
+    pub acks_received: Counter,
+    pub malformed_messages: Counter,
+    pub block_rtt_ms: Histogram,
+
+    // producer workers
+    pub queries_generated: Counter,
+    pub generation_errors: Counter,
+    pub generation_latency_ms: Histogram,
+    pub generation_pending: Gauge,
+    pub blocks_not_ready: Counter,
+
+    // block cache
+    pub block_cache_hits: Counter,
+    pub block_cache_misses: Counter,
+    pub block_cache_evictions: Counter,
+    pub block_cache_bytes: Gauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics{
+            scheduler_rounds: Counter::default(),
+            distributions_decoded: Counter::default(),
+            decode_latency_ms: Histogram::new(&LATENCY_BUCKETS_MS),
+            scheduling_latency_ms: Histogram::new(&LATENCY_BUCKETS_MS),
+            bandwidth_forecast_mbps: Gauge::default(),
+            blocks_sent: Counter::default(),
+            send_errors: Counter::default(),
+            send_congestion: Counter::default(),
+            sender_wait_ms: Gauge::default(),
+            bandwidth_estimate_mbps: Gauge::default(),
+            cache_occupancy_blocks: Gauge::default(),
+            cache_capacity_blocks: Gauge::default(),
+            acks_received: Counter::default(),
+            malformed_messages: Counter::default(),
+            block_rtt_ms: Histogram::new(&LATENCY_BUCKETS_MS),
+            queries_generated: Counter::default(),
+            generation_errors: Counter::default(),
+            generation_latency_ms: Histogram::new(&LATENCY_BUCKETS_MS),
+            generation_pending: Gauge::default(),
+            blocks_not_ready: Counter::default(),
+            block_cache_hits: Counter::default(),
+            block_cache_misses: Counter::default(),
+            block_cache_evictions: Counter::default(),
+            block_cache_bytes: Gauge::default(),
+        }
+    }
+
+    /// (name, help, metric) for every metric, in output order
+    fn describe(&self) -> Vec<(&'static str, &'static str, MetricRef)> {
+        vec![
+            ("scheduler_rounds_total", "scheduling rounds run", MetricRef::Counter(&self.scheduler_rounds)),
+            ("distributions_decoded_total", "client distributions decoded by the app", MetricRef::Counter(&self.distributions_decoded)),
+            ("decode_latency_ms", "time to decode a client distribution", MetricRef::Histogram(&self.decode_latency_ms)),
+            ("scheduling_latency_ms", "time to compute a schedule", MetricRef::Histogram(&self.scheduling_latency_ms)),
+            ("bandwidth_forecast_mbps", "harmonic mean of recent bandwidth samples", MetricRef::Gauge(&self.bandwidth_forecast_mbps)),
+            ("blocks_sent_total", "blocks pushed to the websocket", MetricRef::Counter(&self.blocks_sent)),
+            ("send_errors_total", "blocks the websocket failed to accept", MetricRef::Counter(&self.send_errors)),
+            ("send_congestion_total", "blocks that took more than 1ms to hand to the websocket", MetricRef::Counter(&self.send_congestion)),
+            ("sender_wait_ms", "last pause of the sender to match the bandwidth", MetricRef::Gauge(&self.sender_wait_ms)),
+            ("bandwidth_estimate_mbps", "bandwidth the sender paces blocks at", MetricRef::Gauge(&self.bandwidth_estimate_mbps)),
+            ("cache_occupancy_blocks", "blocks in the simulated client cache", MetricRef::Gauge(&self.cache_occupancy_blocks)),
+            ("cache_capacity_blocks", "size of the simulated client cache", MetricRef::Gauge(&self.cache_capacity_blocks)),
+            ("acks_received_total", "block acks received from the client", MetricRef::Counter(&self.acks_received)),
+            ("malformed_messages_total", "websocket messages that couldn't be parsed", MetricRef::Counter(&self.malformed_messages)),
+            ("block_rtt_ms", "time between sending a block and receiving its ack", MetricRef::Histogram(&self.block_rtt_ms)),
+            ("queries_generated_total", "queries whose blocks the producer generated", MetricRef::Counter(&self.queries_generated)),
+            ("generation_errors_total", "queries the producer failed to generate", MetricRef::Counter(&self.generation_errors)),
+            ("generation_latency_ms", "time to generate the blocks of a query", MetricRef::Histogram(&self.generation_latency_ms)),
+            ("generation_pending", "queries queued or being generated", MetricRef::Gauge(&self.generation_pending)),
+            ("blocks_not_ready_total", "scheduled blocks skipped because they weren't generated yet", MetricRef::Counter(&self.blocks_not_ready)),
+            ("block_cache_hits_total", "blocks sent from the server side block cache", MetricRef::Counter(&self.block_cache_hits)),
+            ("block_cache_misses_total", "blocks read from the app's backend", MetricRef::Counter(&self.block_cache_misses)),
+            ("block_cache_evictions_total", "blocks evicted from the block cache to make room", MetricRef::Counter(&self.block_cache_evictions)),
+            ("block_cache_bytes", "encoded blocks held by the block cache", MetricRef::Gauge(&self.block_cache_bytes)),
+        ]
+    }
+
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, metric) in self.describe() {
+            let name = format!("khameleon_{}", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            match metric {
+                MetricRef::Counter(c) => {
+                    let _ = writeln!(out, "# TYPE {} counter", name);
+                    let _ = writeln!(out, "{} {}", name, c.get());
+                },
+                MetricRef::Gauge(g) => {
+                    let _ = writeln!(out, "# TYPE {} gauge", name);
+                    let _ = writeln!(out, "{} {}", name, g.get());
+                },
+                MetricRef::Histogram(h) => {
+                    let snapshot = h.snapshot();
+                    let _ = writeln!(out, "# TYPE {} histogram", name);
+                    let mut cumulative = 0;
+                    for (bound, count) in snapshot.bounds.iter().zip(snapshot.counts.iter()) {
+                        cumulative += count;
+                        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                    }
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, snapshot.count);
+                    let _ = writeln!(out, "{}_sum {}", name, snapshot.sum);
+                    let _ = writeln!(out, "{}_count {}", name, snapshot.count);
+                },
+            }
+        }
+        out
+    }
+
+    /// JSON summary: counters and gauges as numbers, histograms with count, sum, mean and buckets
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut map = serde_json::Map::new();
+        for (name, _, metric) in self.describe() {
+            let value = match metric {
+                MetricRef::Counter(c) => serde_json::json!(c.get()),
+                MetricRef::Gauge(g) => serde_json::json!(g.get()),
+                MetricRef::Histogram(h) => {
+                    let snapshot = h.snapshot();
+                    serde_json::json!({
+                        "count": snapshot.count,
+                        "sum": snapshot.sum,
+                        "mean": snapshot.mean(),
+                        "bounds": snapshot.bounds,
+                        "counts": snapshot.counts,
+                    })
+                },
+            };
+            map.insert(name.to_owned(), value);
+        }
+        serde_json::Value::Object(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let h = Histogram::new(&[1.0, 10.0]);
+        h.observe(0.5);
+        h.observe(1.0);
+        h.observe(5.0);
+        h.observe(50.0);
+
+        let snapshot = h.snapshot();
+        assert_eq!(snapshot.counts, vec![2, 1, 1]);
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.mean(), Some(56.5 / 4.0));
+    }
+
+    #[test]
+    fn test_exposition() {
+        let metrics = Metrics::new();
+        metrics.blocks_sent.add(3);
+        metrics.bandwidth_estimate_mbps.set(2.5);
+        metrics.block_rtt_ms.observe(15.0);
+        metrics.block_rtt_ms.observe(150.0);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE khameleon_blocks_sent_total counter\nkhameleon_blocks_sent_total 3\n"));
+        assert!(text.contains("khameleon_bandwidth_estimate_mbps 2.5\n"));
+        assert!(text.contains("khameleon_block_rtt_ms_bucket{le=\"10\"} 0\n"));
+        assert!(text.contains("khameleon_block_rtt_ms_bucket{le=\"20\"} 1\n"));
+        assert!(text.contains("khameleon_block_rtt_ms_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("khameleon_block_rtt_ms_count 2\n"));
+
+        let json = metrics.to_json();
+        assert_eq!(json["blocks_sent_total"], 3);
+        assert_eq!(json["block_rtt_ms"]["count"], 2);
+        assert_eq!(json["block_rtt_ms"]["mean"], 82.5);
+    }
+}
@@ -0,0 +1,97 @@
+/*
+ * Decay: late credit, how much a block is still worth to a request that was made
+ * before the block reached the client.
+ *
+ * The default objective only counts requests made after a block arrives. Latency
+ * sensitive apps can instead give late blocks partial credit, e.g. a block that
+ * arrives 20ms after the request still improves the response, one that arrives
+ * 2s late does not. Lateness is measured from the predicted moment of the request
+ * to the block's arrival, see Prob::integrate_with_late_credit.
+ */
+use serde_derive::{Deserialize, Serialize};
+
+/// # Example
+/// {"shape": "Exponential", "half_life_ms": 100.0}
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape")]
+pub enum Decay {
+    /// blocks arriving after the request are worthless
+    None,
+    /// value halves every `half_life_ms` of lateness
+    Exponential { half_life_ms: f32 },
+    /// value drops linearly to zero at `deadline_ms` of lateness
+    Linear { deadline_ms: f32 },
+    /// full value up to `deadline_ms` of lateness, nothing after
+    Step { deadline_ms: f32 },
+}
+
+impl Default for Decay {
+    fn default() -> Self {
+        Decay::None
+    }
+}
+
+impl Decay {
+    /// weight of a block that arrives `lateness_ms` after the request
+    pub fn weight(&self, lateness_ms: f32) -> f32 {
+        if lateness_ms <= 0.0 {
+            return 1.0;
+        }
+
+        match *self {
+            Decay::None => 0.0,
+            Decay::Exponential { half_life_ms } => (0.5f32).powf(lateness_ms / half_life_ms),
+            Decay::Linear { deadline_ms } => (1.0 - lateness_ms / deadline_ms).max(0.0),
+            Decay::Step { deadline_ms } => if lateness_ms <= deadline_ms { 1.0 } else { 0.0 },
+        }
+    }
+
+    /// late-credit window: lateness (ms) past which the weight is zero or negligible
+    pub fn late_window_ms(&self) -> usize {
+        match *self {
+            Decay::None => 0,
+            // 0.5^8 < 0.4%
+            Decay::Exponential { half_life_ms } => (8.0 * half_life_ms).ceil() as usize,
+            Decay::Linear { deadline_ms } => deadline_ms.ceil() as usize,
+            Decay::Step { deadline_ms } => deadline_ms.ceil() as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Prob;
+
+    #[test]
+    fn test_decay_weights() {
+        assert_eq!(Decay::None.weight(-5.0), 1.0);
+        assert_eq!(Decay::None.weight(5.0), 0.0);
+        assert!((Decay::Exponential{ half_life_ms: 100.0 }.weight(100.0) - 0.5).abs() < 1e-6);
+        assert!((Decay::Linear{ deadline_ms: 200.0 }.weight(50.0) - 0.75).abs() < 1e-6);
+        assert_eq!(Decay::Step{ deadline_ms: 50.0 }.weight(60.0), 0.0);
+    }
+
+    #[test]
+    fn test_integrate_with_late_credit() {
+        let mut probs = Prob::new(10);
+        for &delta in [0, 500].iter() {
+            let dist: indexmap::IndexMap<usize, f32> = vec![(3, 0.5)].into_iter().collect();
+            probs.set_probs_at(dist, delta);
+        }
+
+        let low = probs.get_lower_bound(200);
+        let base = probs.integrate_over_range(3, 200, 400, low);
+        assert_eq!(probs.integrate_with_late_credit(3, 200, 400, low, &Decay::None), base);
+
+        // late blocks get partial credit, more with a longer deadline
+        let short = probs.integrate_with_late_credit(3, 200, 400, low, &Decay::Linear{ deadline_ms: 50.0 });
+        let long = probs.integrate_with_late_credit(3, 200, 400, low, &Decay::Linear{ deadline_ms: 150.0 });
+        assert!(base < short && short < long);
+
+        // full credit to the requests predicted in the 100ms before the block arrives,
+        // where q3 has probability 0.5 at every moment
+        let step = probs.integrate_with_late_credit(3, 200, 400, low, &Decay::Step{ deadline_ms: 100.0 });
+        assert!((step - base - 0.5 * 100.0).abs() < 1e-3);
+    }
+}
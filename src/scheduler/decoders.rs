@@ -80,6 +80,13 @@ pub fn decode_point_model(point: &serde_json::Value) -> (f64, f64, f64) {
 /// get list of queries and their layout -> for each query, compute prob given the layout
 #[allow(dead_code)]
 pub fn decode_model(dist: &serde_json::Value, layout_matrix: &Array2<f32>) -> Prob {
+    decode_model_weighted(dist, layout_matrix, None)
+}
+
+/// `decode_model` with the probability of each query scaled by `weights`, e.g. when
+/// the layout holds several alternatives (zoom levels) and the weight is the chance of each
+pub fn decode_model_weighted(dist: &serde_json::Value, layout_matrix: &Array2<f32>,
+                             weights: Option<&Array1<f32>>) -> Prob {
         let nqueries = layout_matrix.rows();
         let mut probs = Prob::new(nqueries);
         let epsilon: f32 = 1.0 / nqueries as f32;
@@ -107,7 +114,10 @@ pub fn decode_model(dist: &serde_json::Value, layout_matrix: &Array2<f32>) -> Pr
                 let out_col_xmw = cdf_array(col_xmw, xmu, xsigma);
                 let out_col_yph = cdf_array(col_yph, ymu, ysigma);
                 let out_col_ymh = cdf_array(col_ymh, ymu, ysigma);
-                let probs_t = &out_col_xpw * &out_col_yph - &out_col_xpw * &out_col_ymh - &out_col_xmw * &out_col_yph + &out_col_xmw * &out_col_ymh;
+                let mut probs_t = &out_col_xpw * &out_col_yph - &out_col_xpw * &out_col_ymh - &out_col_xmw * &out_col_yph + &out_col_xmw * &out_col_ymh;
+                if let Some(weights) = weights {
+                    probs_t *= weights;
+                }
                 
                 let mut sub_queries_idx: Vec<usize> = Vec::new();
                 let mut sub_qprobs: Vec<f32> = Vec::new();
@@ -3,14 +3,32 @@ use crate::ds;
 
 /// public lib
 extern crate rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use rand::distributions::WeightedIndex;
 use rand::distributions::Distribution;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::sync::{Arc,  RwLock};
 use std::time::{Instant};
 
 extern crate ndarray;
-use ndarray::{Array1, Array2, ArrayView2, ArrayViewMut2};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, ArrayViewMut2, Axis};
+
+/// rows below this are not worth a thread of their own
+const MIN_ROWS_PER_THREAD: usize = 1024;
+
+/// random draws for a member of the rest queries without blocks before listing them
+const MAX_REST_DRAWS: usize = 64;
+
+/// how the next block is picked from the marginal expected utilities
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// sample proportionally to utility * probability (hedging)
+    Sample,
+    /// always pick the maximum
+    Argmax,
+}
 
 #[derive(Clone)]
 pub struct GreedyScheduler {
@@ -23,11 +41,173 @@ pub struct GreedyScheduler {
     pub total_queries: usize,
     pub tm: Arc<RwLock<ds::TimeManager>>,
     pub batch: usize,
+    /// threads used to integrate probabilities and compute rewards
+    pub threads: usize,
+    pub rng: StdRng,
+    pub policy: Policy,
+    /// warm start from `previous` instead of planning from scratch
+    pub incremental: bool,
+    pub replan_tolerance: f32,
+    pub previous: Option<PreviousPlan>,
+    pub plan_delta: Option<super::PlanDelta>,
+    /// None: plan `batch` blocks, Some: see `horizon`
+    pub adaptive_horizon: Option<AdaptiveHorizon>,
+    pub decay: super::Decay,
+    /// per query, ms until its blocks can be sent, None if they all can
+    pub wait_ms: Option<Vec<usize>>,
+    /// entries of the previous plan the sender has taken, for the next plan only.
+    /// The sender skips entries without sending, so the cache head can't tell
+    pub consumed: Option<usize>,
+    /// Queries that may have blocks in the client cache: the ones that had some at
+    /// the last plan, and the ones planned since, the sender only sends planned
+    /// blocks. None until the first plan, which scans the state.
+    pub cached: Option<BTreeSet<usize>>,
+    /// queries without blocks
+    pub no_blocks: Vec<usize>,
+}
+
+/// bounds of the adaptive planning horizon
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveHorizon {
+    pub slack_ms: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+/// last plan and the cache slot it started at
+#[derive(Clone, Debug)]
+pub struct PreviousPlan {
+    pub plan: Vec<usize>,
+    pub start_idx: usize,
+}
+
+/// Output of `integrate_probs_sparse`.
+///
+/// Row i of `matrix` holds the integrated probabilities of query `queries_ids[i]`;
+/// if `has_rest` is set, the last row holds the probabilities shared by every query
+/// that is not in `queries_ids`.
+#[derive(Clone, Debug)]
+pub struct SparseProbs {
+    pub queries_ids: Vec<usize>,
+    pub matrix: Array2<f32>,
+    pub has_rest: bool,
+}
+
+/// Rest queries (not in `SparseProbs::queries_ids`) grouped by how many of their blocks
+/// are scheduled. Nearly every query has none, so level 0 isn't listed: its members
+/// are the queries that aren't in `excluded`, and building the levels costs
+/// O(k + cached queries) instead of O(total_queries).
+#[derive(Clone, Debug)]
+struct RestLevels {
+    /// levels[n], n > 0: rest queries with n blocks and more blocks left
+    levels: Vec<Vec<usize>>,
+    /// queries not at level 0: explicit ones, without blocks, or with scheduled blocks
+    excluded: HashSet<usize>,
+    /// members of level 0
+    zero: usize,
+    total_queries: usize,
+    /// queries before it are all excluded, see `take_any`
+    cursor: usize,
+}
+
+impl RestLevels {
+    /// number of levels
+    fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    fn count(&self, nblocks: usize) -> usize {
+        match nblocks {
+            0 => self.zero,
+            n => self.levels[n].len(),
+        }
+    }
+
+    /// add `qid` to level `nblocks` > 0
+    fn push(&mut self, nblocks: usize, qid: usize) {
+        self.levels[nblocks].push(qid);
+    }
+
+    /// remove a member of level `nblocks` picked uniformly
+    fn take_random<R: Rng>(&mut self, nblocks: usize, rng: &mut R) -> usize {
+        if nblocks > 0 {
+            let members = &mut self.levels[nblocks];
+            return members.swap_remove(rng.gen_range(0, members.len()));
+        }
+
+        let drawn = (0..MAX_REST_DRAWS).map(|_| rng.gen_range(0, self.total_queries))
+                                       .find(|qid| !self.excluded.contains(qid));
+        let qid = match drawn {
+            Some(qid) => qid,
+            None => {
+                // most queries are excluded, list the others
+                let members: Vec<usize> = (0..self.total_queries).filter(|qid| !self.excluded.contains(qid)).collect();
+                members[rng.gen_range(0, members.len())]
+            },
+        };
+        self.excluded.insert(qid);
+        self.zero -= 1;
+        qid
+    }
+
+    /// remove any member of level `nblocks`, the same one for the same levels
+    fn take_any(&mut self, nblocks: usize) -> usize {
+        if nblocks > 0 {
+            return self.levels[nblocks].pop().unwrap();
+        }
+
+        let qid = (self.cursor..self.total_queries).find(|qid| !self.excluded.contains(qid)).unwrap();
+        self.cursor = qid + 1;
+        self.excluded.insert(qid);
+        self.zero -= 1;
+        qid
+    }
+}
+
+/// entry of the lazy priority queue used by `greedy_argmax`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Candidate {
+    /// index into `SparseProbs::queries_ids`
+    Query(usize),
+    /// rest queries with this many scheduled blocks
+    Rest(usize),
+}
+
+#[derive(Debug)]
+struct HeapEntry {
+    priority: f32,
+    candidate: Candidate,
+    /// slot at which `priority` was computed
+    slot: usize,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    /// max-heap on priority; ties go to the smaller candidate to keep plans deterministic
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.partial_cmp(&other.priority).unwrap_or(Ordering::Equal)
+            .then_with(|| other.candidate.cmp(&self.candidate))
+    }
 }
 
 pub fn new(batch: usize, cachesize: usize, utility: Array1<f32>,
            blocks_per_query: Vec<usize>,
-           tm: Arc<RwLock<ds::TimeManager>>) -> GreedyScheduler {
+           tm: Arc<RwLock<ds::TimeManager>>,
+           options: &super::SchedulerOptions,
+           policy: Policy) -> GreedyScheduler {
     let total_queries = blocks_per_query.len();
     let max_blocks_count = utility.len();
     let mut utility_matrix: Array2<f32> = Array2::zeros((total_queries, max_blocks_count));
@@ -44,12 +224,35 @@ pub fn new(batch: usize, cachesize: usize, utility: Array1<f32>,
                      }
                  });
 
+    let no_blocks: Vec<usize> = (0..total_queries).filter(|&qid| blocks_per_query[qid] == 0).collect();
     let blocks_per_query: Array1<usize> = blocks_per_query.iter().map(|v| *v).collect();
+    let rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
 
     GreedyScheduler {cachesize: cachesize, utility: utility, batch: batch,
                      total_queries: total_queries, utility_matrix: utility_matrix,
                      tm: tm,
-                     blocks_per_query: blocks_per_query}
+                     blocks_per_query: blocks_per_query,
+                     threads: std::cmp::max(1, options.threads),
+                     rng: rng,
+                     policy: policy,
+                     incremental: options.incremental,
+                     replan_tolerance: options.replan_tolerance,
+                     previous: None,
+                     plan_delta: None,
+                     adaptive_horizon: match options.adaptive_horizon {
+                         true => Some(AdaptiveHorizon{ slack_ms: options.horizon_slack_ms,
+                                                       min: options.min_horizon,
+                                                       max: options.max_horizon }),
+                         false => None,
+                     },
+                     decay: options.decay,
+                     wait_ms: None,
+                     consumed: None,
+                     cached: None,
+                     no_blocks: no_blocks}
 }
 
 
@@ -58,201 +261,498 @@ impl GreedyScheduler {
     #[inline]
     pub fn integrate_probs_slow(&self, probs: super::Prob, total_queries: usize, horizon: usize) -> Array2<f32> {
         let mut matrix: Array2<f32> = Array2::zeros((total_queries, horizon));
-        let mut index = 0;
 
+        let (deltas, lows, horizon_delta) = self.slot_deltas(&probs, horizon);
+        self.integrate_rows(&mut matrix, &probs, |index| index, &deltas, &lows, horizon_delta);
+
+        matrix
+    }
+
+    /// client deltas and model lower bounds for every slot in the horizon,
+    /// plus the delta of the horizon itself
+    fn slot_deltas(&self, probs: &super::Prob, horizon: usize) -> (Vec<usize>, Vec<usize>, usize) {
         let tm = self.tm.read().unwrap();
         let mut deltas: Vec<usize> = Vec::new();
         let mut lows: Vec<usize> = Vec::new();
-        
+
         for t in 0..horizon {
             deltas.push(tm.slot_to_client_delta(t));
             lows.push(probs.get_lower_bound(t));
         }
         let horizon_delta = tm.slot_to_client_delta(horizon);
 
+        (deltas, lows, horizon_delta)
+    }
 
-        for mut row in matrix.genrows_mut() {
-                for (t, v) in row.indexed_iter_mut() {
-                    *v = probs.integrate_over_range(index, deltas[t], horizon_delta, lows[t]);
-                }
-            index += 1;
+    /// first slot that can hold a block of `qid`: slots go on the network before
+    /// the producer generates it
+    fn ready_slot(&self, qid: usize) -> usize {
+        match self.wait_ms.as_ref().and_then(|w| w.get(qid)) {
+            Some(&wait) if wait > 0 => {
+                let tm = self.tm.read().unwrap();
+                tm.client_delta_to_slots(tm.slot_to_client_delta(0) + wait)
+            },
+            _ => 0,
         }
-
-        matrix
     }
 
-    /// Analytically compute area under linear curve from t to m
-    ///
-    /// For each query, integrate (e.g., sum) over probabilities
-    /// precompute u_i,t array
-    ///     i: query
-    ///     t: time step
-    ///     horizon:  max timestep
-    ///     where u_i,t means the sum probabilities from t to m for query i 
-    #[inline]
-    pub fn integrate_probs(&self, probs: super::Prob, total_queries: usize, horizon: usize) -> Array2<f32> {
-        let mut matrix: Array2<f32> = Array2::zeros((total_queries, horizon));
-        let mut index = 0;
-
-        let tm = self.tm.read().unwrap();
-        let mut deltas: Vec<usize> = Vec::new();
-        let mut lows: Vec<usize> = Vec::new();
-        
-        for t in 0..horizon {
-            deltas.push(tm.slot_to_client_delta(t));
-            lows.push(probs.get_lower_bound(t));
-        }
-        let horizon_delta = tm.slot_to_client_delta(horizon);
+    /// first slot each row of `matrix` has a positive probability at, `horizon` if none:
+    /// rows of queries still being generated start at zero, and never grow after that
+    fn first_slots(matrix: ArrayView2<f32>, horizon: usize) -> Vec<usize> {
+        matrix.genrows().into_iter()
+            .map(|row| row.iter().position(|&p| p > 0.0).unwrap_or(horizon))
+            .collect()
+    }
 
-        let q_in_p = probs.get_k();
-        let mut rest: Option<Array1<f32>> = None;
+    /// Last slot a row of `probs` starts at, 0 if none does: before it, slots without
+    /// a reward wait for blocks being generated. The rest row counts like the explicit
+    /// ones, queries only the rest covers may be the last to become ready.
+    fn latest_start(probs: &SparseProbs, horizon: usize) -> usize {
+        let first_slots = Self::first_slots(probs.matrix.view(), horizon);
+        let (explicit, rest) = first_slots.split_at(probs.queries_ids.len());
+        let rest = if probs.has_rest { rest.first() } else { None };
+        explicit.iter().chain(rest).cloned().filter(|&t| t < horizon).max().unwrap_or(0)
+    }
 
-        // iterate over queries in probs and use their explicit probabilites
-        // then compute for a uniform 
-        for mut row in matrix.genrows_mut() {
-            if q_in_p.contains(&index) {
-                // compute the probability of the query over future timestamps
+    /// Fill row i of `matrix` with the probabilities of query `row_to_qid(i)`
+    /// integrated from each slot to the horizon, zero for the slots before it's ready.
+    /// Rows are independent, so they are split across `self.threads` threads.
+    fn integrate_rows<F>(&self, matrix: &mut Array2<f32>, probs: &super::Prob, row_to_qid: F,
+                         deltas: &[usize], lows: &[usize], horizon_delta: usize)
+        where F: Fn(usize) -> usize + Sync {
+        let fill = |offset: usize, mut rows: ArrayViewMut2<f32>| {
+            for (i, mut row) in rows.genrows_mut().into_iter().enumerate() {
+                let qid = row_to_qid(offset + i);
+                let ready = self.ready_slot(qid);
                 for (t, v) in row.indexed_iter_mut() {
-                    *v = probs.integrate_over_range(index, deltas[t], horizon_delta, lows[t]);
-                }
-            } else {
-                    match &rest {
-                        Some(r) => {
-                            row.assign( &r.clone() );
-                        }, None => {
-                            let mut rest_prob: Array1<f32> = Array1::zeros(horizon);
-                            for (t, v) in rest_prob.indexed_iter_mut() {
-                                *v = probs.integrate_over_range(index, deltas[t], horizon_delta, lows[t]);
-                            }
-                            row.assign( &rest_prob.clone() );
-                            rest = Some( rest_prob );
+                    *v = if t < ready {
+                        0.0
+                    } else {
+                        match self.decay {
+                            super::Decay::None => probs.integrate_over_range(qid, deltas[t], horizon_delta, lows[t]),
+                            decay => probs.integrate_with_late_credit(qid, deltas[t], horizon_delta, lows[t], &decay),
                         }
-                    }
+                    };
+                }
             }
-            index += 1;
+        };
+
+        let nrows = matrix.rows();
+        if self.threads <= 1 || nrows < 2 * MIN_ROWS_PER_THREAD {
+            fill(0, matrix.view_mut());
+            return;
         }
 
-        matrix
+        let chunk = std::cmp::max(MIN_ROWS_PER_THREAD, (nrows + self.threads - 1) / self.threads);
+        crossbeam::thread::scope(|s| {
+            for (i, rows) in matrix.axis_chunks_iter_mut(Axis(0), chunk).enumerate() {
+                let fill = &fill;
+                s.spawn(move |_| fill(i * chunk, rows));
+            }
+        }).unwrap();
     }
 
-    pub fn integrate_probs_partition(&self, probs: super::Prob, total_queries: usize, horizon: usize)
-        -> (Array2<f32>, Array1<usize>) {
-        let mut rest_index = 0;
+    /// rewards[i] = utility[nblocks] * p for the i'th query in `queries_ids`,
+    /// or zero if all its blocks are scheduled. Computed in parallel for large inputs;
+    /// every entry is computed independently, so the result doesn't depend on `self.threads`.
+    fn compute_rewards(&self, rewards: &mut [f32], queries_ids: &[usize], probs: ArrayView1<f32>,
+                       state: &Array1<usize>, utility: &Array1<f32>) {
+        let fill = |offset: usize, rewards: &mut [f32], queries_ids: &[usize]| {
+            for (i, &qid) in queries_ids.iter().enumerate() {
+                let nblocks = state[qid];
+                rewards[i] = if nblocks < self.blocks_per_query[qid] {
+                    utility[nblocks] * probs[offset + i]
+                } else {
+                    0.0
+                };
+            }
+        };
 
-        let tm = self.tm.read().unwrap();
-        let mut deltas: Vec<usize> = Vec::new();
-        let mut lows: Vec<usize> = Vec::new();
-        
-        for t in 0..horizon {
-            deltas.push(tm.slot_to_client_delta(t));
-            lows.push(probs.get_lower_bound(t));
+        let len = queries_ids.len();
+        if self.threads <= 1 || len < 2 * MIN_ROWS_PER_THREAD {
+            fill(0, rewards, queries_ids);
+            return;
         }
-        let horizon_delta = tm.slot_to_client_delta(horizon);
 
-        // queries with explicit probabilities, the rest are uniform
-        let q_in_p = probs.get_k();
-        // last element stores one id from uniform queries
-        let mut queries_ids: Array1<usize> = Array1::zeros(q_in_p.len()+1);
-        // last row stores the uniform probability
-        let mut matrix: Array2<f32> = Array2::zeros((q_in_p.len()+1, horizon));
-
-        // iterate over queries in probs and use their explicit probabilites
-        // then compute for a uniform 
-
-        for (index, &qindex) in q_in_p.iter().enumerate() {
-            let mut row = matrix.row_mut(index);
-            for (t, v) in row.indexed_iter_mut() {
-                // compute the probability of the query over future timestamps
-                *v = probs.integrate_over_range(qindex, deltas[t], horizon_delta, lows[t]);
+        let chunk = std::cmp::max(MIN_ROWS_PER_THREAD, (len + self.threads - 1) / self.threads);
+        crossbeam::thread::scope(|s| {
+            for (i, (rewards, ids)) in rewards[..len].chunks_mut(chunk).zip(queries_ids.chunks(chunk)).enumerate() {
+                let fill = &fill;
+                s.spawn(move |_| fill(i * chunk, rewards, ids));
             }
-            queries_ids[index] = qindex;
-            if rest_index == qindex {
-                rest_index += 1;
-            }
-        }
+        }).unwrap();
+    }
 
-        // 
-        if rest_index < total_queries {
-            let mut row = matrix.row_mut(q_in_p.len());
-            for (t, v) in row.indexed_iter_mut() {
-                *v = probs.integrate_over_range(rest_index, deltas[t], horizon_delta, lows[t]);
-            }
-            queries_ids[ q_in_p.len() ] = rest_index; 
-        }
+    /// Sparse version of `integrate_probs_slow`.
+    ///
+    /// Only the queries with an explicit probability in `probs` get their own row,
+    /// every other query shares the uniform remainder, so that remainder is integrated
+    /// once and stored in a single "rest" row. The cost is O((k+1) * horizon) instead
+    /// of O(total_queries * horizon), where k is the number of queries the client mentions.
+    pub fn integrate_probs_sparse(&self, probs: &super::Prob, total_queries: usize, horizon: usize) -> SparseProbs {
+        let (deltas, lows, horizon_delta) = self.slot_deltas(probs, horizon);
 
+        // queries with explicit probabilities, sorted to keep the rows deterministic
+        let mut queries_ids: Vec<usize> = probs.get_k().into_iter()
+                                               .filter(|&q| q < total_queries)
+                                               .collect();
+        queries_ids.sort();
 
-        (matrix, queries_ids)
+        // any query outside `queries_ids` can stand for the rest, preferably a ready one
+        let rest_index = {
+            let explicit: HashSet<usize> = queries_ids.iter().cloned().collect();
+            let is_ready = |q: usize| self.wait_ms.as_ref().and_then(|w| w.get(q)).map(|&w| w == 0).unwrap_or(true);
+            (0..total_queries).find(|&q| !explicit.contains(&q) && is_ready(q))
+                .or_else(|| (0..total_queries).find(|&q| !explicit.contains(&q)))
+        };
+
+        let rows = queries_ids.len() + if rest_index.is_some() { 1 } else { 0 };
+        let mut matrix: Array2<f32> = Array2::zeros((rows, horizon));
+        self.integrate_rows(&mut matrix, probs, |index| {
+            match queries_ids.get(index) {
+                Some(&qid) => qid,
+                None => rest_index.unwrap(),
+            }
+        }, &deltas, &lows, horizon_delta);
+
+        SparseProbs{ queries_ids: queries_ids, matrix: matrix, has_rest: rest_index.is_some() }
     }
-    
-    pub fn greedy_partition(&self, queries_ids: Array1<usize>, horizon: usize, prob_matrix: &mut Array2<f32>,
-                total_queries: usize, utility: &Array1<f32>,
-                mut state: Array1<usize>) -> Vec<usize> {
-        // state: for each query, how many blocks are scheduled
-        // for each block slot in cache, which qid is filling the slot
+
+    /// Sparse version of `greedy_p`, the plans are drawn from the same distribution.
+    ///
+    /// Queries that share the rest probability are only distinguishable by how many
+    /// of their blocks are already scheduled, so they are grouped by that count:
+    /// a group is sampled with weight `utility[nblocks] * p_rest * group size`, and
+    /// then one of its members is picked uniformly.
+    pub fn greedy_p_sparse<R: Rng>(&self, horizon: usize, probs: &SparseProbs,
+                                   total_queries: usize, utility: &Array1<f32>,
+                                   mut state: Array1<usize>, rng: &mut R) -> Vec<usize> {
         let mut blocks: Vec<usize> = Vec::new();
-        let mut rng = rand::thread_rng();
-        let mut rewards: Array1<f32> = Array1::zeros(queries_ids.len());
+        let explicit = probs.queries_ids.len();
+
+        let mut rest = self.rest_levels(probs, total_queries, &state, utility.len());
+
+        let latest_start = Self::latest_start(probs, horizon);
+        let mut rewards: Vec<f32> = vec![0.0; explicit + rest.len()];
         for t in 0..horizon {
-            let mut sum = 0.0;
-            // for each qid, at time t get their probabilities
-            let p_qids = prob_matrix.slice_mut(s![..queries_ids.len(), t]);
-            // get the reward for each query according to how many blocks
-            
-            for i in 0.. p_qids.len() {
-                let qid = queries_ids[i];
-                let nblocks = state[qid];
-                
-                if nblocks < self.blocks_per_query[qid] {
-                    rewards[i] = utility[nblocks] * p_qids[i];
-                    sum += rewards[i];
-                } else {
-                    rewards[i] = 0.0;
+            self.compute_rewards(&mut rewards, &probs.queries_ids, probs.matrix.column(t), &state, utility);
+            let mut sum: f32 = rewards[..explicit].iter().sum();
+
+            if probs.has_rest {
+                let p_rest = probs.matrix[[explicit, t]];
+                for nblocks in 0..rest.len() {
+                    let reward = utility[nblocks] * p_rest * rest.count(nblocks) as f32;
+                    rewards[explicit + nblocks] = reward;
+                    sum += reward;
                 }
             }
 
             if sum <= 0.0 {
-                println!("sum = zero {:?}", p_qids);
+                if t < latest_start {
+                    // nothing is ready for this slot yet
+                    continue;
+                }
                 break;
             }
-            // using rewards as weights, sample from qids
+            // using rewards as weights, sample from qids and rest groups
             let dist = match WeightedIndex::new(&rewards) {
                 Ok(dist) => dist,
                 Err(e) => {
-                    error!("{:?} Invalid weight: {:?}", e, p_qids);
+                    error!("{:?} Invalid weight: {:?}", e, rewards);
                     continue
                 },
             };
 
-            let qindex = dist.sample(&mut rng);
-            let qid = {
-                if qindex == queries_ids.len()-1 {
-                    let num = rng.gen_range(0, total_queries);
-                    num
-                } else {
-                    queries_ids[qindex]
+            let index = dist.sample(rng);
+            let qid = if index < explicit {
+                probs.queries_ids[index]
+            } else {
+                // pick a member of the rest group and move it to the next level
+                let nblocks = index - explicit;
+                let qid = rest.take_random(nblocks, rng);
+                if nblocks + 1 < self.blocks_per_query[qid] {
+                    rest.push(nblocks + 1, qid);
                 }
 
+                qid
             };
 
-            // if the qid is last one then pick randomly from all set of queries
-            
-            if state[qid] < utility.len() {
-                blocks.push(qid);
-                state[qid] += 1;
-            } else {
-                continue;
+            blocks.push(qid);
+            state[qid] += 1;
+        }
+
+        blocks
+    }
+
+    /// Deterministic greedy: in each slot, schedule the block with the maximum
+    /// marginal expected utility `utility[nblocks] * p`.
+    ///
+    /// The integrated probabilities never grow with t once a query is ready, so a priority
+    /// computed at an earlier slot is an upper bound of the current one. Candidates are kept in a
+    /// max-heap and only the top is refreshed until it is up to date (lazy greedy),
+    /// which gives O(horizon log Q) instead of O(horizon * Q).
+    /// Rest queries at the same block count are interchangeable and share one entry.
+    pub fn greedy_argmax(&self, horizon: usize, probs: &SparseProbs,
+                         total_queries: usize, utility: &Array1<f32>,
+                         mut state: Array1<usize>) -> Vec<usize> {
+        let mut blocks: Vec<usize> = Vec::new();
+        let explicit = probs.queries_ids.len();
+        let mut rest = self.rest_levels(probs, total_queries, &state, utility.len());
+
+        let priority = |candidate: Candidate, state: &Array1<usize>, t: usize| -> f32 {
+            match candidate {
+                Candidate::Query(i) => {
+                    let qid = probs.queries_ids[i];
+                    let nblocks = state[qid];
+                    if nblocks < self.blocks_per_query[qid] {
+                        utility[nblocks] * probs.matrix[[i, t]]
+                    } else {
+                        0.0
+                    }
+                },
+                Candidate::Rest(nblocks) => utility[nblocks] * probs.matrix[[explicit, t]],
+            }
+        };
+
+        // candidates enter the heap at the first slot they can be scheduled at,
+        // the ones that aren't ready yet wait in `deferred`
+        let first_slots = Self::first_slots(probs.matrix.view(), horizon);
+        let first_slot = |candidate: Candidate| match candidate {
+            Candidate::Query(i) => first_slots[i],
+            Candidate::Rest(_) => first_slots[explicit],
+        };
+
+        let mut heap: BinaryHeap<HeapEntry> = BinaryHeap::new();
+        let mut deferred: Vec<HeapEntry> = Vec::new();
+        // rest_in_heap[n]: the group of rest queries with n blocks has an entry in the heap
+        let mut rest_in_heap: Vec<bool> = vec![false; rest.len()];
+        if horizon > 0 {
+            for i in 0..explicit {
+                let candidate = Candidate::Query(i);
+                let slot = first_slot(candidate);
+                if slot < horizon {
+                    deferred.push(HeapEntry{ priority: priority(candidate, &state, slot), candidate: candidate, slot: slot });
+                }
+            }
+            for nblocks in 0..rest.len() {
+                let candidate = Candidate::Rest(nblocks);
+                let slot = first_slot(candidate);
+                if rest.count(nblocks) > 0 && slot < horizon {
+                    deferred.push(HeapEntry{ priority: priority(candidate, &state, slot), candidate: candidate, slot: slot });
+                    rest_in_heap[nblocks] = true;
+                }
+            }
+        }
+
+        'slots: for t in 0..horizon {
+            let (ready, waiting): (Vec<HeapEntry>, Vec<HeapEntry>) = deferred.into_iter().partition(|e| e.slot <= t);
+            heap.extend(ready);
+            deferred = waiting;
+
+            // refresh stale entries until the top is up to date
+            let top = loop {
+                let entry = match heap.pop() {
+                    Some(entry) => entry,
+                    // nothing is ready for this slot yet
+                    None if !deferred.is_empty() => continue 'slots,
+                    None => break 'slots,
+                };
+
+                if entry.slot == t {
+                    break entry;
+                }
+
+                if let Candidate::Rest(nblocks) = entry.candidate {
+                    if rest.count(nblocks) == 0 {
+                        rest_in_heap[nblocks] = false;
+                        continue;
+                    }
+                }
+
+                let p = priority(entry.candidate, &state, t);
+                if p > 0.0 {
+                    heap.push(HeapEntry{ priority: p, candidate: entry.candidate, slot: t });
+                } else if let Candidate::Rest(nblocks) = entry.candidate {
+                    rest_in_heap[nblocks] = false;
+                }
+            };
+
+            if top.priority <= 0.0 {
+                break;
+            }
+
+            let qid = match top.candidate {
+                Candidate::Query(i) => probs.queries_ids[i],
+                Candidate::Rest(nblocks) => {
+                    // any member will do, they all have the same marginal utility
+                    let qid = rest.take_any(nblocks);
+                    if nblocks + 1 < self.blocks_per_query[qid] {
+                        rest.push(nblocks + 1, qid);
+                        if !rest_in_heap[nblocks + 1] {
+                            let candidate = Candidate::Rest(nblocks + 1);
+                            heap.push(HeapEntry{ priority: priority(candidate, &state, t), candidate: candidate, slot: t });
+                            rest_in_heap[nblocks + 1] = true;
+                        }
+                    }
+
+                    qid
+                },
+            };
+
+            blocks.push(qid);
+            state[qid] += 1;
+
+            // the chosen candidate's marginal utility changed, push it back with its new value
+            let p = match top.candidate {
+                Candidate::Rest(nblocks) if rest.count(nblocks) == 0 => 0.0,
+                candidate => priority(candidate, &state, t),
+            };
+            if p > 0.0 {
+                heap.push(HeapEntry{ priority: p, candidate: top.candidate, slot: t });
+            } else if let Candidate::Rest(nblocks) = top.candidate {
+                rest_in_heap[nblocks] = false;
             }
         }
 
         blocks
     }
-    
-    pub fn sample_plan(&self, p_qids: &mut ArrayViewMut2<f32>, g_qids: ArrayView2<f32>,
+
+    /// Group the rest queries (not in `probs.queries_ids`) by how many of their blocks
+    /// are scheduled. Only the queries in `self.cached` can have blocks in `state`;
+    /// the state is scanned when it's not known yet.
+    fn rest_levels(&self, probs: &SparseProbs, total_queries: usize,
+                   state: &Array1<usize>, max_blocks_count: usize) -> RestLevels {
+        let mut levels: Vec<Vec<usize>> = vec![Vec::new(); max_blocks_count];
+        if !probs.has_rest {
+            return RestLevels{ levels: levels, excluded: HashSet::new(), zero: 0, total_queries: total_queries, cursor: 0 };
+        }
+        let mut excluded: HashSet<usize> = self.no_blocks.iter().cloned().filter(|&qid| qid < total_queries).collect();
+        excluded.extend(probs.queries_ids.iter().cloned());
+
+        let scanned: BTreeSet<usize>;
+        let cached = match &self.cached {
+            Some(cached) => cached,
+            None => {
+                scanned = (0..total_queries).filter(|&qid| state[qid] > 0).collect();
+                &scanned
+            },
+        };
+        for &qid in cached.iter() {
+            let nblocks = state.get(qid).cloned().unwrap_or(0);
+            if nblocks == 0 || !excluded.insert(qid) {
+                continue;
+            }
+            if nblocks < self.blocks_per_query[qid] {
+                levels[nblocks].push(qid);
+            }
+        }
+
+        let zero = total_queries - excluded.len();
+        RestLevels{ levels: levels, excluded: excluded, zero: zero, total_queries: total_queries, cursor: 0 }
+    }
+
+    /// Length of the prefix of `previous` that is still worth keeping under `probs`.
+    ///
+    /// A block is kept if its marginal expected utility is within `replan_tolerance`
+    /// of the best block for the same slot; the first block that isn't marks the
+    /// start of the suffix to re-plan. `state` is advanced over the kept prefix.
+    pub fn repair_point(&self, previous: &[usize], probs: &SparseProbs, total_queries: usize,
+                        utility: &Array1<f32>, state: &mut Array1<usize>) -> usize {
+        let explicit = probs.queries_ids.len();
+        let rows: HashMap<usize, usize> = probs.queries_ids.iter().enumerate().map(|(i, &q)| (q, i)).collect();
+        let rest = self.rest_levels(probs, total_queries, state, utility.len());
+        let mut rest_counts: Vec<usize> = (0..rest.len()).map(|nblocks| rest.count(nblocks)).collect();
+
+        for (t, &qid) in previous.iter().enumerate() {
+            let nblocks = state[qid];
+            if nblocks >= self.blocks_per_query[qid] {
+                return t;
+            }
+
+            let reward = match rows.get(&qid) {
+                Some(&i) => utility[nblocks] * probs.matrix[[i, t]],
+                None => utility[nblocks] * probs.matrix[[explicit, t]],
+            };
+
+            let mut best = 0.0;
+            for (i, &q) in probs.queries_ids.iter().enumerate() {
+                if state[q] < self.blocks_per_query[q] {
+                    best = f32::max(best, utility[state[q]] * probs.matrix[[i, t]]);
+                }
+            }
+            for (level, &count) in rest_counts.iter().enumerate() {
+                if count > 0 {
+                    best = f32::max(best, utility[level] * probs.matrix[[explicit, t]]);
+                }
+            }
+
+            if reward <= 0.0 || reward < (1.0 - self.replan_tolerance) * best {
+                return t;
+            }
+
+            if !rows.contains_key(&qid) {
+                rest_counts[nblocks] -= 1;
+                if nblocks + 1 < self.blocks_per_query[qid] {
+                    rest_counts[nblocks + 1] += 1;
+                }
+            }
+            state[qid] += 1;
+        }
+
+        previous.len()
+    }
+
+    /// plan `horizon` slots from the first slot of `probs` with the configured policy
+    fn plan(&mut self, horizon: usize, probs: &SparseProbs, total_queries: usize,
+            state: Array1<usize>) -> Vec<usize> {
+        match self.policy {
+            Policy::Sample => {
+                let mut rng = self.rng.clone();
+                let plan = self.greedy_p_sparse(horizon, probs, total_queries, &self.utility, state, &mut rng);
+                self.rng = rng;
+                plan
+            },
+            Policy::Argmax => self.greedy_argmax(horizon, probs, total_queries, &self.utility, state),
+        }
+    }
+
+    /// Number of block slots to plan, at most the free cache slots.
+    ///
+    /// With an adaptive horizon, plan the blocks that reach the client before the
+    /// furthest delta in `probs` (plus some slack), given the current bandwidth and
+    /// latency: past that point the predictions are stale anyway.
+    pub fn horizon(&self, probs: &super::Prob, start_idx: usize) -> usize {
+        let free = self.cachesize - start_idx;
+        let horizon = match (&self.adaptive_horizon, probs.max_delta()) {
+            (Some(adaptive), Some(delta)) => {
+                let slots = self.tm.read().unwrap().client_delta_to_slots(delta + adaptive.slack_ms);
+                std::cmp::min(std::cmp::max(slots, adaptive.min), adaptive.max)
+            },
+            _ => self.batch,
+        };
+
+        std::cmp::min(free, horizon)
+    }
+
+    /// What is left of the previous plan: after the entries the sender took if it
+    /// reported them, see `set_consumed`, or once it reached `start_idx` otherwise.
+    fn previous_remaining(&self, start_idx: usize) -> Option<&[usize]> {
+        let previous = self.previous.as_ref()?;
+        match self.consumed {
+            Some(consumed) => previous.plan.get(consumed..),
+            // the cache wrapped around or the sender went past the plan
+            None if previous.start_idx <= start_idx && start_idx - previous.start_idx <= previous.plan.len() => {
+                Some(&previous.plan[start_idx - previous.start_idx..])
+            },
+            None => None,
+        }
+    }
+
+    pub fn sample_plan<R: Rng>(&self, p_qids: &mut ArrayViewMut2<f32>, g_qids: ArrayView2<f32>,
                    horizon: usize, total_queries: usize, max_blocks_count: usize,
-                   mut state: Array1<usize>) -> Vec<usize> {
+                   mut state: Array1<usize>, rng: &mut R) -> Vec<usize> {
         let mut plan: Vec<usize> = Vec::new();
         let epsilon = 0.0;//1e-6;
-        let mut rng = rand::thread_rng();
 
         assert!(g_qids.shape()[0] <= total_queries && g_qids.shape()[1] <= max_blocks_count);
         assert!(p_qids.shape()[0] <= total_queries && p_qids.shape()[1] <= horizon);
@@ -308,45 +808,40 @@ impl GreedyScheduler {
         plan
     } 
 
-    pub fn greedy_p(&self, horizon: usize, prob_matrix: &mut Array2<f32>,
+    /// Dense version of `greedy_p_sparse`: one row of `prob_matrix` per query.
+    pub fn greedy_p<R: Rng>(&self, horizon: usize, prob_matrix: &Array2<f32>,
                 total_queries: usize, utility: &Array1<f32>,
-                mut state: Array1<usize>) -> Vec<usize> {
+                mut state: Array1<usize>, rng: &mut R) -> Vec<usize> {
         // state: for each query, how many blocks are scheduled
         // for each block slot in cache, which qid is filling the slot
         let mut blocks: Vec<usize> = Vec::new();
-        let mut rng = rand::thread_rng();
-        let mut rewards: Array1<f32> = Array1::zeros(total_queries);
+        let queries_ids: Vec<usize> = (0..total_queries).collect();
+        let mut rewards: Vec<f32> = vec![0.0; total_queries];
+        let latest_start = Self::first_slots(prob_matrix.slice(s![..total_queries, ..horizon]), horizon)
+                               .into_iter().filter(|&t| t < horizon).max().unwrap_or(0);
         for t in 0..horizon {
-            let mut sum = 0.0;
-            // for each qid, at time t get their probabilities
-            let p_qids = prob_matrix.slice_mut(s![..total_queries, t]);
             // get the reward for each query according to how many blocks
-            
-            for i in 0.. p_qids.len() {
-                let nblocks = state[i];
-                
-                if nblocks < self.blocks_per_query[i] {
-                    rewards[i] = utility[nblocks] * p_qids[i];
-                    sum += rewards[i];
-                } else {
-                    rewards[i] = 0.0;
-                }
-
-            }
+            self.compute_rewards(&mut rewards, &queries_ids, prob_matrix.slice(s![..total_queries, t]),
+                                 &state, utility);
+            let sum: f32 = rewards.iter().sum();
 
             if sum <= 0.0 {
+                if t < latest_start {
+                    // nothing is ready for this slot yet
+                    continue;
+                }
                 break;
             }
             // using rewards as weights, sample from qids
             let dist = match WeightedIndex::new(&rewards) {
                 Ok(dist) => dist,
                 Err(e) => {
-                    error!("{:?} Invalid weight: {:?}", e, p_qids);
+                    error!("{:?} Invalid weight: {:?}", e, rewards);
                     continue
                 },
             };
 
-            let qid = dist.sample(&mut rng);
+            let qid = dist.sample(rng);
             if state[qid] < utility.len() {
                 blocks.push(qid);
                 state[qid] += 1;
@@ -376,32 +871,347 @@ impl super::SchedulerTrait for GreedyScheduler {
         // dist indexed using the same index in queries vector
         // get this from app? have one that the app and scheduler use to synchronise?
         //let max_blocks_count = self.utility.len();
-        let horizon = std::cmp::min(self.cachesize - start_idx, self.batch);
-        //let horizon = self.cachesize - start_idx;
+        let horizon = self.horizon(&probs, start_idx);
+        debug!("horizon: {:?} blocks", horizon);
 
         if total_queries == 0 {
             return Vec::new();
         }
 
+        // drop the queries the client no longer has blocks of
+        self.cached = Some(match self.cached.take() {
+            Some(cached) => cached.into_iter().filter(|&qid| state.get(qid).cloned().unwrap_or(0) > 0).collect(),
+            None => (0..total_queries).filter(|&qid| state[qid] > 0).collect(),
+        });
+
 
         let plan: Vec<usize> = {
             // for each query, and for each slot in cache, store the probability of that query
             let start = Instant::now();
-            let mut prob_matrix = self.integrate_probs_slow(probs, total_queries, horizon);
-            //let (mut prob_matrix, queries_ids) = self.integrate_probs_partition(probs, total_queries, horizon);
-            //println!("---> materialized probs len: {}", queries_ids.len());
-            //let mut prob_matrix = self.integrate_probs(probs, total_queries, horizon);
+            let sparse_probs = self.integrate_probs_sparse(&probs, total_queries, horizon);
+            debug!("materialized probs len: {}", sparse_probs.queries_ids.len());
             debug!("integrate probs: {:?}", start.elapsed());
-            println!("integrate probs: {:?}", start.elapsed());
             let start = Instant::now();
             //let plan = self.sample_plan(&mut prob_matrix.view_mut(), self.utility_matrix.view(), horizon, total_queries, max_blocks_count, state);
-            let plan = self.greedy_p(horizon, &mut prob_matrix, total_queries, &self.utility, state);
-            //let plan = self.greedy_partition(queries_ids, horizon, &mut prob_matrix, total_queries, &self.utility, state);
+            let remaining: Vec<usize> = self.previous_remaining(start_idx).unwrap_or(&[]).to_vec();
+            self.consumed = None;
+            let plan = if self.incremental && !remaining.is_empty() {
+                // keep the prefix of the previous plan that is still good, re-plan the rest
+                let mut state = state;
+                let previous = &remaining[..std::cmp::min(remaining.len(), horizon)];
+                let reused = self.repair_point(previous, &sparse_probs, total_queries, &self.utility, &mut state);
+
+                let suffix_probs = SparseProbs{ queries_ids: sparse_probs.queries_ids.clone(),
+                                                matrix: sparse_probs.matrix.slice(s![.., reused..]).to_owned(),
+                                                has_rest: sparse_probs.has_rest };
+                let mut plan = previous[..reused].to_vec();
+                plan.extend(self.plan(horizon - reused, &suffix_probs, total_queries, state));
+                plan
+            } else {
+                self.plan(horizon, &sparse_probs, total_queries, state)
+            };
             debug!("greedy: {:?}", start.elapsed());
-            println!("greedy: {:?}", start.elapsed());
+
+            if self.previous.is_some() {
+                let reused = if self.incremental {
+                    remaining.iter().zip(plan.iter()).take_while(|(a, b)| a == b).count()
+                } else {
+                    0
+                };
+                let changed = remaining.iter().zip(plan.iter()).filter(|(a, b)| a != b).count()
+                              + remaining.len().max(plan.len()) - remaining.len().min(plan.len());
+                self.plan_delta = Some(super::PlanDelta{ previous: remaining.len(), reused: reused,
+                                                         replanned: plan.len() - reused, changed: changed });
+            }
+            self.previous = Some(PreviousPlan{ plan: plan.clone(), start_idx: start_idx });
+            if let Some(cached) = self.cached.as_mut() {
+                cached.extend(plan.iter().cloned());
+            }
+
             plan
         };
 
         plan
     }
+
+    fn set_wait(&mut self, wait_ms: Option<Vec<usize>>) {
+        self.wait_ms = wait_ms;
+    }
+
+    fn set_consumed(&mut self, consumed: Option<usize>) {
+        self.consumed = consumed;
+    }
+
+    fn last_plan_delta(&self) -> Option<super::PlanDelta> {
+        self.plan_delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::{Prob, SchedulerOptions, SchedulerTrait};
+
+    fn test_scheduler(total_queries: usize, nblocks: usize, horizon: usize) -> GreedyScheduler {
+        let utility: Array1<f32> = (0..nblocks).map(|_| 1.0 / nblocks as f32).collect();
+        let tm = Arc::new(RwLock::new(ds::TimeManager::new(10, 0, 1.0)));
+        let options = SchedulerOptions{ threads: 1, seed: Some(7), ..SchedulerOptions::default() };
+        new(horizon, horizon, utility, vec![nblocks; total_queries], tm, &options, Policy::Sample)
+    }
+
+    // a handful of explicit queries at three deltas, the rest is uniform
+    fn test_probs(total_queries: usize, explicit: usize) -> Prob {
+        let mut probs = Prob::new(total_queries);
+        for &delta in [0, 200, 400].iter() {
+            let dist: indexmap::IndexMap<usize, f32> = (0..explicit).map(|i| (i * 7, 0.5 / explicit as f32)).collect();
+            probs.set_probs_at(dist, delta);
+        }
+
+        probs
+    }
+
+    #[test]
+    fn test_sparse_probs_match_dense() {
+        let (total_queries, horizon) = (50, 20);
+        let sched = test_scheduler(total_queries, 4, horizon);
+        let probs = test_probs(total_queries, 5);
+
+        let dense = sched.integrate_probs_slow(probs.clone(), total_queries, horizon);
+        let sparse = sched.integrate_probs_sparse(&probs, total_queries, horizon);
+
+        assert!(sparse.has_rest);
+        assert_eq!(sparse.matrix.rows(), sparse.queries_ids.len() + 1);
+        for qid in 0..total_queries {
+            let row = match sparse.queries_ids.iter().position(|&q| q == qid) {
+                Some(i) => i,
+                None => sparse.queries_ids.len(),
+            };
+            for t in 0..horizon {
+                assert!((dense[[qid, t]] - sparse.matrix[[row, t]]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_greedy_p_sparse_respects_state() {
+        let (total_queries, nblocks, horizon) = (1000, 3, 200);
+        let sched = test_scheduler(total_queries, nblocks, horizon);
+        let probs = test_probs(total_queries, 10);
+        let sparse = sched.integrate_probs_sparse(&probs, total_queries, horizon);
+
+        let mut state: Array1<usize> = Array1::zeros(total_queries);
+        state[1] = nblocks;
+        let mut rng = StdRng::seed_from_u64(7);
+        let plan = sched.greedy_p_sparse(horizon, &sparse, total_queries, &sched.utility, state.clone(), &mut rng);
+
+        assert_eq!(plan.len(), horizon);
+        for &qid in plan.iter() {
+            state[qid] += 1;
+            assert!(state[qid] <= nblocks, "query {} got more blocks than it has", qid);
+        }
+    }
+
+    #[test]
+    fn test_rest_levels_follow_cached_queries() {
+        let (total_queries, nblocks, horizon) = (200, 3, 20);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        let probs = test_probs(total_queries, 5);
+        let plan = sched.run_scheduler(probs.clone(), Array1::zeros(total_queries), 0);
+
+        // the sender sent the plan, and the client lost the blocks of its first query
+        let mut state: Array1<usize> = Array1::zeros(total_queries);
+        for &qid in plan.iter() {
+            state[qid] += 1;
+        }
+        state[plan[0]] = 0;
+
+        let sparse = sched.integrate_probs_sparse(&probs, total_queries, horizon);
+        let tracked = sched.rest_levels(&sparse, total_queries, &state, nblocks);
+        let mut scan = sched.clone();
+        scan.cached = None;
+        let scanned = scan.rest_levels(&sparse, total_queries, &state, nblocks);
+
+        let rest_with_blocks = (0..total_queries).filter(|&q| state[q] > 0 && !sparse.queries_ids.contains(&q)).count();
+        assert_eq!(tracked.count(0), total_queries - sparse.queries_ids.len() - rest_with_blocks);
+        for n in 0..nblocks {
+            assert_eq!(tracked.count(n), scanned.count(n));
+        }
+        for n in 1..nblocks {
+            let mut members = tracked.levels[n].clone();
+            members.sort();
+            assert_eq!(members, scanned.levels[n]);
+        }
+    }
+
+    #[test]
+    fn test_parallel_plan_matches_sequential() {
+        let (total_queries, horizon) = (30_000, 50);
+        let mut sequential = test_scheduler(total_queries, 4, horizon);
+        let mut parallel = sequential.clone();
+        parallel.threads = 4;
+        let probs = test_probs(total_queries, 3000);
+
+        let dense = sequential.integrate_probs_slow(probs.clone(), total_queries, horizon);
+        assert_eq!(dense, parallel.integrate_probs_slow(probs.clone(), total_queries, horizon));
+
+        let state: Array1<usize> = Array1::zeros(total_queries);
+        // the dense plan depends on the seed only
+        let plan = sequential.greedy_p(horizon, &dense, total_queries, &sequential.utility, state.clone(),
+                                       &mut StdRng::seed_from_u64(7));
+        assert_eq!(plan, parallel.greedy_p(horizon, &dense, total_queries, &parallel.utility, state.clone(),
+                                           &mut StdRng::seed_from_u64(7)));
+
+        let plan = sequential.run_scheduler(probs.clone(), state.clone(), 0);
+        assert_eq!(plan, parallel.run_scheduler(probs, state, 0));
+    }
+
+    #[test]
+    fn test_greedy_argmax_picks_maximum() {
+        let (total_queries, nblocks, horizon) = (100, 2, 10);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        sched.policy = Policy::Argmax;
+
+        let mut probs = Prob::new(total_queries);
+        for &delta in [0, 200].iter() {
+            let dist: indexmap::IndexMap<usize, f32> = vec![(5, 0.5), (9, 0.3)].into_iter().collect();
+            probs.set_probs_at(dist, delta);
+        }
+
+        let sparse = sched.integrate_probs_sparse(&probs, total_queries, horizon);
+        let state: Array1<usize> = Array1::zeros(total_queries);
+        let plan = sched.greedy_argmax(horizon, &sparse, total_queries, &sched.utility, state.clone());
+
+        // the most likely queries get all their blocks first, then the rest is spread out
+        assert_eq!(&plan[..4], &[5, 5, 9, 9]);
+        assert_eq!(plan.len(), horizon);
+        assert_eq!(plan, sched.run_scheduler(probs, state, 0));
+    }
+
+    #[test]
+    fn test_waits_for_generated_blocks() {
+        let (total_queries, nblocks, horizon) = (100, 2, 10);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        sched.policy = Policy::Argmax;
+
+        let mut probs = Prob::new(total_queries);
+        for &delta in [0, 200].iter() {
+            let dist: indexmap::IndexMap<usize, f32> = vec![(5, 0.5), (9, 0.3)].into_iter().collect();
+            probs.set_probs_at(dist, delta);
+        }
+
+        // query 5 is generated in 35ms, slots are 10ms apart
+        let mut wait = vec![0; total_queries];
+        wait[5] = 35;
+        sched.set_wait(Some(wait));
+        assert_eq!(sched.ready_slot(5), 3);
+
+        let plan = sched.run_scheduler(probs, Array1::zeros(total_queries), 0);
+        assert!(!plan[..3].contains(&5));
+        assert_eq!(plan.iter().filter(|&&q| q == 5).count(), nblocks);
+    }
+
+    #[test]
+    fn test_dense_waits_for_generated_blocks() {
+        let (total_queries, nblocks, horizon) = (10, 2, 10);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        // every query is generated in 35ms: nothing before slot 3
+        sched.set_wait(Some(vec![35; total_queries]));
+
+        let probs = test_probs(total_queries, 1);
+        let dense = sched.integrate_probs_slow(probs, total_queries, horizon);
+        assert_eq!(dense[[0, 2]], 0.0);
+        let mut rng = StdRng::seed_from_u64(7);
+        let plan = sched.greedy_p(horizon, &dense, total_queries, &sched.utility, Array1::zeros(total_queries), &mut rng);
+        assert_eq!(plan.len(), horizon - 3);
+    }
+
+    #[test]
+    fn test_sparse_waits_for_rest_queries() {
+        let (total_queries, nblocks, horizon) = (20, 2, 10);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        // only query 0 is ready, the rest queries are generated in 35ms
+        let mut wait = vec![35; total_queries];
+        wait[0] = 0;
+        sched.set_wait(Some(wait));
+
+        let probs = test_probs(total_queries, 1);
+        let sparse = sched.integrate_probs_sparse(&probs, total_queries, horizon);
+        assert_eq!(GreedyScheduler::latest_start(&sparse, horizon), 3);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let plan = sched.greedy_p_sparse(horizon, &sparse, total_queries, &sched.utility,
+                                         Array1::zeros(total_queries), &mut rng);
+        // query 0 fills slots 0 and 1, slot 2 waits, the rest queries follow
+        assert_eq!(&plan[..2], &[0, 0]);
+        assert_eq!(plan.len(), horizon - 1);
+        assert!(plan[2..].iter().all(|&q| q != 0));
+    }
+
+    #[test]
+    fn test_incremental_reuses_previous_plan() {
+        let (total_queries, nblocks, horizon) = (100, 4, 20);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        sched.policy = Policy::Argmax;
+        sched.incremental = true;
+        let probs = test_probs(total_queries, 5);
+        let state: Array1<usize> = Array1::zeros(total_queries);
+
+        let first = sched.run_scheduler(probs.clone(), state.clone(), 0);
+        assert_eq!(sched.last_plan_delta(), None);
+
+        // same distribution, the sender consumed two blocks
+        let mut state = state;
+        state[first[0]] += 1;
+        state[first[1]] += 1;
+        let second = sched.run_scheduler(probs, state, 2);
+        let delta = sched.last_plan_delta().unwrap();
+
+        assert_eq!(delta.previous, horizon - 2);
+        assert_eq!(&second[..delta.reused], &first[2..2 + delta.reused]);
+        assert!(delta.reused > 0);
+    }
+
+    #[test]
+    fn test_incremental_follows_consumed_entries() {
+        let (total_queries, nblocks, horizon) = (100, 4, 20);
+        let mut sched = test_scheduler(total_queries, nblocks, horizon);
+        sched.policy = Policy::Argmax;
+        sched.incremental = true;
+        let probs = test_probs(total_queries, 5);
+        let state: Array1<usize> = Array1::zeros(total_queries);
+
+        let first = sched.run_scheduler(probs.clone(), state.clone(), 0);
+
+        // the sender took three entries but skipped two of them: the head moved by one
+        let mut state = state;
+        state[first[0]] += 1;
+        sched.set_consumed(Some(3));
+        let second = sched.run_scheduler(probs, state, 1);
+        let delta = sched.last_plan_delta().unwrap();
+
+        assert_eq!(delta.previous, horizon - 3);
+        assert_eq!(&second[..delta.reused], &first[3..3 + delta.reused]);
+        assert_eq!(sched.consumed, None);
+    }
+
+    #[test]
+    fn test_adaptive_horizon() {
+        let (total_queries, horizon) = (10, 100);
+        // 10ms per block, no latency
+        let mut sched = test_scheduler(total_queries, 2, horizon);
+        sched.adaptive_horizon = Some(AdaptiveHorizon{ slack_ms: 0, min: 5, max: 50 });
+
+        // no prediction: fall back to the batch
+        assert_eq!(sched.horizon(&Prob::new(total_queries), 0), horizon);
+
+        // predictions up to 400ms -> 40 blocks
+        let probs = test_probs(total_queries, 1);
+        assert_eq!(sched.horizon(&probs, 0), 40);
+        // never more than the free cache slots
+        assert_eq!(sched.horizon(&probs, 80), 20);
+
+        // 400ms + 200ms slack -> 60 blocks, capped at 50
+        sched.adaptive_horizon = Some(AdaptiveHorizon{ slack_ms: 200, min: 5, max: 50 });
+        assert_eq!(sched.horizon(&probs, 0), 50);
+    }
 }
@@ -16,10 +16,12 @@ pub mod greedy;
 pub mod ilp;
 pub mod prob;
 pub mod decoders;
+pub mod decay;
 
 use crate::ds;
 
 pub use prob::{Prob};
+pub use decay::{Decay};
 pub use decoders::*;
 use ndarray::{Array1};
 use serde_derive::{Deserialize, Serialize};
@@ -27,10 +29,53 @@ use std::sync::{Arc,  RwLock};
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum SchedulerType {
+    /// sample the next block proportionally to its expected utility
     Greedy,
+    /// always pick the block with the maximum expected utility
+    GreedyArgmax,
     ILP,
 }
 
+/// Tuning knobs shared by the schedulers, read from the "scheduler" key of the server config.
+///
+/// # Example
+/// {"scheduler": {"type": "GreedyArgmax", "threads": 4, "seed": 42}}
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerOptions {
+    #[serde(rename = "type")]
+    pub stype: SchedulerType,
+    /// threads used to compute the probability matrix and the rewards
+    pub threads: usize,
+    /// seed the scheduler's random number generator to get reproducible plans
+    pub seed: Option<u64>,
+    /// reuse the part of the previous plan that is still good under the new distribution
+    pub incremental: bool,
+    /// incremental mode: a previous block is kept while its expected utility is
+    /// within this fraction of the best block for the same slot
+    pub replan_tolerance: f32,
+    /// plan as many blocks as the network delivers within the predicted future,
+    /// instead of a fixed batch
+    pub adaptive_horizon: bool,
+    /// adaptive horizon: ms planned beyond the furthest predicted delta
+    pub horizon_slack_ms: usize,
+    /// adaptive horizon: bounds on the number of planned blocks
+    pub min_horizon: usize,
+    pub max_horizon: usize,
+    /// credit given to blocks that arrive after the request, apps may override it
+    pub decay: Decay,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        SchedulerOptions{ stype: SchedulerType::Greedy, threads: 1, seed: None,
+                          incremental: false, replan_tolerance: 0.1,
+                          adaptive_horizon: false, horizon_slack_ms: 0,
+                          min_horizon: 10, max_horizon: 1000,
+                          decay: Decay::None }
+    }
+}
+
 
 pub fn discretise_utility(utility: Vec<f32>, max_blocks_count: usize) -> Array1<f32> {
     let utility: Array1<f32> = (0..max_blocks_count).enumerate().map(|(i, _v)| {
@@ -48,7 +93,8 @@ pub fn discretise_utility(utility: Vec<f32>, max_blocks_count: usize) -> Array1<
 
 pub fn new(&stype: &SchedulerType, batch: usize, cachesize: usize,
             utility: Vec<f32>, blocks_per_query: Vec<usize>,
-            tm: Option<Arc<RwLock<ds::TimeManager>>>) -> Box<dyn SchedulerTrait> {
+            tm: Option<Arc<RwLock<ds::TimeManager>>>,
+            options: &SchedulerOptions) -> Box<dyn SchedulerTrait> {
     
     let tm = match tm {
         Some(tm) => tm,
@@ -60,16 +106,45 @@ pub fn new(&stype: &SchedulerType, batch: usize, cachesize: usize,
     // init utility array function and the utility for the queries
     let utility = discretise_utility(utility, max_blocks_count);
     match stype {
-        SchedulerType::Greedy => Box::new( greedy::new(batch, cachesize, utility, blocks_per_query, tm) ) as Box<dyn SchedulerTrait>,
+        SchedulerType::Greedy => Box::new( greedy::new(batch, cachesize, utility, blocks_per_query, tm, options, greedy::Policy::Sample) ) as Box<dyn SchedulerTrait>,
+        SchedulerType::GreedyArgmax => Box::new( greedy::new(batch, cachesize, utility, blocks_per_query, tm, options, greedy::Policy::Argmax) ) as Box<dyn SchedulerTrait>,
         SchedulerType::ILP => Box::new( ilp::new(cachesize, utility, total_queries, tm) ) as Box<dyn SchedulerTrait>,
     }
 }
 
+/// How much a new plan differs from what was left of the previous one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlanDelta {
+    /// slots of the previous plan the sender had not reached yet
+    pub previous: usize,
+    /// leading slots copied from the previous plan
+    pub reused: usize,
+    /// slots planned from scratch
+    pub replanned: usize,
+    /// slots whose query differs from the previous plan at the same position
+    pub changed: usize,
+}
+
 pub trait SchedulerTrait: Send + Sync + SchedulerClone {
     /// dist: hashmap[key] -> probability
     /// returns hashmap[key] -> block counts
     fn run_scheduler(&mut self, probs: Prob,
                      state: Array1<usize>, start_idx: usize) -> Vec<usize>;
+
+    /// optional: per query, ms until its blocks can be sent because they're still
+    /// being generated (0 when ready), None when every block is ready
+    fn set_wait(&mut self, _wait_ms: Option<Vec<usize>>) {
+    }
+
+    /// optional: entries of the last plan the sender has taken, sent or skipped,
+    /// None if unknown
+    fn set_consumed(&mut self, _consumed: Option<usize>) {
+    }
+
+    /// optional: compare the last plan with the previous one
+    fn last_plan_delta(&self) -> Option<PlanDelta> {
+        None
+    }
 }
 
 pub trait  SchedulerClone {
@@ -103,6 +103,24 @@ impl Prob {
         self.deltas_ms.insert(delta);
     }
 
+    /// The k most likely queries at each delta in the model, with the probability of a
+    /// query that isn't listed explicitly.
+    ///
+    /// Returns (delta_ms, [(query index, prob)] sorted by prob, rest prob) per delta.
+    pub fn top_k(&self, k: usize) -> Vec<(usize, Vec<(usize, f32)>, f32)> {
+        self.deltas_ms.iter().map(|&delta| {
+            let instance = &self.probs_t[&delta];
+            let mut keys = instance.get_k();
+            keys.insert(self.point_dist.q_index);
+
+            let mut top: Vec<(usize, f32)> = keys.into_iter().map(|q| (q, self.get_probs_at(q, delta))).collect();
+            top.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+            top.truncate(k);
+
+            (delta, top, self.get_linear_prob(std::usize::MAX, instance.rest_dist))
+        }).collect()
+    }
+
     pub fn set_point_dist(&mut self, alpha: f64, index: usize) {
         self.point_dist.alpha =alpha as f32;
         self.point_dist.q_index = index;
@@ -189,6 +207,11 @@ impl Prob {
         p
     }
 
+    /// furthest delta (ms) the client sent a distribution for
+    pub fn max_delta(&self) -> Option<usize> {
+        self.deltas_ms.iter().next_back().cloned()
+    }
+
     pub fn get_lower_bound(&self, delta_0: usize) -> usize {
         let mut low = 0;
         // delta_ms: for each state sent from client, delta_ms stores distribtions in x ms in the
@@ -231,6 +254,32 @@ impl Prob {
 
         p.abs()
     }
+
+    /// `integrate_over_range` plus a late-credit window: a request predicted at delta
+    /// d < delta_0, i.e. before the block arrives, counts with `decay.weight(delta_0 - d)`,
+    /// the block's lateness from that request. Requests more than `decay.late_window_ms()`
+    /// before delta_0 get nothing. With `Decay::None` both are the same.
+    pub fn integrate_with_late_credit(&self, qid: usize, delta_0: usize, delta_m: usize, low: usize,
+                                      decay: &super::Decay) -> f32 {
+        let p = self.integrate_over_range(qid, delta_0, delta_m, low);
+        let window = decay.late_window_ms();
+        if window == 0 || delta_0 == 0 {
+            return p;
+        }
+
+        // sum over ~20 steps of the window before delta_0; `get` interpolates between
+        // non-negative probabilities, so the sum doesn't need `abs` like the areas above
+        let step = std::cmp::max(1, window / 20);
+        let mut late: f32 = 0.0;
+        let mut delta = delta_0.saturating_sub(window);
+        while delta < delta_0 {
+            let width = std::cmp::min(step, delta_0 - delta);
+            late += self.get(qid, delta) * decay.weight((delta_0 - delta) as f32) * width as f32;
+            delta += width;
+        }
+
+        p + late
+    }
 }
 
 
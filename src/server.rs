@@ -0,0 +1,75 @@
+/*
+ * Server builder: starts the manager actor and the webserver.
+ *
+ * # Example
+ * khameleon::Server::new(ServerConfig::default())
+ *     .bind("0.0.0.0:8080")
+ *     .app("MyApp", |appstate, config, state_change_flag| Ok(Box::new(MyApp::new(appstate, config)?) as Box<dyn AppTrait>))
+ *     .run()
+ */
+
+/// local imports
+use crate::apps;
+use crate::config;
+use crate::ds;
+use crate::manager;
+use crate::metrics;
+use crate::webserver;
+
+/// public lib
+use actix_web::{App, HttpServer, middleware};
+use actix_session::{CookieSession};
+use actix::prelude::*;
+use std::sync::{Arc, RwLock};
+
+pub struct Server {
+    config: config::ServerConfig,
+    registry: apps::AppRegistry,
+}
+
+impl Server {
+    /// config: server configuration, `config.app` is passed to the apps on initialization
+    pub fn new(config: config::ServerConfig) -> Self {
+        Server{ config: config, registry: apps::AppRegistry::with_builtin() }
+    }
+
+    /// address the webserver listens on, overrides `config.bind`
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.config.bind = addr.to_owned();
+        self
+    }
+
+    /// register an app, clients select it by sending `name` as their appname
+    pub fn app<F>(mut self, name: &str, factory: F) -> Self
+        where F: Fn(&ds::AppState, serde_json::Value, Arc<RwLock<bool>>) -> Result<Box<dyn apps::AppTrait>, String> + Send + Sync + 'static {
+        self.registry.register(name, factory);
+        self
+    }
+
+    /// start the manager and the webserver, blocks until the system stops
+    pub fn run(self) -> std::io::Result<()> {
+        info!("start server");
+        let sys = actix_rt::System::new("khameleon-actix");
+
+        // 1) Start Manager Thread/Actor
+        info!("registered apps: {:?}", self.registry.names());
+        let bind = self.config.bind.clone();
+        let metrics = Arc::new(metrics::Metrics::new());
+        let imanager = manager::Manager::new(self.config, self.registry, metrics.clone());
+        let manager_addr = imanager.start();
+
+        // 2) Initialize &start server and websocket
+        HttpServer::new(move || {
+            App::new()
+                .data(manager_addr.clone())
+                .data(metrics.clone())
+                .configure(webserver::appconfig::config_app)
+                // enable logger
+                .wrap(middleware::Logger::default())
+                .wrap(CookieSession::signed(&[0;32]).secure(false))
+        })
+        .bind(&bind)?
+        .start();
+        sys.run()
+    }
+}
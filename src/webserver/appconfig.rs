@@ -1,12 +1,14 @@
 use crate::manager;
+use crate::ds;
+use crate::metrics;
+use crate::error::KhameleonError;
 
-use actix_web::{FromRequest, error, web, HttpRequest, HttpResponse, Result, Error};
+use actix_web::{FromRequest, error, web, HttpRequest, HttpResponse, ResponseError, Result, Error};
 use actix_session::{Session};
 use actix_web::http::{StatusCode};
 use actix_files as fs;
 use actix::prelude::*;
-use futures::{future::{ok as fut_ok}, Future};
-use actix_rt::spawn;
+use futures::{future::{self, ok as fut_ok}, Future};
 use serde_derive::{Deserialize, Serialize};
 
 /// serve multi_index.html
@@ -30,19 +32,22 @@ fn index(session: Session, req: HttpRequest) -> Result<HttpResponse> {
 
 pub fn log_bandwidth_handle(srv: web::Data<Addr<manager::Manager>>,
                             msg: String) -> impl Future<Item = String, Error = Error> {
-    let stat: manager::SystemStat = serde_json::from_str(&msg).unwrap();
-    let actor_req = srv.send(stat);
-    actor_req.map_err(error::Error::from)
-             .and_then(|_| {
-                 fut_ok("done".to_owned())
-             })
+    future::result(serde_json::from_str::<manager::SystemStat>(&msg))
+        .map_err(|err| Error::from(KhameleonError::from(err)))
+        .and_then(move |stat| {
+            srv.send(stat).map_err(error::Error::from)
+        })
+        .and_then(|_| {
+            fut_ok("done".to_owned())
+        })
 }
 
 pub fn start_threads_handle(srv: web::Data<Addr<manager::Manager>>) -> impl Future<Item = String, Error = Error> {
     let actor_req = srv.send(manager::manager::StartThreads);
     actor_req.map_err(error::Error::from)
-             .and_then(|_| {
-                 fut_ok("done".to_owned())
+             .and_then(|res| {
+                 res.map_err(Error::from)?;
+                 Ok("done".to_owned())
              })
 }
 
@@ -53,52 +58,68 @@ pub fn log_latency_handle() -> Result<()> {
 
 pub fn direct_request(srv: web::Data<Addr<manager::Manager>>,
                       msg: String) -> impl Future<Item = String, Error = Error> {
-    let request: manager::Request = match serde_json::from_str(&msg) {
-        Ok(content) => content,
-        Err(err) => panic!("direct_request msg({:?}) error ({:?})", msg, err),
-    };
-    let actor_req = srv.send( request );
+    future::result(serde_json::from_str::<manager::Request>(&msg))
+        .map_err(|err| {
+            error!("direct_request msg({:?}) error ({:?})", msg, err);
+            Error::from(KhameleonError::from(err))
+        })
+        .and_then(move |request| {
+            srv.send( request ).map_err(error::Error::from)
+        })
+        .and_then(|res| {
+            res.map_err(Error::from)?;
+            // get feedback from the app and pass it to the client
+            Ok( "done".to_owned() )
+        })
+}
 
-    actor_req
-        .map_err(error::Error::from)
-        .and_then(|_| {
-                // get feedback from the app and pass it to the client
-             fut_ok( "done".to_owned() )
+/// the client's layout changed, body: {key: {x, y, w, h}}
+pub fn layout_handle(srv: web::Data<Addr<manager::Manager>>,
+                     msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
+    future::result(serde_json::from_str::<serde_json::Value>(&msg))
+        .map_err(|err| Error::from(KhameleonError::from(err)))
+        .and_then(move |layout| {
+            srv.send(manager::UpdateLayout{layout}).map_err(error::Error::from)
+        })
+        .and_then(|res| {
+            let placed = res.map_err(Error::from)?;
+            Ok(HttpResponse::Ok().json(serde_json::json!({"placed": placed})))
         })
 }
 
-// todo: add a handler to handle layout updates
 pub fn init_app_handle(srv: web::Data<Addr<manager::Manager>>,
-                       msg: String) -> impl Future<Item = String, Error = Error> {
+                       msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
     // takes on msg as String and use Value to deserialize it
     let actor_req = srv.send(manager::InitApp{state: msg,});
     actor_req
         .map_err(error::Error::from)
-        .and_then(|data| {
+        .and_then(|res| {
+            let data = match res {
+                Ok(data) => data,
+                // e.g. unknown app name, or the app's backend is missing
+                Err(err) => {
+                    error!("init app failed: {}", err);
+                    return fut_ok( err.error_response() );
+                }
+            };
             info!("init app state {}", data.instance);
 
-            if data.instance > 1 {
-                error!("one insrtance is already running: {}", data.instance);
-                // todo: find a way to clear and start without
-                // killing the app
-                System::current().stop();
-                panic!("one instance is already running");
-            } else {
-                // get feedback from the app and pass it to the client
-                fut_ok( data.data )
-            }
+            // get feedback from the app and pass it to the client
+            fut_ok( HttpResponse::Ok().body(data.data) )
         })
 }
 
 /// https://docs.serde.rs/serde_json/enum.Value.html
-pub fn distribution_handle(srv: web::Data<Addr<manager::Manager>>, msg: String) -> Result<()> {
-    // takes on msg as String and use Value to deserialize it
-    let res = srv.send(manager::Distributions{data: msg,});
-    spawn(
-        res.map(|_| ()).map_err(|_| ()),
-    );
-
-    Ok(())
+pub fn distribution_handle(srv: web::Data<Addr<manager::Manager>>, msg: String) -> impl Future<Item = HttpResponse, Error = Error> {
+    future::result(serde_json::from_str::<ds::PredictorState>(&msg))
+        .map_err(|err| Error::from(KhameleonError::from(err)))
+        .and_then(move |userstate| {
+            srv.send(manager::Distributions{userstate}).map_err(error::Error::from)
+        })
+        .and_then(|res| {
+            res.map_err(Error::from)?;
+            Ok(HttpResponse::Ok().finish())
+        })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -108,10 +129,7 @@ struct TraceData {
 }
 
 pub fn logtrace_tofile_handle(msg: String) -> Result<HttpResponse> {
-    let datainfo: TraceData = match serde_json::from_str(&msg) {
-        Ok(content) => content,
-        Err(err) => panic!("logtrace_tofile_handle msg({:?}) error ({:?})", msg, err),
-    };
+    let datainfo: TraceData = serde_json::from_str(&msg).map_err(KhameleonError::from)?;
 
     let path = "./traces/";
 
@@ -143,10 +161,7 @@ struct ResultsData {
 }
 
 pub fn log_tofile_handle(msg: String) -> Result<HttpResponse> {
-    let datainfo: ResultsData = match serde_json::from_str(&msg) {
-        Ok(content) => content,
-        Err(err) => panic!("log_tofile_handle msg({:?}) error ({:?})", msg, err),
-    };
+    let datainfo: ResultsData = serde_json::from_str(&msg).map_err(KhameleonError::from)?;
 
     let path = "./log/exp_details.json";
     info!("log_results {:?}", path);
@@ -165,6 +180,18 @@ pub fn log_tofile_handle(msg: String) -> Result<HttpResponse> {
     }
 }
 
+/// runtime metrics in Prometheus text format
+pub fn metrics_handle(metrics: web::Data<std::sync::Arc<metrics::Metrics>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.to_prometheus())
+}
+
+/// runtime metrics as JSON
+pub fn stats_handle(metrics: web::Data<std::sync::Arc<metrics::Metrics>>) -> HttpResponse {
+    HttpResponse::Ok().json(metrics.to_json())
+}
+
 /// initialize handles
 pub fn config_app(cfg: &mut web::ServiceConfig)
 {
@@ -183,7 +210,12 @@ pub fn config_app(cfg: &mut web::ServiceConfig)
                      .route(web::post().to_async(direct_request)))
         .service(index)
         .service(web::resource("/post_dist")
-                     .route(web::post().to(distribution_handle)))
+                     .route(web::post().to_async(distribution_handle)))
+        .service(web::resource("/layout")
+                  .data(String::configure(|g| {
+                      g.limit(1024*1024*10)
+                  }))
+                     .route(web::post().to_async(layout_handle)))
         .service(web::resource("/initapp")
                   .data(String::configure(|g| {
                       g.limit(1024*1024*100)
@@ -195,6 +227,14 @@ pub fn config_app(cfg: &mut web::ServiceConfig)
                      .route(web::post().to_async(log_bandwidth_handle)))
         .service(web::resource("/start/threads")
                      .route(web::post().to_async(start_threads_handle)))
+        .service(web::resource("/metrics")
+                     .route(web::get().to(metrics_handle)))
+        .service(web::resource("/stats")
+                     .route(web::get().to(stats_handle)))
+        .service(web::resource("/inspect")
+                     .route(web::get().to_async(super::inspect::inspect_handle)))
+        .service(web::resource("/inspect/ws/")
+                     .route(web::get().to(super::inspect::inspect_ws_index)))
         .service(web::resource("/ws/")
                      .route(web::get().to(super::ws::ws_index)))
         .service(fs::Files::new("static", "client/static").show_files_listing());
@@ -0,0 +1,86 @@
+/*
+ * Introspection endpoints: /inspect returns a snapshot of the session,
+ * /inspect/ws/ pushes a snapshot every `interval_ms` while the session runs.
+ */
+use crate::manager;
+use crate::error::KhameleonError;
+
+use serde_derive::{Deserialize};
+use actix_web::{error, web, HttpRequest, HttpResponse, Error};
+use actix_web_actors::ws;
+use futures::Future;
+use actix::prelude::*;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct InspectParams {
+    /// queries listed per delta of the distribution
+    pub top_k: usize,
+    /// streaming period
+    pub interval_ms: u64,
+}
+
+impl Default for InspectParams {
+    fn default() -> Self {
+        InspectParams{ top_k: 5, interval_ms: 500 }
+    }
+}
+
+pub fn inspect_handle(srv: web::Data<Addr<manager::Manager>>,
+                      params: web::Query<InspectParams>) -> impl Future<Item = HttpResponse, Error = Error> {
+    srv.send(manager::Inspect{ top_k: params.top_k })
+        .map_err(error::Error::from)
+        .and_then(|res| {
+            let inspection = res.map_err(Error::from)?;
+            Ok(HttpResponse::Ok().json(inspection))
+        })
+}
+
+/// read-only websocket, it ignores everything the client sends except close
+pub struct InspectSocket {
+    addr: Addr<manager::Manager>,
+    params: InspectParams,
+}
+
+impl InspectSocket {
+    fn push(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr.send(manager::Inspect{ top_k: self.params.top_k })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                let body = match res {
+                    Ok(Ok(inspection)) => serde_json::to_string(&inspection)
+                        .unwrap_or_else(|e| KhameleonError::Internal(e.to_string()).to_json().to_string()),
+                    Ok(Err(err)) => err.to_json().to_string(),
+                    Err(err) => KhameleonError::Internal(format!("manager unavailable: {}", err)).to_json().to_string(),
+                };
+                ctx.text(body);
+                fut::ok(())
+            }).wait(ctx);
+    }
+}
+
+impl Actor for InspectSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.push(ctx);
+        let interval = std::time::Duration::from_millis(std::cmp::max(self.params.interval_ms, 10));
+        ctx.run_interval(interval, |act, ctx| act.push(ctx));
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for InspectSocket {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Close(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+pub fn inspect_ws_index(srv: web::Data<Addr<manager::Manager>>, params: web::Query<InspectParams>,
+                        r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    let socket = InspectSocket{ addr: srv.get_ref().clone(), params: params.into_inner() };
+    ws::start(socket, &r, stream)
+}
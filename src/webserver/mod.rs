@@ -1,2 +1,3 @@
 pub mod appconfig;
 pub mod ws;
+pub mod inspect;
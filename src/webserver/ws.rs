@@ -1,18 +1,24 @@
 /// local imports
 use crate::ds;
 use crate::manager;
+use crate::metrics;
+use crate::error::KhameleonError;
 
 /// public lib
-use serde_derive::{Serialize};
+use serde_derive::{Deserialize, Serialize};
 use csv::Writer;
-use std::collections::HashMap;
 use actix_web::{web, HttpRequest, HttpResponse, Error, Result};
 use actix_web_actors::ws;
-use std::sync::{Arc};
+use std::sync::{Arc, Mutex};
 use crossbeam_utils::atomic::AtomicCell;
 // for the Actor primitive
 use actix::prelude::*;
 
+/// how often the server starts a clock synchronization exchange with the client
+const CLOCK_SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// close code of a socket replaced by a newer connection, the client doesn't reconnect on it
+pub const CLOSE_REPLACED: u16 = 4001;
+
 #[derive(Serialize)]
 struct BlockDelays {
     bid: u32,
@@ -27,43 +33,59 @@ impl Handler<ds::StreamBlock> for WebSocket {
 
     fn handle(&mut self, block: ds::StreamBlock, ctx: &mut Self::Context) {
         match block {
-            ds::StreamBlock::Binary(x) => {
-                let mut bytebuffer: Vec<u8> = Vec::new();
-                
-                // metadata attached to each block to help track their rrt
-                let bid: u32 = {
-                    let timestamp: u128 = {
-                        let now = std::time::SystemTime::now();
-                        let since_the_epoch = now.duration_since(std::time::UNIX_EPOCH).expect("Time went backwards");
-                        since_the_epoch.as_millis() as u128
-                    };
-
-                    self.block_counter += 1;
-
-                    self.last_timestamp = timestamp;
-                    self.blocks_tracker.insert( self.block_counter, timestamp );
-                    self.block_counter
-                };
-                let mut bid = bincode::serialize(&bid).unwrap();
-                
-                bytebuffer.append(&mut bid);
-                bytebuffer.extend( x );
-                ctx.binary(bytebuffer)
-            },
-            ds::StreamBlock::Stop => ctx.stop()
+            ds::StreamBlock::Binary(x) => self.send_direct(x.into(), ctx),
+            ds::StreamBlock::Shared(x) => self.send_direct(x, ctx),
+            // the one copy of the block, into the websocket frame
+            ds::StreamBlock::Framed(bytebuffer) => ctx.binary(bytebuffer.to_vec()),
+            ds::StreamBlock::Stop => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Other(CLOSE_REPLACED),
+                    description: Some("replaced".to_owned()),
+                }));
+                ctx.stop();
+            }
+        }
+
+    }
+}
+
+impl WebSocket {
+    /// a block of a direct request, not framed yet
+    fn send_direct(&self, block: ds::BlockBytes, ctx: &mut ws::WebsocketContext<Self>) {
+        // metadata attached to each block to help track their rrt
+        let framed = match &self.session {
+            Some(session) => session.lock().unwrap().frame(None, block),
+            None => {
+                error!("block received before the session was set up");
+                return;
+            }
+        };
+        if let ds::StreamBlock::Framed(bytebuffer) = framed {
+            ctx.binary(bytebuffer.to_vec())
         }
+    }
 
+    /// report an error to the client as a JSON text frame, the session stays open
+    fn send_error(&self, err: KhameleonError, ctx: &mut ws::WebsocketContext<Self>) {
+        error!("websocket: {}", err);
+        if let KhameleonError::BadRequest(_) = err {
+            self.metrics.malformed_messages.inc();
+        }
+        ctx.text(err.to_json().to_string());
     }
 }
 
 pub struct WebSocket {
     /// Stream Server address
     pub addr: Addr<manager::Manager>,
-    pub block_counter: u32,
-    pub blocks_tracker: HashMap<u32, u128>,
+    /// frames blocks and tracks them until acked, shared with the sender thread
+    pub session: Option<Arc<Mutex<manager::Session>>>,
+    /// session the client asks to resume
+    pub resume: Option<manager::Resume>,
+    pub connection: usize,
     pub writer: Writer<std::fs::File>,
     pub congestion: Arc<AtomicCell<u128>>,
-    pub last_timestamp: u128,
+    pub metrics: Arc<metrics::Metrics>,
 }
 
 impl Actor for WebSocket {
@@ -72,20 +94,42 @@ impl Actor for WebSocket {
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Initializing WebSocket Actor");
         let addr = ctx.address();
-        self.addr.send(manager::Connect{ws_addr: addr.recipient(), congestion: self.congestion.clone()})
+        self.addr.send(manager::Connect{ws_addr: addr.recipient(), congestion: self.congestion.clone(),
+                                        resume: self.resume.take()})
                  .into_actor(self)
-                 .then(|res, _, ctx| {
+                 .then(|res, act, ctx| {
                      // pass on the laten
                      match res {
-                          Ok(_) => info!("successfully initialized ws"),
+                          Ok(data) => {
+                              info!("successfully initialized ws, session resumed: {}", data.resumed);
+                              act.connection = data.connection;
+                              act.session = Some(data.session);
+                              // the client keeps the token to resume the session if the socket drops
+                              ctx.text(serde_json::json!({"session": data.token, "resumed": data.resumed,
+                                                          "replayed": data.replay.len()}).to_string());
+                              for block in data.replay {
+                                  if let ds::StreamBlock::Framed(bytebuffer) = block {
+                                      ctx.binary(bytebuffer.to_vec());
+                                  }
+                              }
+                          },
                           // something is wrong with server
-                          _ => {
+                          Err(err) => {
+                              act.send_error(KhameleonError::Internal(format!("websocket initialization error: {}", err)), ctx);
                               ctx.stop();
-                              panic!("websocket initialization error");
                           }
                      }
                      fut::ok(())
                  }).wait(ctx);
+
+        // ping the client with the server time, it replies with "sync t0 t1 t2"
+        ctx.run_interval(CLOCK_SYNC_INTERVAL, |_, ctx| {
+            ctx.text(format!("{{\"sync\": {}}}", ds::now_ms()));
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.addr.do_send(manager::Disconnect{ connection: self.connection });
     }
 }
 
@@ -101,26 +145,53 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocket {
             ws::Message::Text(text) => {
                 let lines = text.split_whitespace();
                 let nums: Vec<&str> = lines.collect();
+                if nums.len() == 4 && nums[0] == "sync" {
+                    let t3 = ds::now_ms();
+                    let times: Vec<f64> = nums[1..].iter().filter_map(|t| t.parse::<f64>().ok()).collect();
+                    match times.len() == 3 {
+                        true => self.addr.do_send(manager::ClockSample{ sample: ds::ClockSample::new(times[0], times[1], times[2], t3) }),
+                        false => self.send_error(KhameleonError::BadRequest(format!("malformed clock sync reply {:?}", text)), ctx),
+                    }
+                    return;
+                }
+
+                if nums.is_empty() || nums.len() > 2 {
+                    self.send_error(KhameleonError::BadRequest(format!("malformed ack {:?}", text)), ctx);
+                    return;
+                }
+
                 let bid = nums[0];
                 let client_timestamp = { 
-                    match nums.len() == 2 {
-                        true => nums[1].parse::<u128>().unwrap(),
-                        false => 0
+                    match nums.get(1).map(|t| t.parse::<u128>()) {
+                        Some(Ok(t)) => t,
+                        Some(Err(_)) => {
+                            self.send_error(KhameleonError::BadRequest(format!("malformed ack timestamp {:?}", text)), ctx);
+                            return;
+                        },
+                        None => 0
                     }
                 };
 
                 match bid.parse::<u32>() {
                     Ok(n) => {
-                        match self.blocks_tracker.get( &n ) {
-                            Some(&t1) => {
+                        let sent = self.session.as_ref().and_then(|session| session.lock().unwrap().ack(n));
+                        match sent {
+                            Some(t1) => {
                                 let t2: u128 = {
                                     let now = std::time::SystemTime::now();
                                     let since_the_epoch = now.duration_since(std::time::UNIX_EPOCH).expect("Time went backwards");
                                     since_the_epoch.as_millis() as u128
                                 };
 
-                                let delay = t2 - t1;
+                                let delay = t2.saturating_sub(t1);
                                 self.congestion.store( delay );
+                                self.metrics.acks_received.inc();
+                                self.metrics.block_rtt_ms.observe(delay as f64);
+                                if client_timestamp > 0 {
+                                    // an ack is an exchange where the client replies as soon as it receives
+                                    let client = client_timestamp as f64;
+                                    self.addr.do_send(manager::ClockSample{ sample: ds::ClockSample::new(t1 as f64, client, client, t2 as f64) });
+                                }
                                 match self.writer.serialize( BlockDelays {bid: n, delay: delay, t1: t1, t2: t2, client: client_timestamp} ) {
                                     Ok(_) => (),
                                     Err(e) => println!("writing to writer: {:?}", e),
@@ -130,7 +201,7 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocket {
                             None => error!("no matching timestamp in blocks tracker {:?}", n),
                         }
                     },
-                    Err(_) => error!("something wrong with the received block index {:?}", bid),
+                    Err(_) => self.send_error(KhameleonError::BadRequest(format!("something wrong with the received block index {:?}", bid)), ctx),
                 }
                 
             },
@@ -145,17 +216,32 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for WebSocket {
     }
 }
 
-pub fn ws_index(srv: web::Data<Addr<manager::Manager>>, r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+/// query string of a reconnecting client: /ws/?session=<token>&last_ack=<block id>
+#[derive(Debug, Deserialize)]
+pub struct ResumeParams {
+    session: Option<String>,
+    last_ack: Option<u32>,
+}
+
+pub fn ws_index(srv: web::Data<Addr<manager::Manager>>, metrics: web::Data<Arc<metrics::Metrics>>,
+                params: web::Query<ResumeParams>,
+                r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
     info!("Initialize websocket header: {:?}", r);
     
     let fname = format!("./log/block_details.csv");
-    let wtr = Writer::from_path(fname).unwrap();
+    let wtr = Writer::from_path(&fname)
+        .map_err(|err| KhameleonError::Internal(format!("couldn't open {}: {}", fname, err)))?;
     let congestion = Arc::new(AtomicCell::new(0));
-    let websocket = WebSocket{ addr: srv.get_ref().clone() , block_counter: 0,
-                               blocks_tracker: HashMap::new(),
-                               writer: wtr, congestion: congestion, last_timestamp: 0};
+    let params = params.into_inner();
+    let resume = match params.session {
+        Some(session) => Some(manager::Resume{ session: session, last_ack: params.last_ack.unwrap_or(0) }),
+        None => None,
+    };
+    let websocket = WebSocket{ addr: srv.get_ref().clone(), session: None, resume: resume, connection: 0,
+                               writer: wtr, congestion: congestion,
+                               metrics: metrics.get_ref().clone()};
     let res = ws::start(websocket, &r, stream);
 
-    info!("ws session header response: {:?}", res.as_ref().unwrap());
+    info!("ws session header response: {:?}", res);
     res
 }
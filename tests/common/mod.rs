@@ -0,0 +1,357 @@
+/*
+ * Headless test harness: runs the khameleon webserver on an ephemeral port and
+ * plays the part of the browser client over HTTP and the /ws/ websocket.
+ *
+ * SyntheticApp is a deterministic app for the tests: `queries` queries named
+ * q0, q1, ... with `blocks` blocks each. Its distributions are
+ *     {"probs": {"<query index>": prob, ...}}
+ * and hold at every delta.
+ */
+#![allow(dead_code)]
+
+use khameleon::{apps, config, ds, manager, metrics, scheduler, webserver};
+use khameleon::apps::AppTrait;
+
+use actix::prelude::*;
+use actix_web::{App, HttpServer};
+use serde_derive::{Deserialize, Serialize};
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// deltas (ms) the synthetic distributions are defined at
+const DELTAS_MS: [usize; 4] = [0, 100, 1000, 10000];
+
+pub struct SyntheticApp {
+    blocks_per_query: indexmap::IndexMap<String, usize>,
+    utility: Vec<f32>,
+    blocksize: usize,
+}
+
+impl SyntheticApp {
+    pub fn new(queries: usize, blocks: usize, blocksize: usize) -> Self {
+        let blocks_per_query = (0..queries).map(|i| (format!("q{}", i), blocks)).collect();
+        let utility = (0..blocks).map(|i| (i + 1) as f32 / blocks as f32).collect();
+        SyntheticApp{ blocks_per_query, utility, blocksize }
+    }
+
+    /// same layout as the TestApp: block id, block count, key, then the content
+    fn encode_block(&self, key: &str, block_id: u32, nblocks: u32) -> ds::StreamBlock {
+        let mut bytebuffer = bincode::serialize(&block_id).unwrap();
+        bytebuffer.extend(bincode::serialize(&nblocks).unwrap());
+        bytebuffer.extend(bincode::serialize(&key).unwrap());
+        bytebuffer.extend(vec![block_id as u8; self.blocksize]);
+        ds::StreamBlock::Binary(bytebuffer)
+    }
+}
+
+impl AppTrait for SyntheticApp {
+    fn get_scheduler_config(&self) -> (indexmap::IndexMap<String, usize>, Vec<f32>) {
+        (self.blocks_per_query.clone(), self.utility.clone())
+    }
+
+    fn decode_dist(&mut self, userstate: ds::PredictorState) -> scheduler::Prob {
+        let mut prob = scheduler::Prob::new(self.blocks_per_query.len());
+        let probs: indexmap::IndexMap<usize, f32> = match userstate.data.get("probs") {
+            Some(probs) => serde_json::from_value::<indexmap::IndexMap<String, f32>>(probs.clone())
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(k, p)| k.parse::<usize>().ok().map(|k| (k, p)))
+                .collect(),
+            None => indexmap::IndexMap::new(),
+        };
+
+        for &delta in DELTAS_MS.iter() {
+            prob.set_probs_at(probs.clone(), delta);
+        }
+
+        prob
+    }
+
+    fn get_block_size(&self) -> usize {
+        self.blocksize
+    }
+
+    fn get_nblocks_byindex(&mut self, index: usize, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let key = self.blocks_per_query.get_index(index).map(|(k, _)| k.clone())?;
+        self.get_nblocks_bykey(&key, count, incache)
+    }
+
+    fn get_nblocks_bykey(&mut self, key: &str, count: usize, incache: usize) -> Option<Vec<ds::StreamBlock>> {
+        let nblocks = *self.blocks_per_query.get(key)?;
+        let end = std::cmp::min(nblocks, incache + count);
+        Some((incache..end).map(|i| self.encode_block(key, i as u32, nblocks as u32)).collect())
+    }
+}
+
+/// registry with a SyntheticApp of 4 queries x 3 blocks
+pub fn synthetic_registry() -> apps::AppRegistry {
+    let mut registry = apps::AppRegistry::new();
+    registry.register("SyntheticApp", |_appstate, _config, _flag| {
+        Ok(Box::new(SyntheticApp::new(4, 3, 256)) as Box<dyn AppTrait>)
+    });
+    registry
+}
+
+/// deterministic scheduler, no bandwidth limits in the way
+pub fn test_config() -> config::ServerConfig {
+    let mut config = config::ServerConfig::default();
+    config.bind = "127.0.0.1:0".to_owned();
+    config.bandwidth = 1000.0;
+    config.scheduler.stype = scheduler::SchedulerType::GreedyArgmax;
+    config.scheduler.seed = Some(0);
+    config
+}
+
+/// a khameleon webserver on an ephemeral port, stopped on drop
+pub struct TestServer {
+    pub addr: SocketAddr,
+    system: System,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(config: config::ServerConfig, registry: apps::AppRegistry) -> Self {
+        // the websocket logs block delays to ./log
+        std::fs::create_dir_all("log").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let sys = actix_rt::System::new("khameleon-test");
+            let bind = config.bind.clone();
+            let metrics = Arc::new(metrics::Metrics::new());
+            let manager_addr = manager::Manager::new(config, registry, metrics.clone()).start();
+
+            let server = HttpServer::new(move || {
+                App::new()
+                    .data(manager_addr.clone())
+                    .data(metrics.clone())
+                    .configure(webserver::appconfig::config_app)
+            })
+            .bind(&bind)
+            .unwrap();
+            let addr = server.addrs()[0];
+            server.start();
+
+            tx.send((addr, System::current())).unwrap();
+            let _ = sys.run();
+        });
+
+        let (addr, system) = rx.recv_timeout(Duration::from_secs(10)).expect("test server didn't start");
+        TestServer{ addr, system, thread: Some(thread) }
+    }
+
+    pub fn client(&self) -> Client {
+        Client{ addr: self.addr }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.system.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+/// blocking HTTP/1.1 client, one connection per request
+pub struct Client {
+    addr: SocketAddr,
+}
+
+impl Client {
+    fn send(&self, method: &str, path: &str, body: &str) -> Response {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               method, path, self.addr, body.len(), body).unwrap();
+
+        let mut raw = String::new();
+        stream.read_to_string(&mut raw).unwrap();
+        let status = raw.split_whitespace().nth(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+        let body = match raw.find("\r\n\r\n") {
+            Some(i) => raw[i + 4..].to_owned(),
+            None => String::new(),
+        };
+        Response{ status, body }
+    }
+
+    pub fn get(&self, path: &str) -> Response {
+        self.send("GET", path, "")
+    }
+
+    pub fn post(&self, path: &str, body: &str) -> Response {
+        self.send("POST", path, body)
+    }
+
+    pub fn init_app(&self, appname: &str, cachesize: usize) -> Response {
+        let appstate = serde_json::json!({"appname": appname, "cachesize": cachesize, "state": {}});
+        self.post("/initapp", &appstate.to_string())
+    }
+
+    pub fn post_dist(&self, probs: &[(usize, f32)]) -> Response {
+        let probs: serde_json::Map<String, serde_json::Value> =
+            probs.iter().map(|(k, p)| (k.to_string(), serde_json::json!(p))).collect();
+        let state = ds::PredictorState::new("synthetic", serde_json::json!({ "probs": probs }));
+        self.post("/post_dist", &serde_json::to_string(&state).unwrap())
+    }
+
+    pub fn connect_ws(&self) -> WsClient {
+        self.open_ws(&format!("ws://{}/ws/", self.addr))
+    }
+
+    /// reconnect to the session of `previous`, after the last block it acked
+    pub fn resume_ws(&self, previous: WsClient) -> WsClient {
+        let token = previous.session.clone().expect("no session to resume");
+        let url = format!("ws://{}/ws/?session={}&last_ack={}", self.addr, token, previous.last_ack);
+        drop(previous);
+        self.open_ws(&url)
+    }
+
+    fn open_ws(&self, url: &str) -> WsClient {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let url = url::Url::parse(url).unwrap();
+        let (socket, _) = tungstenite::client(url, stream).expect("websocket handshake");
+        WsClient{ socket, errors: Vec::new(), session: None, resumed: false, last_ack: 0, close_code: None }
+    }
+}
+
+/// block as decoded by the client: the websocket's block id, then the app's header
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Block {
+    pub bid: u32,
+    pub block_id: u32,
+    pub nblocks: u32,
+    pub key: String,
+    pub content: Vec<u8>,
+}
+
+impl Block {
+    pub fn decode(frame: &[u8]) -> Option<Self> {
+        let mut cursor = Cursor::new(frame);
+        let (bid, block_id, nblocks, key): (u32, u32, u32, String) = bincode::deserialize_from(&mut cursor).ok()?;
+        let content = frame[cursor.position() as usize..].to_vec();
+        Some(Block{ bid, block_id, nblocks, key, content })
+    }
+}
+
+#[derive(Deserialize)]
+struct SyncPing {
+    sync: f64,
+}
+
+#[derive(Deserialize)]
+struct SessionInfo {
+    session: String,
+    resumed: bool,
+}
+
+/// the client side of /ws/: answers clock syncs and acks every block it decodes
+pub struct WsClient {
+    socket: tungstenite::WebSocket<TcpStream>,
+    /// error frames sent by the server
+    pub errors: Vec<serde_json::Value>,
+    /// session token, sent by the server when the socket opens
+    pub session: Option<String>,
+    pub resumed: bool,
+    /// highest block id acked
+    pub last_ack: u32,
+    /// code of the close frame sent by the server
+    pub close_code: Option<u16>,
+}
+
+impl Drop for WsClient {
+    fn drop(&mut self) {
+        let _ = self.socket.close(None);
+        let _ = self.socket.write_pending();
+    }
+}
+
+impl WsClient {
+    pub fn send_text(&mut self, text: &str) {
+        self.socket.write_message(Message::Text(text.to_owned())).unwrap();
+    }
+
+    /// next block within `timeout`, acked as soon as it's decoded
+    pub fn next_block(&mut self, timeout: Duration) -> Option<Block> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let msg = match self.socket.read_message() {
+                Ok(msg) => msg,
+                Err(tungstenite::Error::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock
+                                                   || e.kind() == std::io::ErrorKind::TimedOut => continue,
+                Err(_) => return None,
+            };
+
+            match msg {
+                Message::Binary(frame) => {
+                    let block = Block::decode(&frame)?;
+                    if block.bid > 0 {
+                        self.send_text(&format!("{} {}", block.bid, ds::now_ms() as u128));
+                        self.last_ack = std::cmp::max(self.last_ack, block.bid);
+                    }
+                    return Some(block);
+                },
+                Message::Text(text) => {
+                    let received = ds::now_ms();
+                    if let Ok(ping) = serde_json::from_str::<SyncPing>(&text) {
+                        self.send_text(&format!("sync {} {} {}", ping.sync, received, ds::now_ms()));
+                    } else if let Ok(info) = serde_json::from_str::<SessionInfo>(&text) {
+                        self.session = Some(info.session);
+                        self.resumed = info.resumed;
+                    } else if let Ok(err) = serde_json::from_str::<serde_json::Value>(&text) {
+                        self.errors.push(err);
+                    }
+                },
+                Message::Close(frame) => {
+                    self.close_code = frame.map(|f| f.code.into());
+                    return None;
+                },
+                _ => (),
+            }
+        }
+
+        None
+    }
+
+    pub fn blocks(&mut self, n: usize, timeout: Duration) -> Vec<Block> {
+        let deadline = Instant::now() + timeout;
+        let mut blocks = Vec::new();
+        while blocks.len() < n && Instant::now() < deadline {
+            match self.next_block(deadline.saturating_duration_since(Instant::now())) {
+                Some(block) => blocks.push(block),
+                None => break,
+            }
+        }
+        blocks
+    }
+}
+
+/// poll `check` until it holds or `timeout` passes
+pub fn eventually<F: FnMut() -> bool>(timeout: Duration, mut check: F) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if check() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    check()
+}
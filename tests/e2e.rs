@@ -0,0 +1,120 @@
+/*
+ * End to end tests: a headless client drives the webserver the way the browser does.
+ *
+ * $ cargo test --test e2e
+ */
+mod common;
+
+use common::{eventually, synthetic_registry, test_config, TestServer};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn test_streams_predicted_query_in_order() {
+    let server = TestServer::start(test_config(), synthetic_registry());
+    let client = server.client();
+
+    assert_eq!(client.init_app("SyntheticApp", 12).status, 200);
+    // the synthetic app has no layout
+    let res = client.post("/layout", r#"{"q1": {"x": 0, "y": 0, "w": 10, "h": 10}}"#);
+    assert_eq!(res.status, 400);
+    assert_eq!(res.json()["error"], "bad_request");
+    let mut ws = client.connect_ws();
+    assert_eq!(client.post("/start/threads", "").status, 200);
+
+    // all the mass on q2: its blocks come first, in order
+    assert_eq!(client.post_dist(&[(2, 1.0)]).status, 200);
+    let blocks = ws.blocks(3, TIMEOUT);
+    let received: Vec<(String, u32)> = blocks.iter().map(|b| (b.key.clone(), b.block_id)).collect();
+    assert_eq!(received, vec![("q2".to_owned(), 0), ("q2".to_owned(), 1), ("q2".to_owned(), 2)]);
+    assert!(blocks.iter().all(|b| b.nblocks == 3 && b.content.len() == 256));
+
+    // the acks reach the server
+    assert!(eventually(TIMEOUT, || {
+        client.get("/stats").json()["acks_received_total"].as_u64().unwrap_or(0) >= 3
+    }));
+    assert!(ws.errors.is_empty(), "{:?}", ws.errors);
+}
+
+#[test]
+fn test_resume_replays_unacked_blocks() {
+    let server = TestServer::start(test_config(), synthetic_registry());
+    let client = server.client();
+
+    assert_eq!(client.init_app("SyntheticApp", 12).status, 200);
+    let mut ws = client.connect_ws();
+    assert_eq!(client.post("/start/threads", "").status, 200);
+    assert_eq!(client.post_dist(&[(2, 1.0)]).status, 200);
+
+    let first = ws.next_block(TIMEOUT).expect("no block streamed");
+    assert_eq!((first.key.as_str(), first.block_id), ("q2", 0));
+    assert!(ws.session.is_some() && !ws.resumed);
+
+    // the socket drops before the client reads the rest, they come through the new one
+    let mut ws = client.resume_ws(ws);
+    let blocks = ws.blocks(2, TIMEOUT);
+    let received: Vec<(String, u32)> = blocks.iter().map(|b| (b.key.clone(), b.block_id)).collect();
+    assert_eq!(received, vec![("q2".to_owned(), 1), ("q2".to_owned(), 2)]);
+    assert!(ws.resumed);
+    assert!(blocks.iter().all(|b| b.bid > first.bid));
+}
+
+#[test]
+fn test_replaced_socket_is_closed() {
+    let server = TestServer::start(test_config(), synthetic_registry());
+    let client = server.client();
+
+    assert_eq!(client.init_app("SyntheticApp", 12).status, 200);
+    let mut first = client.connect_ws();
+    let _second = client.connect_ws();
+
+    // the client doesn't reconnect on this code
+    assert!(first.next_block(TIMEOUT).is_none());
+    assert_eq!(first.close_code, Some(khameleon::webserver::ws::CLOSE_REPLACED));
+}
+
+#[test]
+fn test_direct_request() {
+    let server = TestServer::start(test_config(), synthetic_registry());
+    let client = server.client();
+
+    assert_eq!(client.init_app("SyntheticApp", 12).status, 200);
+    let mut ws = client.connect_ws();
+
+    let res = client.post("/request", r#"{"query": "q1", "rtype": false}"#);
+    assert_eq!(res.status, 200);
+    let block = ws.next_block(TIMEOUT).expect("no block for the request");
+    assert_eq!((block.key.as_str(), block.block_id), ("q1", 0));
+}
+
+#[test]
+fn test_errors() {
+    let server = TestServer::start(test_config(), synthetic_registry());
+    let client = server.client();
+
+    let res = client.post("/request", r#"{"query": "q1", "rtype": false}"#);
+    assert_eq!(res.status, 409);
+    assert_eq!(res.json()["error"], "not_initialized");
+
+    let res = client.init_app("NoSuchApp", 12);
+    assert_eq!(res.status, 404);
+    assert_eq!(res.json()["error"], "unknown_app");
+
+    let res = client.post("/post_dist", "not json");
+    assert_eq!(res.status, 400);
+    assert_eq!(res.json()["error"], "bad_request");
+
+    // the server survives bad clients
+    assert_eq!(client.init_app("SyntheticApp", 12).status, 200);
+    let mut ws = client.connect_ws();
+    ws.send_text("not an ack");
+    assert!(ws.next_block(Duration::from_millis(500)).is_none());
+    assert_eq!(ws.errors.len(), 1);
+    assert_eq!(ws.errors[0]["error"], "bad_request");
+
+    // a running app doesn't hide an unknown one
+    let res = client.init_app("NoSuchApp", 12);
+    assert_eq!(res.status, 404);
+    assert_eq!(res.json()["error"], "unknown_app");
+}